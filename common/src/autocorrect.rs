@@ -0,0 +1,397 @@
+use heapless::Vec;
+use keyberon::key_code::KeyCode;
+
+use crate::keymap::HostLayout;
+use crate::sequence::{tap, Sequence, Steps};
+use crate::typing::{Keys, Press};
+use crate::unicode::{self, Method};
+
+const MAX_LEN: usize = 16;
+const MAX_TYPOS: usize = 128;
+const LEAF: u8 = 0x80;
+
+struct Nodes {
+    // First typo starting with the same d letters as typo i, the one
+    // owning their node
+    first: [[u8; MAX_LEN + 1]; MAX_TYPOS],
+    offset: [[u16; MAX_LEN + 1]; MAX_TYPOS],
+    len: usize,
+}
+
+const fn same_prefix(a: &[u8], b: &[u8], len: usize) -> bool {
+    if a.len() < len || b.len() < len {
+        return false;
+    }
+    let mut k = 0;
+    while k < len {
+        if a[k] != b[k] {
+            return false;
+        }
+        k += 1;
+    }
+    true
+}
+
+const fn nodes(typos: &[(&str, &str)]) -> Nodes {
+    assert!(typos.len() <= MAX_TYPOS, "too many typos");
+    let mut nodes = Nodes {
+        first: [[0; MAX_LEN + 1]; MAX_TYPOS],
+        offset: [[0; MAX_LEN + 1]; MAX_TYPOS],
+        len: 0,
+    };
+    let mut i = 0;
+    while i < typos.len() {
+        let typo = typos[i].0.as_bytes();
+        assert!(!typo.is_empty() && typo.len() <= MAX_LEN, "bad typo length");
+        let mut d = 0;
+        while d <= typo.len() {
+            assert!(
+                d == 0 || typo[d - 1].is_ascii_lowercase(),
+                "typo not in a-z"
+            );
+            let mut j = 0;
+            while j < i && !same_prefix(typo, typos[j].0.as_bytes(), d) {
+                j += 1;
+            }
+            nodes.first[i][d] = j as u8;
+            d += 1;
+        }
+        i += 1;
+    }
+    // Breadth first, so that the root comes first
+    let mut d = 0;
+    while d <= MAX_LEN {
+        let mut i = 0;
+        while i < typos.len() {
+            if typos[i].0.len() >= d && nodes.first[i][d] as usize == i {
+                nodes.offset[i][d] = nodes.len as u16;
+                nodes.len += 1;
+                let mut j = 0;
+                while j < typos.len() {
+                    let len = typos[j].0.len();
+                    if len == d && nodes.first[j][d] as usize == i {
+                        nodes.len += 1;
+                    } else if len > d
+                        && nodes.first[j][d] as usize == i
+                        && nodes.first[j][d + 1] as usize == j
+                    {
+                        nodes.len += 3;
+                    }
+                    j += 1;
+                }
+                assert!(nodes.len <= u16::MAX as usize, "trie too large");
+            }
+            i += 1;
+        }
+        d += 1;
+    }
+    nodes
+}
+
+/// Size of the trie of `typos`.
+pub const fn trie_len(typos: &[(&str, &str)]) -> usize {
+    nodes(typos).len
+}
+
+/// Builds the trie of `typos`, panics on duplicates.
+///
+/// Typos are lowercase ASCII letters. A node is a header byte, the leaf flag
+/// and the number of children, the typo index if it is a leaf, then the
+/// letter and little endian offset of each child node. The root is at
+/// offset 0.
+pub const fn trie<const N: usize>(typos: &[(&str, &str)]) -> [u8; N] {
+    let nodes = nodes(typos);
+    let mut trie = [0; N];
+    let mut d = 0;
+    while d <= MAX_LEN {
+        let mut i = 0;
+        while i < typos.len() {
+            if typos[i].0.len() >= d && nodes.first[i][d] as usize == i {
+                let header = nodes.offset[i][d] as usize;
+                let mut pos = header + 1;
+                let mut j = 0;
+                while j < typos.len() {
+                    if typos[j].0.len() == d && nodes.first[j][d] as usize == i {
+                        assert!(trie[header] & LEAF == 0, "duplicate typo");
+                        trie[header] |= LEAF;
+                        trie[pos] = j as u8;
+                        pos += 1;
+                    }
+                    j += 1;
+                }
+                let mut j = 0;
+                while j < typos.len() {
+                    let typo = typos[j].0.as_bytes();
+                    if typo.len() > d
+                        && nodes.first[j][d] as usize == i
+                        && nodes.first[j][d + 1] as usize == j
+                    {
+                        let offset = nodes.offset[j][d + 1];
+                        trie[pos] = typo[d];
+                        trie[pos + 1] = offset as u8;
+                        trie[pos + 2] = (offset >> 8) as u8;
+                        trie[header] += 1;
+                        pos += 3;
+                    }
+                    j += 1;
+                }
+            }
+            i += 1;
+        }
+        d += 1;
+    }
+    trie
+}
+
+// Child of the node at `node` of `trie` for `letter`
+fn child(trie: &[u8], node: u16, letter: u8) -> Option<u16> {
+    let node = node as usize;
+    let header = trie[node];
+    let start = node + 1 + (header & LEAF != 0) as usize;
+    trie[start..start + 3 * (header & !LEAF) as usize]
+        .chunks(3)
+        .find(|entry| entry[0] == letter)
+        .map(|entry| u16::from_le_bytes([entry[1], entry[2]]))
+}
+
+// Typo ending at `node` of `trie`
+fn leaf(trie: &[u8], node: u16) -> Option<usize> {
+    let node = node as usize;
+    (trie[node] & LEAF != 0).then(|| trie[node + 1] as usize)
+}
+
+/// Corrects the typos of a table when a word ends.
+///
+/// The letters typed since the last word boundary are followed in the trie.
+/// When a typo is completed by a space, punctuation, Enter or Tab, the
+/// boundary key is held back while backspaces, the correction and the
+/// boundary are typed. Other keys without a character (arrows, shortcuts)
+/// stop the matching until the next boundary.
+pub struct Autocorrect {
+    typos: &'static [(&'static str, &'static str)],
+    trie: &'static [u8],
+    enabled: bool,
+    // nodes of the letters of the word, empty if it is unknown
+    path: Vec<u16, { MAX_LEN + 1 }>,
+    // letters typed past the last match
+    extra: u8,
+    capital: bool,
+    hidden: Option<KeyCode>,
+}
+
+impl Autocorrect {
+    /// Corrects the `typos`, compiled in `trie` by `trie(typos)`.
+    pub fn new(typos: &'static [(&'static str, &'static str)], trie: &'static [u8]) -> Self {
+        let mut autocorrect = Self {
+            typos,
+            trie,
+            enabled: true,
+            path: Vec::new(),
+            extra: 0,
+            capital: false,
+            hidden: None,
+        };
+        autocorrect.boundary();
+        autocorrect
+    }
+
+    pub fn toggle(&mut self) {
+        self.enabled = !self.enabled;
+        self.path.clear();
+    }
+
+    fn boundary(&mut self) {
+        self.path.clear();
+        let _ = self.path.push(0);
+        self.extra = 0;
+    }
+
+    fn letter(&mut self, c: char) {
+        let node = match self.path.last() {
+            Some(&node) => node,
+            None => return,
+        };
+        if self.path.len() == 1 && self.extra == 0 {
+            self.capital = c.is_ascii_uppercase();
+        }
+        if self.extra > 0 {
+            self.extra = self.extra.saturating_add(1);
+            return;
+        }
+        match child(self.trie, node, c.to_ascii_lowercase() as u8) {
+            Some(next) if self.path.push(next).is_ok() => (),
+            _ => self.extra = 1,
+        }
+    }
+
+    fn backspace(&mut self) {
+        if self.extra > 0 {
+            self.extra -= 1;
+        } else if self.path.len() > 1 {
+            self.path.pop();
+        } else {
+            self.path.clear();
+        }
+    }
+
+    // Typo the word typed so far, if it is one
+    fn typo(&self) -> Option<usize> {
+        match self.path.last() {
+            Some(&node) if self.enabled && self.extra == 0 => leaf(self.trie, node),
+            _ => None,
+        }
+    }
+
+    /// Follows the `presses` reaching the report `keys`, correcting the
+    /// word when a typo is completed.
+    pub fn filter(
+        &mut self,
+        keys: &mut Keys,
+        presses: &[Press],
+        sequence: &mut Sequence,
+        method: Method,
+        layout: HostLayout,
+    ) {
+        for press in presses.iter().filter(|press| keys.contains(&press.key)) {
+            match (press.char(layout), press.key) {
+                (Some(c), _) if c.is_ascii_alphabetic() => self.letter(c),
+                (None, KeyCode::BSpace) => self.backspace(),
+                (Some(_), _) | (None, KeyCode::Enter) | (None, KeyCode::Tab) => {
+                    if let Some(i) = self.typo() {
+                        self.correct(self.typos[i], *press, sequence, method, layout);
+                        self.hidden = Some(press.key);
+                    }
+                    self.boundary();
+                }
+                (None, _) => self.path.clear(),
+            }
+        }
+        if let Some(kc) = self.hidden {
+            if keys.contains(&kc) {
+                keys.retain(|&k| k != kc);
+            } else {
+                self.hidden = None;
+            }
+        }
+    }
+
+    fn correct(
+        &self,
+        (typo, correction): (&str, &str),
+        boundary: Press,
+        sequence: &mut Sequence,
+        method: Method,
+        layout: HostLayout,
+    ) {
+        let mut steps = Steps::new();
+        if !typo.chars().all(|_| tap(&mut steps, KeyCode::BSpace)) || !sequence.push(&steps) {
+            return;
+        }
+        let mut chars = correction.chars();
+        if let Some(first) = chars.next() {
+            let first = if self.capital {
+                first.to_ascii_uppercase()
+            } else {
+                first
+            };
+            let mut buf = [0; 4];
+            unicode::type_str(sequence, method, layout, first.encode_utf8(&mut buf));
+            unicode::type_str(sequence, method, layout, chars.as_str());
+        }
+        steps.clear();
+        if boundary.replay(&mut steps) {
+            sequence.push(&steps);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_host::{run, strokes};
+
+    const TYPOS: [(&str, &str); 5] = [
+        ("teh", "the"),
+        ("hte", "the"),
+        ("untill", "until"),
+        ("thier", "their"),
+        ("th", "this"),
+    ];
+    static TRIE: [u8; trie_len(&TYPOS)] = trie(&TYPOS);
+
+    fn typed(text: &str) -> std::string::String {
+        typed_with(&mut Autocorrect::new(&TYPOS, &TRIE), text)
+    }
+
+    fn typed_with(autocorrect: &mut Autocorrect, text: &str) -> std::string::String {
+        let layout = HostLayout::Us;
+        run(strokes(text, layout), layout, |keys, presses, sequence| {
+            autocorrect.filter(keys, presses, sequence, Method::Linux, layout)
+        })
+    }
+
+    // Typo of each word of the trie, following `child` and `leaf`
+    fn lookup(word: &str) -> Option<usize> {
+        let node = word
+            .bytes()
+            .try_fold(0, |node, letter| child(&TRIE, node, letter))?;
+        leaf(&TRIE, node)
+    }
+
+    #[test]
+    fn trie_holds_every_typo() {
+        for (i, (typo, _)) in TYPOS.iter().enumerate() {
+            assert_eq!(lookup(typo), Some(i), "{}", typo);
+        }
+        // Prefixes and extensions of the typos
+        for word in ["t", "te", "unti", "tehx", "thi", "x"] {
+            assert_eq!(lookup(word), None, "{}", word);
+        }
+    }
+
+    #[test]
+    fn trie_layout() {
+        const SMALL: [(&str, &str); 2] = [("ab", "x"), ("b", "y")];
+        let small: [u8; trie_len(&SMALL)] = trie(&SMALL);
+        #[rustfmt::skip]
+        assert_eq!(small, [
+            2, b'a', 7, 0, b'b', 11, 0, // root
+            1, b'b', 13, 0, // a
+            LEAF, 1, // b
+            LEAF, 0, // ab
+        ]);
+    }
+
+    #[test]
+    fn corrects_at_word_end() {
+        assert_eq!(typed("teh "), "the ");
+        assert_eq!(typed("Teh cat, hte dog."), "The cat, the dog.");
+        assert_eq!(typed("untill\n"), "until\n");
+        assert_eq!(typed("thier\tth "), "their\tthis ");
+    }
+
+    #[test]
+    fn keeps_other_words() {
+        assert_eq!(typed("tehx "), "tehx ");
+        assert_eq!(typed("xteh "), "xteh ");
+        assert_eq!(typed("unti "), "unti ");
+        assert_eq!(typed("teh"), "teh");
+    }
+
+    #[test]
+    fn follows_backspace() {
+        assert_eq!(typed("tex\x08h "), "the ");
+        assert_eq!(typed("tehx\x08 "), "the ");
+        assert_eq!(typed("teh\x08\x08\x08hte "), "the ");
+    }
+
+    #[test]
+    fn toggles() {
+        let mut autocorrect = Autocorrect::new(&TYPOS, &TRIE);
+        autocorrect.toggle();
+        assert_eq!(typed_with(&mut autocorrect, "teh "), "teh ");
+        autocorrect.toggle();
+        // The word typed when it is turned on is not known
+        assert_eq!(typed_with(&mut autocorrect, "teh teh "), "teh the ");
+    }
+}
@@ -0,0 +1,152 @@
+use heapless::Vec;
+use keyberon::key_code::KeyCode;
+
+use crate::keymap::HostLayout;
+use crate::sequence::{tap, Sequence, Steps};
+use crate::typing::{Keys, Press};
+use crate::unicode::{self, Method};
+
+/// What a compose sequence types.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Output {
+    Char(char),
+    /// Keys tapped one after the other
+    Keys(&'static [KeyCode]),
+}
+
+/// Compose key: the two characters typed after it are replaced by the
+/// output of their entry in the table.
+///
+/// A key without a character (Escape, arrows, shortcuts) cancels the
+/// sequence, typing the first character if any, and is sent as usual. An
+/// unknown pair is typed as it was.
+pub struct Compose {
+    table: &'static [([char; 2], Output)],
+    active: bool,
+    first: Option<(Press, char)>,
+    // keys typed in the sequence, hidden from the report until released
+    hidden: Vec<KeyCode, 4>,
+}
+
+impl Compose {
+    pub fn new(table: &'static [([char; 2], Output)]) -> Self {
+        Self {
+            table,
+            active: false,
+            first: None,
+            hidden: Vec::new(),
+        }
+    }
+
+    /// Starts a sequence, or cancels the current one.
+    pub fn toggle(&mut self) {
+        self.active = !self.active;
+        self.first = None;
+    }
+
+    /// Takes the `presses` of a sequence reaching the report `keys` out of
+    /// it, typing the result once it is complete.
+    pub fn filter(
+        &mut self,
+        keys: &mut Keys,
+        presses: &[Press],
+        sequence: &mut Sequence,
+        method: Method,
+        layout: HostLayout,
+    ) {
+        for press in presses.iter().filter(|press| keys.contains(&press.key)) {
+            if !self.active {
+                break;
+            }
+            let c = match press.char(layout) {
+                Some(c) => c,
+                None => {
+                    // The hidden first character is typed before the key
+                    if let Some((first, _)) = self.first {
+                        let mut steps = Steps::new();
+                        if first.replay(&mut steps) {
+                            sequence.push(&steps);
+                        }
+                    }
+                    self.toggle();
+                    break;
+                }
+            };
+            let _ = self.hidden.push(press.key);
+            let (first, c1) = match self.first.take() {
+                Some(first) => first,
+                None => {
+                    self.first = Some((*press, c));
+                    continue;
+                }
+            };
+            self.active = false;
+            let mut steps = Steps::new();
+            match self.table.iter().find(|(pair, _)| *pair == [c1, c]) {
+                Some((_, Output::Char(ch))) => {
+                    let mut buf = [0; 4];
+                    unicode::type_str(sequence, method, layout, ch.encode_utf8(&mut buf));
+                }
+                Some((_, Output::Keys(kcs))) => {
+                    if kcs.iter().all(|&kc| tap(&mut steps, kc)) {
+                        sequence.push(&steps);
+                    }
+                }
+                None => {
+                    if first.replay(&mut steps) && press.replay(&mut steps) {
+                        sequence.push(&steps);
+                    }
+                }
+            }
+        }
+        self.hidden.retain(|kc| keys.contains(kc));
+        keys.retain(|kc| !self.hidden.contains(kc));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_host::{self, strokes};
+    use keyberon::key_code::KeyCode::*;
+    use std::string::String;
+    use std::vec::Vec;
+
+    static TABLE: [([char; 2], Output); 2] = [
+        (['e', '\''], Output::Char('é')),
+        (['-', '>'], Output::Keys(&[Kb1, Kb2])),
+    ];
+
+    // Host text of the `reports`, with compose pressed before them
+    fn typed(reports: Vec<Vec<KeyCode>>) -> String {
+        let mut compose = Compose::new(&TABLE);
+        compose.toggle();
+        let layout = HostLayout::Us;
+        mock_host::run(reports, layout, |keys, presses, sequence| {
+            compose.filter(keys, presses, sequence, Method::Linux, layout)
+        })
+    }
+
+    #[test]
+    fn types_the_output() {
+        assert_eq!(typed(strokes("e'x", HostLayout::Us)), "éx");
+        assert_eq!(typed(strokes("->", HostLayout::Us)), "12");
+    }
+
+    #[test]
+    fn unknown_pair_typed_as_is() {
+        assert_eq!(typed(strokes("xy", HostLayout::Us)), "xy");
+    }
+
+    #[test]
+    fn key_without_char_cancels() {
+        // Enter held until the first character is typed again
+        let mut reports = strokes("a", HostLayout::Us);
+        reports.extend([vec![Enter], vec![Enter], vec![Enter], vec![]]);
+        reports.extend(strokes("e'", HostLayout::Us));
+        assert_eq!(typed(reports), "a\ne'");
+        let mut reports = vec![vec![Escape], vec![]];
+        reports.extend(strokes("e'", HostLayout::Us));
+        assert_eq!(typed(reports), "e'");
+    }
+}
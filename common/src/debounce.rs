@@ -0,0 +1,338 @@
+use keyberon::layout::Event;
+
+/// Debounce algorithm, fed one matrix scan per ms.
+pub trait Debounce<const C: usize, const R: usize> {
+    /// Takes a scan of the matrix.
+    fn update(&mut self, scan: &[[bool; C]; R]);
+    /// The debounced state of the keys.
+    fn get(&self) -> &[[bool; C]; R];
+    /// Sets the debounce time, in scans.
+    fn set_time(&mut self, time: u16);
+}
+
+/// Eager per-key debounce: a change is reported at once, then the key
+/// ignores the matrix for the debounce time. No added latency, but noise
+/// on an idle key is reported.
+pub struct EagerPerKey<const C: usize, const R: usize> {
+    state: [[bool; C]; R],
+    // scans left before the key follows the matrix again
+    locked: [[u16; C]; R],
+    time: u16,
+}
+
+impl<const C: usize, const R: usize> EagerPerKey<C, R> {
+    pub fn new(time: u16) -> Self {
+        Self {
+            state: [[false; C]; R],
+            locked: [[0; C]; R],
+            time,
+        }
+    }
+}
+
+impl<const C: usize, const R: usize> Debounce<C, R> for EagerPerKey<C, R> {
+    fn update(&mut self, scan: &[[bool; C]; R]) {
+        let keys = self.state.iter_mut().flatten();
+        let keys = keys.zip(self.locked.iter_mut().flatten());
+        for ((state, locked), &pressed) in keys.zip(scan.iter().flatten()) {
+            if *locked > 0 {
+                *locked -= 1;
+            } else if pressed != *state {
+                *state = pressed;
+                *locked = self.time;
+            }
+        }
+    }
+
+    fn get(&self) -> &[[bool; C]; R] {
+        &self.state
+    }
+
+    fn set_time(&mut self, time: u16) {
+        self.time = time;
+    }
+}
+
+/// Deferred per-key debounce: a key changes once the matrix has shown the
+/// new state for more than the debounce time, whatever the other keys do.
+pub struct DeferredPerKey<const C: usize, const R: usize> {
+    state: [[bool; C]; R],
+    // consecutive scans differing from the state
+    since: [[u16; C]; R],
+    time: u16,
+}
+
+impl<const C: usize, const R: usize> DeferredPerKey<C, R> {
+    pub fn new(time: u16) -> Self {
+        Self {
+            state: [[false; C]; R],
+            since: [[0; C]; R],
+            time,
+        }
+    }
+}
+
+impl<const C: usize, const R: usize> Debounce<C, R> for DeferredPerKey<C, R> {
+    fn update(&mut self, scan: &[[bool; C]; R]) {
+        let keys = self.state.iter_mut().flatten();
+        let keys = keys.zip(self.since.iter_mut().flatten());
+        for ((state, since), &pressed) in keys.zip(scan.iter().flatten()) {
+            if pressed == *state {
+                *since = 0;
+            } else if *since >= self.time {
+                *state = pressed;
+                *since = 0;
+            } else {
+                *since += 1;
+            }
+        }
+    }
+
+    fn get(&self) -> &[[bool; C]; R] {
+        &self.state
+    }
+
+    fn set_time(&mut self, time: u16) {
+        self.time = time;
+    }
+}
+
+/// Keyberon's debounce: the keys change once the whole matrix has been
+/// stable for more than the debounce time.
+pub struct WholeMatrix<const C: usize, const R: usize> {
+    debouncer: keyberon::debounce::Debouncer<[[bool; C]; R]>,
+}
+
+impl<const C: usize, const R: usize> WholeMatrix<C, R> {
+    pub fn new(time: u16) -> Self {
+        let state = [[false; C]; R];
+        Self {
+            debouncer: keyberon::debounce::Debouncer::new(state, state, time),
+        }
+    }
+}
+
+impl<const C: usize, const R: usize> Debounce<C, R> for WholeMatrix<C, R> {
+    fn update(&mut self, scan: &[[bool; C]; R]) {
+        self.debouncer.update(*scan);
+    }
+
+    fn get(&self) -> &[[bool; C]; R] {
+        self.debouncer.get()
+    }
+
+    fn set_time(&mut self, time: u16) {
+        let state = *self.debouncer.get();
+        self.debouncer = keyberon::debounce::Debouncer::new(state, state, time);
+    }
+}
+
+// A key pressed again this soon after its release is chattering, in scans
+const CHATTER: u16 = 30;
+// Debounce time added for each chatter event of a key, and its limit
+const CHATTER_STEP: u16 = 5;
+const CHATTER_MAX: u16 = 40;
+// Scans without chatter after which a step of the added time is taken back
+const CHATTER_DECAY: u16 = 1000;
+
+#[derive(Clone, Copy)]
+struct Key {
+    // debounced state before the last scan
+    debounced: bool,
+    // scans since the last change reported
+    since: u16,
+    // debounce time added to a chattering key
+    extra: u16,
+    // scans since the last chatter event or decay of `extra`
+    quiet: u16,
+    // chatter events since power-up
+    chatter: u16,
+}
+
+impl Key {
+    const NEW: Self = Self {
+        debounced: false,
+        since: u16::MAX,
+        extra: 0,
+        quiet: 0,
+        chatter: 0,
+    };
+
+    fn update(&mut self, state: &mut bool, debounced: bool) {
+        self.since = self.since.saturating_add(1);
+        self.quiet = self.quiet.saturating_add(1);
+        if debounced && !self.debounced && !*state && self.since <= CHATTER {
+            self.chatter = self.chatter.saturating_add(1);
+            self.extra = (self.extra + CHATTER_STEP).min(CHATTER_MAX);
+            self.quiet = 0;
+        } else if self.quiet >= CHATTER_DECAY {
+            self.extra = self.extra.saturating_sub(CHATTER_STEP);
+            self.quiet = 0;
+        }
+        self.debounced = debounced;
+        if debounced != *state && self.since > self.extra {
+            *state = debounced;
+            self.since = 0;
+        }
+    }
+}
+
+/// Key events from the changes of the state of a `Debounce`.
+///
+/// A key pressed again right after its release is counted as chattering,
+/// and its changes are delayed a bit more each time, then less again as it
+/// stops chattering.
+pub struct Debouncer<D, const C: usize, const R: usize> {
+    debounce: D,
+    last: [[bool; C]; R],
+    keys: [[Key; C]; R],
+}
+
+impl<D: Debounce<C, R>, const C: usize, const R: usize> Debouncer<D, C, R> {
+    pub fn new(debounce: D) -> Self {
+        let last = *debounce.get();
+        Self {
+            debounce,
+            last,
+            keys: [[Key::NEW; C]; R],
+        }
+    }
+
+    pub fn events(&mut self, scan: [[bool; C]; R]) -> impl Iterator<Item = Event> {
+        self.debounce.update(&scan);
+        let old = self.last;
+        let rows = self.last.iter_mut().zip(self.keys.iter_mut());
+        for ((states, keys), debounced) in rows.zip(self.debounce.get()) {
+            for ((state, key), &debounced) in states.iter_mut().zip(keys).zip(debounced) {
+                key.update(state, debounced);
+            }
+        }
+        let new = self.last;
+        (0..R).flat_map(move |i| {
+            (0..C).filter_map(move |j| match (old[i][j], new[i][j]) {
+                (false, true) => Some(Event::Press(i as u8, j as u8)),
+                (true, false) => Some(Event::Release(i as u8, j as u8)),
+                _ => None,
+            })
+        })
+    }
+
+    pub fn set_time(&mut self, time: u16) {
+        self.debounce.set_time(time);
+    }
+
+    /// Matrix coordinates and chatter count of the keys that chattered.
+    pub fn chatter(&self) -> impl Iterator<Item = (u8, u8, u16)> + '_ {
+        self.keys.iter().enumerate().flat_map(|(i, keys)| {
+            keys.iter()
+                .enumerate()
+                .filter(|(_, key)| key.chatter > 0)
+                .map(move |(j, key)| (i as u8, j as u8, key.chatter))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keyberon::layout::Event::{Press, Release};
+    use std::vec::Vec;
+
+    // Scans of a 1x2 matrix, a string per key with `#` for pressed
+    fn scans(keys: [&str; 2]) -> Vec<[[bool; 2]; 1]> {
+        let pressed = |key: &str, i| key.as_bytes().get(i) == Some(&b'#');
+        let len = keys[0].len().max(keys[1].len());
+        (0..len)
+            .map(|i| [[pressed(keys[0], i), pressed(keys[1], i)]])
+            .collect()
+    }
+
+    // Events with the index of the scan reporting them
+    fn trace<D: Debounce<2, 1>>(debounce: D, keys: [&str; 2]) -> Vec<(usize, Event)> {
+        let mut debouncer = Debouncer::new(debounce);
+        let mut events = Vec::new();
+        for (i, scan) in scans(keys).into_iter().enumerate() {
+            events.extend(debouncer.events(scan).map(|event| (i, event)));
+        }
+        events
+    }
+
+    #[test]
+    fn eager_adds_no_latency() {
+        // Reported on the first scan, the bounces hidden by the 5 scans
+        let keys = ["..#.#.####......................", ""];
+        let events = trace(EagerPerKey::new(5), keys);
+        assert_eq!(events, [(2, Press(0, 0)), (10, Release(0, 0))]);
+    }
+
+    #[test]
+    fn deferred_adds_time_plus_one_scans() {
+        // Both keys stable from scan 4, reported on the 6th scan
+        let keys = ["..#.##########.#........", "....######"];
+        let events = trace(DeferredPerKey::new(5), keys);
+        assert_eq!(
+            events,
+            [
+                (9, Press(0, 0)),
+                (9, Press(0, 1)),
+                (15, Release(0, 1)),
+                (21, Release(0, 0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn whole_matrix_waits_for_every_key() {
+        // The matrix changes last on scan 7, when the second key releases
+        let keys = ["..######################......", "....#.#"];
+        let events = trace(WholeMatrix::new(5), keys);
+        assert_eq!(events, [(12, Press(0, 0)), (29, Release(0, 0))]);
+        let keys = ["..##########", ""];
+        let events = trace(WholeMatrix::new(5), keys);
+        assert_eq!(events, [(7, Press(0, 0))]);
+    }
+
+    #[test]
+    fn chatter_delays_the_key() {
+        let mut debouncer = Debouncer::new(EagerPerKey::<2, 1>::new(1));
+        let mut events = Vec::new();
+        // Released and pressed again 10 scans later, twice
+        let key = "###..........###..........####################";
+        for scan in scans([key, ""]) {
+            events.push(debouncer.events(scan).count());
+        }
+        assert_eq!(debouncer.chatter().collect::<Vec<_>>(), [(0, 0, 2)]);
+        let at = |i: usize| events[i];
+        assert_eq!((at(0), at(3), at(13)), (1, 1, 1));
+        // 5 more scans after the first chatter, 10 after the second
+        assert_eq!((at(16), at(19)), (0, 1));
+        assert_eq!((at(26), at(30)), (0, 1));
+        assert_eq!(events.iter().sum::<usize>(), 5);
+    }
+    // Scans from the press of a 2 scan tap to its release being reported
+    fn tap(debouncer: &mut Debouncer<EagerPerKey<2, 1>, 2, 1>) -> usize {
+        let key = format!("{}##{}", ".".repeat(40), ".".repeat(40));
+        let mut events = Vec::new();
+        for (i, scan) in scans([&key, ""]).into_iter().enumerate() {
+            events.extend(debouncer.events(scan).map(|_| i));
+        }
+        events[1] - events[0]
+    }
+
+    #[test]
+    fn chatter_delay_wears_off() {
+        let mut debouncer = Debouncer::new(EagerPerKey::<2, 1>::new(1));
+        assert_eq!(tap(&mut debouncer), 2);
+        let key = "###..........###..........###.....";
+        for scan in scans([key, ""]) {
+            debouncer.events(scan).count();
+        }
+        assert_eq!(tap(&mut debouncer), 11);
+        // 5 scans less after each second without chatter
+        for scan in scans([&".".repeat(2000), ""]) {
+            debouncer.events(scan).count();
+        }
+        assert_eq!(tap(&mut debouncer), 2);
+        assert_eq!(debouncer.chatter().collect::<Vec<_>>(), [(0, 0, 2)]);
+    }
+}
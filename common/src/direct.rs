@@ -0,0 +1,28 @@
+use embedded_hal::digital::v2::InputPin;
+
+/// Keys wired from a GPIO to ground, outside of the matrix.
+///
+/// Each pin is read as a key of the layout at its own coordinate, in rows
+/// past the ones of the matrix.
+pub struct DirectPins<P, const N: usize> {
+    pins: [(P, (u8, u8)); N],
+}
+
+impl<P: InputPin<Error = E>, E, const N: usize> DirectPins<P, N> {
+    pub fn new(pins: [(P, (u8, u8)); N]) -> Self {
+        Self { pins }
+    }
+
+    /// The `matrix` scan with `RS - R` more rows holding the direct keys.
+    pub fn merge<const CS: usize, const R: usize, const RS: usize>(
+        &self,
+        matrix: [[bool; CS]; R],
+    ) -> Result<[[bool; CS]; RS], E> {
+        let mut keys = [[false; CS]; RS];
+        keys[..R].copy_from_slice(&matrix);
+        for (pin, (i, j)) in &self.pins {
+            keys[*i as usize][*j as usize] = pin.is_low()?;
+        }
+        Ok(keys)
+    }
+}
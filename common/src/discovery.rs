@@ -0,0 +1,200 @@
+use core::fmt::{self, Write};
+use heapless::Vec;
+
+use crate::matrix::settle;
+
+/// A GPIO that can either drive the matrix low or read it with a pull-up.
+pub trait Probe {
+    fn drive_low(&mut self);
+    fn pull_up(&mut self);
+    fn is_low(&self) -> bool;
+}
+
+/// Wiring discovery for new hand-wired builds: each candidate pin is driven
+/// low in turn while the others are read, to find which pins each key
+/// connects.
+///
+/// Keys are to be pressed one at a time, in the order of the layout. The
+/// pin driving a key through its diode is a row, the other a column, and
+/// rows and columns are listed in the order they are first found. After a
+/// pin changes the scan waits `settle` CPU cycles.
+pub struct Discovery<P, const N: usize> {
+    pins: [(&'static str, P); N],
+    settle: u32,
+    wiring: Wiring<N>,
+}
+
+impl<P: Probe, const N: usize> Discovery<P, N> {
+    pub fn new(mut pins: [(&'static str, P); N], settle: u32) -> Self {
+        for (_, pin) in pins.iter_mut() {
+            pin.pull_up();
+        }
+        Self {
+            pins,
+            settle,
+            wiring: Wiring::default(),
+        }
+    }
+
+    /// Scans every pin pair, returning the key found if it is a new one.
+    pub fn scan(&mut self) -> Option<usize> {
+        let mut links = [[false; N]; N];
+        for (driver, links) in links.iter_mut().enumerate() {
+            self.pins[driver].1.drive_low();
+            settle(self.settle);
+            for (reader, link) in links.iter_mut().enumerate() {
+                *link = reader != driver && self.pins[reader].1.is_low();
+            }
+            self.pins[driver].1.pull_up();
+            settle(self.settle);
+        }
+        self.wiring.update(&links)
+    }
+
+    /// Prints a key with its matrix coordinate and pins, then the suggested
+    /// rows and columns of `Matrix::new`.
+    pub fn report(&self, key: usize, w: &mut impl Write) -> fmt::Result {
+        let (row, col) = self.wiring.keys[key];
+        let (i, j) = self.wiring.coord(key);
+        let name = |pin: u8| self.pins[pin as usize].0;
+        write!(w, "key {} {}: {} {}\r\n", i, j, name(row), name(col))?;
+        w.write_str("rows:")?;
+        for &pin in &self.wiring.rows {
+            write!(w, " {}", name(pin))?;
+        }
+        w.write_str("\r\ncols:")?;
+        for &pin in &self.wiring.cols {
+            write!(w, " {}", name(pin))?;
+        }
+        w.write_str("\r\n")
+    }
+}
+
+/// Bookkeeping of the pin pairs found by the scans.
+#[derive(Default)]
+pub struct Wiring<const N: usize> {
+    // row and column pins of the keys found
+    keys: Vec<(u8, u8), 64>,
+    rows: Vec<u8, N>,
+    cols: Vec<u8, N>,
+}
+
+impl<const N: usize> Wiring<N> {
+    /// Takes the pins read low (`links[driver][reader]`) in a scan, and
+    /// records the key pressed if it is new.
+    ///
+    /// Scans with no key or several keys pressed are ignored, as are keys
+    /// too many for the list.
+    pub fn update(&mut self, links: &[[bool; N]; N]) -> Option<usize> {
+        let mut pairs = (0..N).flat_map(|d| (0..N).map(move |r| (d, r)));
+        let (d, r) = pairs.find(|&(d, r)| links[d][r])?;
+        // Without a diode the key also links the pins the other way
+        if pairs.any(|(d2, r2)| links[d2][r2] && (d2, r2) != (r, d)) {
+            return None;
+        }
+        let (d, r) = (d as u8, r as u8);
+        if self.keys.iter().any(|&key| key == (d, r) || key == (r, d)) {
+            return None;
+        }
+        let (row, col) = if links[r as usize][d as usize]
+            && (self.rows.contains(&r) || self.cols.contains(&d))
+        {
+            (r, d)
+        } else {
+            (d, r)
+        };
+        self.keys.push((row, col)).ok()?;
+        if !self.rows.contains(&row) {
+            let _ = self.rows.push(row);
+        }
+        if !self.cols.contains(&col) {
+            let _ = self.cols.push(col);
+        }
+        Some(self.keys.len() - 1)
+    }
+
+    /// Matrix coordinate of a key, (row, column).
+    pub fn coord(&self, key: usize) -> (usize, usize) {
+        let (row, col) = self.keys[key];
+        let index = |pins: &[u8], pin| pins.iter().position(|&p| p == pin).unwrap_or(0);
+        (index(&self.rows, row), index(&self.cols, col))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_net::Net;
+    use std::string::String;
+
+    // Links of a scan from the pin pairs read low
+    fn links(pairs: &[(usize, usize)]) -> [[bool; 5]; 5] {
+        let mut links = [[false; 5]; 5];
+        for &(driver, reader) in pairs {
+            links[driver][reader] = true;
+        }
+        links
+    }
+
+    #[test]
+    fn wiring_with_diodes() {
+        let mut wiring = Wiring::<5>::default();
+        assert_eq!(wiring.update(&links(&[])), None);
+        assert_eq!(wiring.update(&links(&[(4, 0)])), Some(0));
+        assert_eq!(wiring.update(&links(&[(4, 0)])), None);
+        assert_eq!(wiring.update(&links(&[(4, 3)])), Some(1));
+        assert_eq!(wiring.update(&links(&[(1, 3)])), Some(2));
+        assert_eq!(wiring.coord(2), (1, 1));
+        assert_eq!(
+            (&wiring.rows[..], &wiring.cols[..]),
+            (&[4, 1][..], &[0, 3][..])
+        );
+    }
+
+    #[test]
+    fn wiring_without_diode() {
+        let mut wiring = Wiring::<5>::default();
+        assert_eq!(wiring.update(&links(&[(4, 0)])), Some(0));
+        // Read both ways, the known row tells which pin is the row
+        assert_eq!(wiring.update(&links(&[(2, 4), (4, 2)])), Some(1));
+        assert_eq!(wiring.coord(1), (0, 1));
+        assert_eq!(wiring.update(&links(&[(0, 1), (1, 0)])), Some(2));
+        assert_eq!(wiring.coord(2), (1, 0));
+        assert_eq!(wiring.update(&links(&[(1, 0), (0, 1)])), None);
+    }
+
+    #[test]
+    fn wiring_ignores_two_keys() {
+        let mut wiring = Wiring::<5>::default();
+        assert_eq!(wiring.update(&links(&[(4, 0), (1, 3)])), None);
+        assert_eq!(wiring.update(&links(&[(4, 0), (0, 4), (4, 3)])), None);
+        assert_eq!(wiring.keys.len(), 0);
+    }
+
+    #[test]
+    fn discovers_the_matrix() {
+        let net = Net::default();
+        let names = ["a", "b", "c", "d", "e"];
+        let mut pins = net.pins::<5>().into_iter();
+        let mut discovery = Discovery::new(names.map(|name| (name, pins.next().unwrap())), 0);
+        let mut out = String::new();
+        // Rows e and b, columns a, d and c, the third key without a diode
+        let keys = [
+            (4, 0, true),
+            (4, 3, true),
+            (2, 4, false),
+            (1, 0, true),
+            (1, 3, true),
+        ];
+        for key in keys {
+            net.link(&[key]);
+            let found = discovery.scan().unwrap();
+            assert_eq!(discovery.scan(), None);
+            discovery.report(found, &mut out).unwrap();
+            net.link(&[]);
+            assert_eq!(discovery.scan(), None);
+        }
+        assert!(out.starts_with("key 0 0: e a\r\n"));
+        assert!(out.ends_with("key 1 1: b d\r\nrows: e b\r\ncols: a d c\r\n"));
+    }
+}
@@ -0,0 +1,115 @@
+use crate::discovery::Probe;
+use crate::matrix::settle;
+
+/// Duplex matrix: two keys sit on each row and column pin pair, with
+/// opposite diodes, so that `CP` column pins read `2 * CP` columns.
+///
+/// The rows are driven low in turn to read the even columns, then the
+/// column pins to read the odd ones: column pin `p` reads columns `2p` and
+/// `2p + 1`. Columns past `2 * CP` read released, as unwired columns.
+///
+/// Unlike a matrix with diodes, a duplex matrix ghosts: a driven row reaches
+/// another column pin back through the opposite diode of a pressed key.
+pub struct DuplexMatrix<P, const CP: usize, const CS: usize, const RS: usize> {
+    cols: [P; CP],
+    rows: [P; RS],
+    settle: u32,
+}
+
+impl<P: Probe, const CP: usize, const CS: usize, const RS: usize> DuplexMatrix<P, CP, CS, RS> {
+    pub fn new(mut cols: [P; CP], mut rows: [P; RS], settle: u32) -> Self {
+        assert!(2 * CP <= CS, "too many column pins");
+        for pin in cols.iter_mut().chain(rows.iter_mut()) {
+            pin.pull_up();
+        }
+        Self { cols, rows, settle }
+    }
+
+    pub fn get(&mut self) -> [[bool; CS]; RS] {
+        let mut keys = [[false; CS]; RS];
+        for (row, keys) in self.rows.iter_mut().zip(keys.iter_mut()) {
+            row.drive_low();
+            settle(self.settle);
+            for (keys, col) in keys.chunks_mut(2).zip(&self.cols) {
+                keys[0] = col.is_low();
+            }
+            row.pull_up();
+        }
+        for (p, col) in self.cols.iter_mut().enumerate() {
+            col.drive_low();
+            settle(self.settle);
+            for (keys, row) in keys.iter_mut().zip(&self.rows) {
+                keys[2 * p + 1] = row.is_low();
+            }
+            col.pull_up();
+        }
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_net::{Net, Pin};
+
+    // 3 column pins on pins 0 to 2, 2 rows on pins 3 and 4, and a seventh
+    // column left unwired
+    fn duplex(net: &Net) -> DuplexMatrix<Pin, 3, 7, 2> {
+        DuplexMatrix::new(net.pins(), [net.pin(3), net.pin(4)], 0)
+    }
+
+    // The link of the key at (i, j): its diode lets the row pull the column
+    // pin low for the even columns, the other way for the odd ones
+    // `is_multiple_of` is too recent for the toolchains building the boards
+    #[allow(clippy::manual_is_multiple_of)]
+    fn key(i: usize, j: usize) -> (usize, usize, bool) {
+        let (row, col) = (3 + i, j / 2);
+        if j % 2 == 0 {
+            (row, col, true)
+        } else {
+            (col, row, true)
+        }
+    }
+
+    #[test]
+    fn column_pin_reads_two_columns() {
+        let net = Net::default();
+        let mut matrix = duplex(&net);
+        assert_eq!(matrix.get(), [[false; 7]; 2]);
+        for i in 0..2 {
+            for j in 0..6 {
+                net.link(&[key(i, j)]);
+                let mut expected = [[false; 7]; 2];
+                expected[i][j] = true;
+                assert_eq!(matrix.get(), expected, "key {} {}", i, j);
+            }
+        }
+    }
+
+    #[test]
+    fn keys_sharing_a_pin_pair() {
+        let net = Net::default();
+        let mut matrix = duplex(&net);
+        net.link(&[key(1, 2), key(1, 3), key(0, 5)]);
+        let mut expected = [[false; 7]; 2];
+        expected[1][2] = true;
+        expected[1][3] = true;
+        expected[0][5] = true;
+        assert_eq!(matrix.get(), expected);
+    }
+
+    #[test]
+    fn three_keys_ghost_a_fourth() {
+        let net = Net::default();
+        let mut matrix = duplex(&net);
+        // Row 0 pulls column pin 0 low through (0, 0), then row 1 through
+        // (1, 1) and column pin 1 through (1, 2), reading (0, 2) as well
+        net.link(&[key(0, 0), key(1, 1), key(1, 2)]);
+        let mut expected = [[false; 7]; 2];
+        expected[0][0] = true;
+        expected[1][1] = true;
+        expected[1][2] = true;
+        expected[0][2] = true;
+        assert_eq!(matrix.get(), expected);
+    }
+}
@@ -0,0 +1,192 @@
+use heapless::Vec;
+use keyberon::action::{Action, HoldTapAction};
+use keyberon::key_code::KeyCode;
+use keyberon::layout::{Event, Layers};
+
+/// Resolves hold-tap keys as taps while typing fast ("require prior idle").
+///
+/// A hold-tap pressed less than its term after the previous non-modifier
+/// key press never reaches the `Layout`: its tap keycodes are reported from
+/// here until the key is released. While a hold-tap given to the layout is
+/// still held no tap is forced, so the output order is kept.
+pub struct FlowTap<const C: usize, const R: usize, const L: usize, T: 'static> {
+    layers: &'static Layers<C, R, L, T>,
+    terms: &'static [(Action<T>, u16)],
+    term: u16,
+    idle: u16,
+    // held hold-taps handed to the layout, possibly still undecided
+    pending: Vec<(u8, u8), 8>,
+    taps: Vec<((u8, u8), Action<T>), 8>,
+}
+
+impl<const C: usize, const R: usize, const L: usize, T> FlowTap<C, R, L, T>
+where
+    T: 'static + Copy + PartialEq,
+{
+    pub fn new(
+        layers: &'static Layers<C, R, L, T>,
+        terms: &'static [(Action<T>, u16)],
+        term: u16,
+    ) -> Self {
+        Self {
+            layers,
+            terms,
+            term,
+            idle: u16::MAX,
+            pending: Vec::new(),
+            taps: Vec::new(),
+        }
+    }
+
+    /// Filters an event, returning it if it must go to the layout.
+    pub fn event(&mut self, event: Event, layer: usize, default: usize) -> Option<Event> {
+        let coord = event.coord();
+        if let Some(i) = self.taps.iter().position(|&(c, _)| c == coord) {
+            if !event.is_press() {
+                self.taps.swap_remove(i);
+            }
+            return None;
+        }
+        if !event.is_press() {
+            self.pending.retain(|&c| c != coord);
+            return Some(event);
+        }
+
+        let action = self.action(layer, default, coord);
+        let idle = core::mem::replace(&mut self.idle, 0);
+        match action {
+            Action::KeyCode(kc) if kc.is_modifier() => self.idle = idle,
+            Action::Layer(_) => self.idle = idle,
+            Action::HoldTap(ht) => {
+                if idle < self.term_of(ht)
+                    && self.pending.is_empty()
+                    && is_keycodes(&ht.tap)
+                    && self.taps.push((coord, ht.tap)).is_ok()
+                {
+                    return None;
+                }
+                let _ = self.pending.push(coord);
+            }
+            _ => (),
+        }
+        Some(event)
+    }
+
+    /// A hold-tap handed to the layout is held, possibly still undecided.
+    pub fn is_holding(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    pub fn tick(&mut self) {
+        self.idle = self.idle.saturating_add(1);
+    }
+
+    pub fn keycodes(&self) -> impl Iterator<Item = KeyCode> + '_ {
+        self.taps.iter().flat_map(|(_, action)| {
+            let (single, multiple): (Option<KeyCode>, &'static [KeyCode]) = match *action {
+                Action::KeyCode(kc) => (Some(kc), &[]),
+                Action::MultipleKeyCodes(kcs) => (None, *kcs),
+                _ => (None, &[]),
+            };
+            single.into_iter().chain(multiple.iter().copied())
+        })
+    }
+
+    fn term_of(&self, ht: &HoldTapAction<T>) -> u16 {
+        self.terms
+            .iter()
+            .find(|(action, _)| matches!(action, Action::HoldTap(other) if *other == ht))
+            .map_or(self.term, |&(_, term)| term)
+    }
+
+    fn action(&self, layer: usize, default: usize, (i, j): (u8, u8)) -> Action<T> {
+        let (i, j) = (i as usize, j as usize);
+        match self.layers[layer][i][j] {
+            Action::Trans => self.layers[default][i][j],
+            action => action,
+        }
+    }
+}
+
+fn is_keycodes<T>(action: &Action<T>) -> bool {
+    matches!(action, Action::KeyCode(_) | Action::MultipleKeyCodes(_))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keyberon::action::{k, HoldTapConfig};
+    use keyberon::key_code::KeyCode::*;
+    use keyberon::layout::Event::{Press, Release};
+
+    static A_LS: HoldTapAction<()> = HoldTapAction {
+        timeout: 200,
+        hold: k(LShift),
+        tap: k(A),
+        config: HoldTapConfig::Default,
+        tap_hold_interval: 0,
+    };
+    static LAYERS: Layers<4, 1, 1, ()> = [[[
+        Action::HoldTap(&A_LS),
+        k(B),
+        k(LShift),
+        Action::HoldTap(&A_LS),
+    ]]];
+    static TERMS: [(Action<()>, u16); 1] = [(Action::HoldTap(&A_LS), 100)];
+
+    fn flow_tap(terms: &'static [(Action<()>, u16)]) -> FlowTap<4, 1, 1, ()> {
+        FlowTap::new(&LAYERS, terms, 200)
+    }
+
+    fn idle(flow_tap: &mut FlowTap<4, 1, 1, ()>, ms: u16) {
+        for _ in 0..ms {
+            flow_tap.tick();
+        }
+    }
+
+    #[test]
+    fn taps_while_typing() {
+        let mut flow_tap = flow_tap(&[]);
+        assert_eq!(flow_tap.event(Press(0, 1), 0, 0), Some(Press(0, 1)));
+        idle(&mut flow_tap, 50);
+        assert_eq!(flow_tap.event(Press(0, 0), 0, 0), None);
+        assert_eq!(flow_tap.keycodes().collect::<std::vec::Vec<_>>(), [A]);
+        assert_eq!(flow_tap.event(Release(0, 0), 0, 0), None);
+        assert_eq!(flow_tap.keycodes().count(), 0);
+    }
+
+    #[test]
+    fn holds_after_idle() {
+        let mut flow_tap = flow_tap(&[]);
+        assert_eq!(flow_tap.event(Press(0, 0), 0, 0), Some(Press(0, 0)));
+        assert_eq!(flow_tap.event(Release(0, 0), 0, 0), Some(Release(0, 0)));
+        assert_eq!(flow_tap.event(Press(0, 1), 0, 0), Some(Press(0, 1)));
+        idle(&mut flow_tap, 200);
+        assert_eq!(flow_tap.event(Press(0, 0), 0, 0), Some(Press(0, 0)));
+        // Not forced while a hold-tap of the layout is held
+        assert_eq!(flow_tap.event(Press(0, 3), 0, 0), Some(Press(0, 3)));
+        assert_eq!(flow_tap.keycodes().count(), 0);
+        assert!(flow_tap.is_holding());
+        flow_tap.event(Release(0, 0), 0, 0);
+        assert!(flow_tap.is_holding());
+        flow_tap.event(Release(0, 3), 0, 0);
+        assert!(!flow_tap.is_holding());
+    }
+
+    #[test]
+    fn modifiers_keep_idle() {
+        let mut flow_tap = flow_tap(&[]);
+        assert_eq!(flow_tap.event(Press(0, 1), 0, 0), Some(Press(0, 1)));
+        idle(&mut flow_tap, 200);
+        assert_eq!(flow_tap.event(Press(0, 2), 0, 0), Some(Press(0, 2)));
+        assert_eq!(flow_tap.event(Press(0, 0), 0, 0), Some(Press(0, 0)));
+    }
+
+    #[test]
+    fn term_per_key() {
+        let mut flow_tap = flow_tap(&TERMS);
+        assert_eq!(flow_tap.event(Press(0, 1), 0, 0), Some(Press(0, 1)));
+        idle(&mut flow_tap, 150);
+        assert_eq!(flow_tap.event(Press(0, 0), 0, 0), Some(Press(0, 0)));
+    }
+}
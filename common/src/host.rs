@@ -0,0 +1,148 @@
+use core::fmt::Write;
+use heapless::{String, Vec};
+use usb_device::bus::UsbBus;
+use usbd_serial::SerialPort;
+
+use crate::self_test::Fault;
+use crate::settings::{Error, Settings};
+
+/// Line based command channel on the USB serial port:
+///
+/// - `get [name]` prints one or all settings
+/// - `set <name> <value>` changes a setting
+/// - `reset` restores the defaults
+/// - `chatter` prints the row, column and chatter count of the switches
+///   that chattered since power-up
+/// - `selftest` prints the faults found by the self-test at power-up
+pub struct Host {
+    defaults: Settings,
+    line: Vec<u8, 64>,
+    reply: String<256>,
+    report_chatter: bool,
+    report_faults: bool,
+}
+
+impl Host {
+    pub fn new(defaults: Settings) -> Self {
+        Self {
+            defaults,
+            line: Vec::new(),
+            reply: String::new(),
+            report_chatter: false,
+            report_faults: false,
+        }
+    }
+
+    pub fn poll<B: UsbBus>(
+        &mut self,
+        serial: &mut SerialPort<'_, B>,
+        settings: &mut Settings,
+        chatter: impl Iterator<Item = (u8, u8, u16)>,
+        faults: impl Iterator<Item = Fault>,
+    ) {
+        let mut buf = [0; 64];
+        while let Ok(len) = serial.read(&mut buf) {
+            if len == 0 {
+                break;
+            }
+            for &b in &buf[..len] {
+                self.byte(b, settings);
+            }
+        }
+        if core::mem::take(&mut self.report_chatter) {
+            let mut any = false;
+            for (row, col, count) in chatter {
+                let _ = write!(self.reply, "chatter {} {} {}\r\n", row, col, count);
+                any = true;
+            }
+            if !any {
+                self.ok();
+            }
+        }
+        if core::mem::take(&mut self.report_faults) {
+            let mut any = false;
+            for fault in faults {
+                let _ = write!(self.reply, "{}\r\n", fault);
+                any = true;
+            }
+            if !any {
+                self.ok();
+            }
+        }
+        if !self.reply.is_empty() {
+            // nobody listening is not an error, the reply is dropped
+            let _ = serial.write(self.reply.as_bytes());
+            self.reply.clear();
+        }
+    }
+
+    fn byte(&mut self, b: u8, settings: &mut Settings) {
+        match b {
+            b'\r' | b'\n' => {
+                let line = core::mem::take(&mut self.line);
+                match core::str::from_utf8(&line) {
+                    Ok(line) if !line.trim().is_empty() => self.command(line, settings),
+                    Ok(_) => (),
+                    Err(_) => self.error("invalid utf-8"),
+                }
+            }
+            b => {
+                if self.line.push(b).is_err() {
+                    self.line.clear();
+                    self.error("line too long");
+                }
+            }
+        }
+    }
+
+    fn command(&mut self, line: &str, settings: &mut Settings) {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("get"), None, None) => {
+                for name in Settings::names() {
+                    self.value(name, settings);
+                }
+            }
+            (Some("get"), Some(name), None) => match settings.get(name) {
+                Some(_) => self.value(name, settings),
+                None => self.error("unknown setting"),
+            },
+            (Some("set"), Some(name), Some(value)) => match value.parse() {
+                Ok(value) => match settings.set(name, value) {
+                    Ok(()) => self.ok(),
+                    Err(Error::UnknownName) => self.error("unknown setting"),
+                    Err(Error::OutOfRange) => self.error("out of range"),
+                },
+                Err(_) => self.error("invalid value"),
+            },
+            (Some("reset"), None, None) => {
+                *settings = self.defaults;
+                self.ok();
+            }
+            (Some("chatter"), None, None) => self.report_chatter = true,
+            (Some("selftest"), None, None) => self.report_faults = true,
+            _ => self.error("unknown command"),
+        }
+    }
+
+    fn value(&mut self, name: &str, settings: &Settings) {
+        if let Some(value) = settings.get(name) {
+            let _ = write!(self.reply, "{} {}\r\n", name, value);
+        }
+    }
+
+    fn ok(&mut self) {
+        let _ = self.reply.push_str("ok\r\n");
+    }
+
+    fn error(&mut self, msg: &str) {
+        let _ = write!(self.reply, "error: {}\r\n", msg);
+    }
+}
+
+/// Text sent with the next reply.
+impl Write for Host {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.reply.push_str(s).map_err(|_| core::fmt::Error)
+    }
+}
@@ -0,0 +1,389 @@
+use heapless::{Deque, Vec};
+use keyberon::layout::Event;
+
+// Kind of a word, in its top 2 bits
+const PRESS: u32 = 0;
+const RELEASE: u32 = 1;
+const CHATTER: u32 = 2;
+const CONTROL: u32 = 3;
+
+// Control words, core 1 to core 0
+const PAUSED: u32 = CONTROL << 30;
+// Control words, core 0 to core 1
+const DEBOUNCE: u32 = CONTROL << 30;
+const PAUSE: u32 = CONTROL << 30 | 1 << 16;
+/// Word releasing core 1 once the flash written, read by its RAM loop.
+pub const RESUME: u32 = CONTROL << 30 | 2 << 16;
+
+/// Message of core 1, scanning and debouncing the matrix, to core 0, as one
+/// word of the SIO FIFO: its kind, row, column and value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Message {
+    Event(Event),
+    /// New chatter count of a key.
+    Chatter(u8, u8, u16),
+    /// Core 1 waits for `RESUME`, running from RAM.
+    Paused,
+}
+
+impl Message {
+    pub fn to_word(self) -> u32 {
+        let word = |kind: u32, i: u8, j: u8, value: u16| {
+            kind << 30 | (i as u32) << 24 | (j as u32) << 16 | value as u32
+        };
+        match self {
+            Message::Event(Event::Press(i, j)) => word(PRESS, i, j, 0),
+            Message::Event(Event::Release(i, j)) => word(RELEASE, i, j, 0),
+            Message::Chatter(i, j, count) => word(CHATTER, i, j, count),
+            Message::Paused => PAUSED,
+        }
+    }
+
+    pub fn from_word(word: u32) -> Option<Self> {
+        let (i, j) = ((word >> 24) as u8 & 0x3f, (word >> 16) as u8);
+        match word >> 30 {
+            PRESS => Some(Message::Event(Event::Press(i, j))),
+            RELEASE => Some(Message::Event(Event::Release(i, j))),
+            CHATTER => Some(Message::Chatter(i, j, word as u16)),
+            _ if word == PAUSED => Some(Message::Paused),
+            _ => None,
+        }
+    }
+}
+
+/// Command of core 0 to core 1, as one word of the SIO FIFO.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// New debounce time, in ms.
+    Debounce(u16),
+    /// Stop running from flash until `RESUME`, answered by `Paused`.
+    Pause,
+}
+
+impl Command {
+    pub fn to_word(self) -> u32 {
+        match self {
+            Command::Debounce(time) => DEBOUNCE | time as u32,
+            Command::Pause => PAUSE,
+        }
+    }
+
+    pub fn from_word(word: u32) -> Option<Self> {
+        match word & !0xffff {
+            DEBOUNCE => Some(Command::Debounce(word as u16)),
+            PAUSE => Some(Command::Pause),
+            _ => None,
+        }
+    }
+}
+
+/// Messages of core 1 waiting for room in the 8 words of the FIFO.
+pub struct Outbox<const C: usize, const R: usize, const N: usize> {
+    words: Deque<u32, N>,
+    // chatter counts already sent
+    chatter: [[u16; C]; R],
+}
+
+impl<const C: usize, const R: usize, const N: usize> Default for Outbox<C, R, N> {
+    fn default() -> Self {
+        Self {
+            words: Deque::new(),
+            chatter: [[0; C]; R],
+        }
+    }
+}
+
+impl<const C: usize, const R: usize, const N: usize> Outbox<C, R, N> {
+    /// Queues `message`, or nothing if the queue is full, for core 1 to
+    /// flush and try again.
+    pub fn push(&mut self, message: Message) -> bool {
+        self.words.push_back(message.to_word()).is_ok()
+    }
+
+    /// Queues the chatter counts changed since the last call, the ones not
+    /// fitting the queue being sent by a later call.
+    pub fn chatter(&mut self, chatter: impl Iterator<Item = (u8, u8, u16)>) {
+        for (i, j, count) in chatter {
+            let sent = &mut self.chatter[i as usize][j as usize];
+            if *sent != count
+                && self
+                    .words
+                    .push_back(Message::Chatter(i, j, count).to_word())
+                    .is_ok()
+            {
+                *sent = count;
+            }
+        }
+    }
+
+    /// Writes the queued words, in order, until `write` finds the FIFO full.
+    pub fn flush(&mut self, mut write: impl FnMut(u32) -> bool) {
+        while let Some(&word) = self.words.front() {
+            if !write(word) {
+                break;
+            }
+            self.words.pop_front();
+        }
+    }
+}
+
+// State of core 1, as seen by core 0
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Core1 {
+    Running,
+    // `Pause` sent, `Paused` not read yet
+    Pausing,
+    Paused,
+}
+
+/// Messages of core 1 received by core 0.
+///
+/// Words are only read from the FIFO while there is room for their events,
+/// so that core 1 waits for core 0 rather than losing any.
+pub struct Inbox<const C: usize, const R: usize> {
+    events: Deque<Event, 16>,
+    chatter: [[u16; C]; R],
+    core1: Core1,
+}
+
+impl<const C: usize, const R: usize> Default for Inbox<C, R> {
+    fn default() -> Self {
+        Self {
+            events: Deque::new(),
+            chatter: [[0; C]; R],
+            core1: Core1::Running,
+        }
+    }
+}
+
+impl<const C: usize, const R: usize> Inbox<C, R> {
+    /// Reads the words of `fifo` with `read` while there is room for their
+    /// events.
+    pub fn receive<F>(&mut self, fifo: &mut F, read: fn(&mut F) -> Option<u32>) {
+        while !self.events.is_full() {
+            match read(fifo) {
+                Some(word) => self.take(word),
+                None => break,
+            }
+        }
+    }
+
+    /// Asks core 1 to pause, once, then reads `fifo` until it answers
+    /// `Paused` or the inbox is full. True once core 1 is paused, otherwise
+    /// the tick should go on and ask again.
+    pub fn pause<F>(
+        &mut self,
+        fifo: &mut F,
+        write: fn(&mut F, u32),
+        read: fn(&mut F) -> Option<u32>,
+    ) -> bool {
+        if self.core1 == Core1::Running {
+            write(fifo, Command::Pause.to_word());
+            self.core1 = Core1::Pausing;
+        }
+        while self.core1 == Core1::Pausing && !self.events.is_full() {
+            if let Some(word) = read(fifo) {
+                self.take(word);
+            }
+        }
+        self.core1 == Core1::Paused
+    }
+
+    /// Releases core 1, once paused.
+    pub fn resume<F>(&mut self, fifo: &mut F, write: fn(&mut F, u32)) {
+        if self.core1 == Core1::Paused {
+            write(fifo, RESUME);
+            self.core1 = Core1::Running;
+        }
+    }
+
+    // Takes a word read from the FIFO, with room for its event
+    fn take(&mut self, word: u32) {
+        match Message::from_word(word) {
+            Some(Message::Event(event)) => {
+                let _ = self.events.push_back(event);
+            }
+            Some(Message::Chatter(i, j, count)) => {
+                if let Some(sent) = self
+                    .chatter
+                    .get_mut(i as usize)
+                    .and_then(|keys| keys.get_mut(j as usize))
+                {
+                    *sent = count;
+                }
+            }
+            Some(Message::Paused) => self.core1 = Core1::Paused,
+            None => (),
+        }
+    }
+
+    /// The events for this tick, at most the 8 taken by chording.
+    pub fn events(&mut self) -> Vec<Event, 8> {
+        let mut events = Vec::new();
+        while !events.is_full() {
+            match self.events.pop_front() {
+                Some(event) => events.push(event).unwrap(),
+                None => break,
+            }
+        }
+        events
+    }
+
+    /// Matrix coordinates and chatter count of the keys that chattered.
+    pub fn chatter(&self) -> impl Iterator<Item = (u8, u8, u16)> + '_ {
+        self.chatter.iter().enumerate().flat_map(|(i, keys)| {
+            keys.iter()
+                .enumerate()
+                .filter(|(_, &count)| count > 0)
+                .map(move |(j, &count)| (i as u8, j as u8, count))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn messages_round_trip() {
+        let messages = [
+            Message::Event(Event::Press(0, 0)),
+            Message::Event(Event::Release(3, 13)),
+            Message::Event(Event::Press(63, 255)),
+            Message::Chatter(2, 13, 0xffff),
+            Message::Chatter(0, 0, 1),
+            Message::Paused,
+        ];
+        for message in messages {
+            assert_eq!(Message::from_word(message.to_word()), Some(message));
+        }
+        assert_eq!(Message::from_word(RESUME), None);
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        let commands = [
+            Command::Debounce(0),
+            Command::Debounce(0xffff),
+            Command::Pause,
+        ];
+        for command in commands {
+            assert_eq!(Command::from_word(command.to_word()), Some(command));
+        }
+        assert_eq!(Command::from_word(RESUME), None);
+    }
+
+    // A FIFO of `room` words
+    fn fifo(words: &mut VecDeque<u32>, room: usize) -> impl FnMut(u32) -> bool + '_ {
+        move |word| {
+            let ready = words.len() < room;
+            if ready {
+                words.push_back(word);
+            }
+            ready
+        }
+    }
+
+    fn press(j: u8) -> Message {
+        Message::Event(Event::Press(0, j))
+    }
+
+    #[test]
+    fn outbox_waits_for_room() {
+        let mut outbox: Outbox<4, 1, 3> = Outbox::default();
+        assert!(outbox.push(press(0)) && outbox.push(press(1)) && outbox.push(press(2)));
+        assert!(!outbox.push(press(3)));
+        let mut words = VecDeque::new();
+        outbox.flush(fifo(&mut words, 2));
+        assert!(outbox.push(press(3)));
+        outbox.flush(fifo(&mut words, 8));
+        let sent: Vec<_, 4> = words.into_iter().map(Message::from_word).collect();
+        let expected: Vec<_, 4> = (0..4).map(|j| Some(press(j))).collect();
+        assert_eq!(sent, expected);
+    }
+
+    #[test]
+    fn outbox_sends_changed_chatter() {
+        let mut outbox: Outbox<4, 1, 2> = Outbox::default();
+        let counts = [(0, 1, 1), (0, 2, 0), (0, 3, 5)];
+        outbox.push(press(0));
+        outbox.chatter(counts.into_iter());
+        let mut words = VecDeque::new();
+        outbox.flush(fifo(&mut words, 8));
+        // Key 3 did not fit, it is sent with the next counts
+        outbox.chatter(counts.into_iter());
+        outbox.flush(fifo(&mut words, 8));
+        let sent: Vec<_, 4> = words.into_iter().map(Message::from_word).collect();
+        let expected = [
+            press(0),
+            Message::Chatter(0, 1, 1),
+            Message::Chatter(0, 3, 5),
+        ];
+        assert_eq!(sent, expected.map(Some));
+    }
+
+    // Core 0's side of the FIFO, the words of core 1 to read and the
+    // commands written
+    #[derive(Default)]
+    struct MockFifo {
+        words: VecDeque<u32>,
+        commands: std::vec::Vec<u32>,
+    }
+
+    impl MockFifo {
+        fn new(words: impl Iterator<Item = Message>) -> Self {
+            let words = words.map(Message::to_word).collect();
+            Self {
+                words,
+                ..Self::default()
+            }
+        }
+
+        fn read(&mut self) -> Option<u32> {
+            self.words.pop_front()
+        }
+
+        fn write(&mut self, word: u32) {
+            self.commands.push(word);
+        }
+    }
+
+    #[test]
+    fn inbox_reads_while_there_is_room() {
+        let mut inbox: Inbox<20, 1> = Inbox::default();
+        let mut fifo = MockFifo::new((0..20).map(press));
+        fifo.words.insert(3, Message::Chatter(0, 2, 7).to_word());
+        inbox.receive(&mut fifo, MockFifo::read);
+        // 16 events read, the rest left in the FIFO
+        assert_eq!(fifo.words.len(), 4);
+        let first = inbox.events();
+        assert_eq!(first.len(), 8);
+        assert_eq!(first[0], Event::Press(0, 0));
+        inbox.receive(&mut fifo, MockFifo::read);
+        assert!(fifo.words.is_empty());
+        let events: Vec<_, 32> = (0..2).flat_map(|_| inbox.events()).collect();
+        assert_eq!(events.len(), 12);
+        assert_eq!(events[11], Event::Press(0, 19));
+        assert!(inbox.chatter().eq([(0, 2, 7)]));
+    }
+
+    #[test]
+    fn inbox_pauses_core1() {
+        let mut inbox: Inbox<20, 1> = Inbox::default();
+        let mut fifo = MockFifo::new((0..18).map(press));
+        // Full before `Paused`, core 1 is asked once
+        assert!(!inbox.pause(&mut fifo, MockFifo::write, MockFifo::read));
+        assert_eq!(fifo.words.len(), 2);
+        inbox.events();
+        fifo.words.push_back(Message::Paused.to_word());
+        assert!(inbox.pause(&mut fifo, MockFifo::write, MockFifo::read));
+        assert_eq!(fifo.commands, [Command::Pause.to_word()]);
+        inbox.resume(&mut fifo, MockFifo::write);
+        inbox.resume(&mut fifo, MockFifo::write);
+        assert_eq!(fifo.commands, [Command::Pause.to_word(), RESUME]);
+        // The events read while pausing are kept
+        let events: Vec<_, 32> = (0..2).flat_map(|_| inbox.events()).collect();
+        assert_eq!(events.len(), 10);
+    }
+}
@@ -0,0 +1,249 @@
+use keyberon::key_code::KeyCode::{self, *};
+
+use crate::sequence::{tap, Step, Steps};
+
+/// Software keyboard layout of the host, stored in the `host_layout`
+/// setting. Text typed by the firmware is translated through it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HostLayout {
+    Us,
+    Uk,
+    De,
+    Fr,
+    /// Swedish and Finnish
+    Nordic,
+}
+
+/// Key typing a character and the modifiers held with it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Stroke {
+    pub key: KeyCode,
+    pub shift: bool,
+    pub altgr: bool,
+    /// Dead key, followed by Space to type the character itself
+    pub dead: bool,
+}
+
+// Stroke flags: shift, AltGr, dead key
+const SH: u8 = 1;
+const AG: u8 = 2;
+const DK: u8 = 4;
+
+// Space and the printable ASCII symbols, letters and digits are mapped in
+// `HostLayout::stroke`
+#[rustfmt::skip]
+const US: [(char, KeyCode, u8); 33] = [
+    (' ', Space, 0), ('!', Kb1, SH), ('"', Quote, SH), ('#', Kb3, SH), ('$', Kb4, SH),
+    ('%', Kb5, SH), ('&', Kb7, SH), ('\'', Quote, 0), ('(', Kb9, SH), (')', Kb0, SH),
+    ('*', Kb8, SH), ('+', Equal, SH), (',', Comma, 0), ('-', Minus, 0), ('.', Dot, 0),
+    ('/', Slash, 0), (':', SColon, SH), (';', SColon, 0), ('<', Comma, SH), ('=', Equal, 0),
+    ('>', Dot, SH), ('?', Slash, SH), ('@', Kb2, SH), ('[', LBracket, 0), ('\\', Bslash, 0),
+    (']', RBracket, 0), ('^', Kb6, SH), ('_', Minus, SH), ('`', Grave, 0), ('{', LBracket, SH),
+    ('|', Bslash, SH), ('}', RBracket, SH), ('~', Grave, SH),
+];
+
+#[rustfmt::skip]
+const UK: [(char, KeyCode, u8); 33] = [
+    (' ', Space, 0), ('!', Kb1, SH), ('"', Kb2, SH), ('#', NonUsHash, 0), ('$', Kb4, SH),
+    ('%', Kb5, SH), ('&', Kb7, SH), ('\'', Quote, 0), ('(', Kb9, SH), (')', Kb0, SH),
+    ('*', Kb8, SH), ('+', Equal, SH), (',', Comma, 0), ('-', Minus, 0), ('.', Dot, 0),
+    ('/', Slash, 0), (':', SColon, SH), (';', SColon, 0), ('<', Comma, SH), ('=', Equal, 0),
+    ('>', Dot, SH), ('?', Slash, SH), ('@', Quote, SH), ('[', LBracket, 0),
+    ('\\', NonUsBslash, 0), (']', RBracket, 0), ('^', Kb6, SH), ('_', Minus, SH),
+    ('`', Grave, 0), ('{', LBracket, SH), ('|', NonUsBslash, SH), ('}', RBracket, SH),
+    ('~', NonUsHash, SH),
+];
+
+#[rustfmt::skip]
+const DE: [(char, KeyCode, u8); 33] = [
+    (' ', Space, 0), ('!', Kb1, SH), ('"', Kb2, SH), ('#', NonUsHash, 0), ('$', Kb4, SH),
+    ('%', Kb5, SH), ('&', Kb6, SH), ('\'', NonUsHash, SH), ('(', Kb8, SH), (')', Kb9, SH),
+    ('*', RBracket, SH), ('+', RBracket, 0), (',', Comma, 0), ('-', Slash, 0), ('.', Dot, 0),
+    ('/', Kb7, SH), (':', Dot, SH), (';', Comma, SH), ('<', NonUsBslash, 0), ('=', Kb0, SH),
+    ('>', NonUsBslash, SH), ('?', Minus, SH), ('@', Q, AG), ('[', Kb8, AG), ('\\', Minus, AG),
+    (']', Kb9, AG), ('^', Grave, DK), ('_', Slash, SH), ('`', Equal, SH | DK), ('{', Kb7, AG),
+    ('|', NonUsBslash, AG), ('}', Kb0, AG), ('~', RBracket, AG),
+];
+
+#[rustfmt::skip]
+const FR: [(char, KeyCode, u8); 33] = [
+    (' ', Space, 0), ('!', Slash, 0), ('"', Kb3, 0), ('#', Kb3, AG), ('$', RBracket, 0),
+    ('%', Quote, SH), ('&', Kb1, 0), ('\'', Kb4, 0), ('(', Kb5, 0), (')', Minus, 0),
+    ('*', NonUsHash, 0), ('+', Equal, SH), (',', M, 0), ('-', Kb6, 0), ('.', Comma, SH),
+    ('/', Dot, SH), (':', Dot, 0), (';', Comma, 0), ('<', NonUsBslash, 0), ('=', Equal, 0),
+    ('>', NonUsBslash, SH), ('?', M, SH), ('@', Kb0, AG), ('[', Kb5, AG), ('\\', Kb8, AG),
+    (']', Minus, AG), ('^', Kb9, AG), ('_', Kb8, 0), ('`', Kb7, AG | DK), ('{', Kb4, AG),
+    ('|', Kb6, AG), ('}', Equal, AG), ('~', Kb2, AG | DK),
+];
+
+#[rustfmt::skip]
+const NORDIC: [(char, KeyCode, u8); 33] = [
+    (' ', Space, 0), ('!', Kb1, SH), ('"', Kb2, SH), ('#', Kb3, SH), ('$', Kb4, AG),
+    ('%', Kb5, SH), ('&', Kb6, SH), ('\'', NonUsHash, 0), ('(', Kb8, SH), (')', Kb9, SH),
+    ('*', NonUsHash, SH), ('+', Minus, 0), (',', Comma, 0), ('-', Slash, 0), ('.', Dot, 0),
+    ('/', Kb7, SH), (':', Dot, SH), (';', Comma, SH), ('<', NonUsBslash, 0), ('=', Kb0, SH),
+    ('>', NonUsBslash, SH), ('?', Minus, SH), ('@', Kb2, AG), ('[', Kb8, AG), ('\\', Minus, AG),
+    (']', Kb9, AG), ('^', RBracket, SH | DK), ('_', Slash, SH), ('`', Equal, SH | DK),
+    ('{', Kb7, AG), ('|', NonUsBslash, AG), ('}', Kb0, AG), ('~', RBracket, AG | DK),
+];
+
+const LETTERS: [KeyCode; 26] = [
+    A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+];
+const DIGITS: [KeyCode; 10] = [Kb0, Kb1, Kb2, Kb3, Kb4, Kb5, Kb6, Kb7, Kb8, Kb9];
+
+impl HostLayout {
+    pub fn from_setting(value: u16) -> HostLayout {
+        match value {
+            1 => HostLayout::Uk,
+            2 => HostLayout::De,
+            3 => HostLayout::Fr,
+            4 => HostLayout::Nordic,
+            _ => HostLayout::Us,
+        }
+    }
+
+    /// How to type `c`, `None` if it is not printable ASCII.
+    pub fn stroke(self, c: char) -> Option<Stroke> {
+        let stroke = |key, flags: u8| Stroke {
+            key,
+            shift: flags & SH != 0,
+            altgr: flags & AG != 0,
+            dead: flags & DK != 0,
+        };
+        match c {
+            'a'..='z' => Some(stroke(self.letter(c), 0)),
+            'A'..='Z' => Some(stroke(self.letter(c.to_ascii_lowercase()), SH)),
+            '0'..='9' => {
+                let key = DIGITS[c as usize - '0' as usize];
+                Some(stroke(key, if self == HostLayout::Fr { SH } else { 0 }))
+            }
+            _ => self
+                .symbols()
+                .iter()
+                .find(|&&(ch, _, _)| ch == c)
+                .map(|&(_, key, flags)| stroke(key, flags)),
+        }
+    }
+
+    /// The printable ASCII character typed by `key`, `None` for dead keys.
+    pub fn char_of(self, key: KeyCode, shift: bool, altgr: bool) -> Option<char> {
+        (' '..='~').find(|&c| {
+            self.stroke(c)
+                == Some(Stroke {
+                    key,
+                    shift,
+                    altgr,
+                    dead: false,
+                })
+        })
+    }
+
+    fn letter(self, c: char) -> KeyCode {
+        let c = match (self, c) {
+            (HostLayout::De, 'y') => 'z',
+            (HostLayout::De, 'z') => 'y',
+            (HostLayout::Fr, 'a') => 'q',
+            (HostLayout::Fr, 'q') => 'a',
+            (HostLayout::Fr, 'w') => 'z',
+            (HostLayout::Fr, 'z') => 'w',
+            (HostLayout::Fr, 'm') => return SColon,
+            _ => c,
+        };
+        LETTERS[c as usize - 'a' as usize]
+    }
+
+    fn symbols(self) -> &'static [(char, KeyCode, u8)] {
+        match self {
+            HostLayout::Us => &US,
+            HostLayout::Uk => &UK,
+            HostLayout::De => &DE,
+            HostLayout::Fr => &FR,
+            HostLayout::Nordic => &NORDIC,
+        }
+    }
+}
+
+/// Adds the steps typing `stroke`.
+pub fn type_stroke(steps: &mut Steps, stroke: Stroke) -> bool {
+    let mods = [(stroke.shift, LShift), (stroke.altgr, RAlt)];
+    let mut mods = mods.iter().filter(|&&(held, _)| held).map(|&(_, kc)| kc);
+    mods.clone().all(|kc| steps.push(Step::Press(kc)).is_ok())
+        && tap(steps, stroke.key)
+        && mods.all(|kc| steps.push(Step::Release(kc)).is_ok())
+        && (!stroke.dead || tap(steps, Space))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_host;
+    use std::string::String;
+
+    const LAYOUTS: [HostLayout; 5] = [
+        HostLayout::Us,
+        HostLayout::Uk,
+        HostLayout::De,
+        HostLayout::Fr,
+        HostLayout::Nordic,
+    ];
+
+    // The text the host types from the keycodes of `c`
+    fn typed(c: char, layout: HostLayout) -> String {
+        let mut steps = Steps::new();
+        let stroke = layout.stroke(c).unwrap();
+        assert!(type_stroke(&mut steps, stroke));
+        let mut pushed = false;
+        mock_host::run(vec![vec![]], layout, |_, _, sequence| {
+            if !pushed {
+                pushed = sequence.push(&steps);
+            }
+        })
+    }
+
+    #[test]
+    fn printable_ascii_round_trip() {
+        for layout in LAYOUTS {
+            for c in ' '..='~' {
+                assert_eq!(typed(c, layout), c.to_string(), "{:?}", layout);
+            }
+        }
+    }
+
+    #[test]
+    fn one_stroke_per_char() {
+        for layout in LAYOUTS {
+            for c in ' '..='~' {
+                let stroke = layout.stroke(c).unwrap();
+                let others = (' '..='~').filter(|&other| other != c);
+                let same = |other| {
+                    let s = layout.stroke(other).unwrap();
+                    (s.key, s.shift, s.altgr) == (stroke.key, stroke.shift, stroke.altgr)
+                };
+                assert!(!others.clone().any(same), "{:?} {:?}", layout, c);
+                let char_of = layout.char_of(stroke.key, stroke.shift, stroke.altgr);
+                assert_eq!(char_of, (!stroke.dead).then_some(c));
+            }
+        }
+    }
+
+    #[test]
+    fn host_layout_keys() {
+        let key = |layout: HostLayout, c| {
+            let s = layout.stroke(c).unwrap();
+            (s.key, s.shift, s.altgr, s.dead)
+        };
+        assert_eq!(key(HostLayout::Us, '~'), (Grave, true, false, false));
+        assert_eq!(key(HostLayout::Uk, '@'), (Quote, true, false, false));
+        assert_eq!(key(HostLayout::Uk, '#'), (NonUsHash, false, false, false));
+        assert_eq!(key(HostLayout::De, 'z'), (Y, false, false, false));
+        assert_eq!(key(HostLayout::De, '@'), (Q, false, true, false));
+        assert_eq!(key(HostLayout::De, '^'), (Grave, false, false, true));
+        assert_eq!(key(HostLayout::Fr, 'a'), (Q, false, false, false));
+        assert_eq!(key(HostLayout::Fr, 'M'), (SColon, true, false, false));
+        assert_eq!(key(HostLayout::Fr, '1'), (Kb1, true, false, false));
+        assert_eq!(key(HostLayout::Nordic, '$'), (Kb4, false, true, false));
+        assert_eq!(key(HostLayout::Nordic, '~'), (RBracket, false, true, true));
+        assert_eq!(HostLayout::Us.stroke('é'), None);
+    }
+}
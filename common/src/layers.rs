@@ -0,0 +1,392 @@
+use heapless::Vec;
+use keyberon::action::Action;
+use keyberon::layout::{Event, Layers};
+
+/// Lays out the layers of a layout with several base layers, the default
+/// layer being one of them. Each base layer starts a block of `L / B` layers:
+/// itself, its own copy of the layers above it, then transparent layers.
+///
+/// The layout offsets the held layers by the default layer, so layer `n` held
+/// on any base layer is its copy of `above[n - 1]`. The transparent layers
+/// catch a layer kept by `VirtualLayers` plus a layer key resolved on top of
+/// it, until the key is taken over: they fall through to the base layer
+/// rather than reach the next one.
+pub const fn stack<
+    T: Copy,
+    const C: usize,
+    const R: usize,
+    const B: usize,
+    const A: usize,
+    const L: usize,
+>(
+    bases: &Layers<C, R, B, T>,
+    above: &Layers<C, R, A, T>,
+) -> Layers<C, R, L, T> {
+    let block = L / B;
+    assert!(block * B == L && block > A, "bad block size");
+    let mut layers = [[[Action::Trans; C]; R]; L];
+    let mut b = 0;
+    while b < B {
+        layers[b * block] = bases[b];
+        let mut n = 0;
+        while n < A {
+            layers[b * block + 1 + n] = above[n];
+            n += 1;
+        }
+        b += 1;
+    }
+    layers
+}
+
+/// Layers kept active by the firmware: locked and toggled layers, and
+/// conditional layers activated while two other layers are active.
+///
+/// The layout adds up the layers of its held layer keys, so the firmware
+/// keeps a single virtual layer key pressed, column `n` of
+/// `layout::VIRTUAL_ROW` holding layer `n`, that tops the physical layer keys
+/// up to the wanted layer. When they already add up to more, their layers are
+/// released in the layout and the virtual key alone holds the wanted layer.
+/// Its transparent keys still fall through to the default layer.
+///
+/// Layers are counted from the base layer, in blocks of `block` layers laid
+/// out by `stack`.
+pub struct VirtualLayers<const C: usize, const R: usize, const L: usize, T: 'static> {
+    layers: &'static Layers<C, R, L, T>,
+    rules: &'static [([usize; 2], usize)],
+    row: u8,
+    block: usize,
+    locked: [bool; L],
+    pressed: usize,
+    held: Vec<Held, 8>,
+}
+
+// A physical key holding a layer, confirmed once the layout activated it and
+// taken once the firmware released it in the layout
+struct Held {
+    coord: (u8, u8),
+    layer: usize,
+    confirmed: bool,
+    taken: bool,
+}
+
+impl<const C: usize, const R: usize, const L: usize, T: 'static> VirtualLayers<C, R, L, T> {
+    pub fn new(
+        layers: &'static Layers<C, R, L, T>,
+        rules: &'static [([usize; 2], usize)],
+        row: u8,
+        block: usize,
+    ) -> Self {
+        Self {
+            layers,
+            rules,
+            row,
+            block,
+            locked: [false; L],
+            pressed: 0,
+            held: Vec::new(),
+        }
+    }
+
+    /// Tracks the physical keys holding a layer.
+    pub fn event(&mut self, event: Event, current: usize, default: usize) {
+        let coord = event.coord();
+        if !event.is_press() {
+            self.held.retain(|h| h.coord != coord);
+            return;
+        }
+        let (i, j) = (coord.0 as usize, coord.1 as usize);
+        let action = match &self.layers[current][i][j] {
+            Action::Trans => &self.layers[default][i][j],
+            action => action,
+        };
+        let (layer, confirmed) = match action {
+            Action::Layer(layer) => (*layer, true),
+            Action::HoldTap(ht) => match ht.hold {
+                Action::Layer(layer) => (layer, false),
+                _ => return,
+            },
+            _ => return,
+        };
+        let _ = self.held.push(Held {
+            coord,
+            layer,
+            confirmed,
+            taken: false,
+        });
+    }
+
+    /// Locks the layer of the held layer keys, or unlocks it if it is already
+    /// locked. With no layer key held, unlocks the locked layer shown.
+    pub fn lock(&mut self) {
+        let layer = self.target();
+        if layer != 0 {
+            self.toggle(layer);
+        }
+    }
+
+    pub fn toggle(&mut self, layer: usize) {
+        if layer < self.block {
+            self.locked[layer] = !self.locked[layer];
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.locked = [false; L];
+    }
+
+    /// Evaluates the conditional layers, returning the virtual key events,
+    /// and the releases of the physical layer keys taken over, to feed the
+    /// layout.
+    pub fn update(&mut self, current: usize) -> impl Iterator<Item = Event> {
+        self.confirm(current % self.block);
+        let target = self.target();
+        let mut events: Vec<Event, 10> = Vec::new();
+        let physical = self.physical();
+        let release = target < physical;
+        let pressed = if release { target } else { target - physical };
+        if pressed != self.pressed && self.pressed != 0 {
+            let _ = events.push(Event::Release(self.row, self.pressed as u8));
+        }
+        if release {
+            for h in self.held.iter_mut().filter(|h| h.confirmed && !h.taken) {
+                h.taken = true;
+                let _ = events.push(Event::Release(h.coord.0, h.coord.1));
+            }
+        }
+        if pressed != self.pressed && pressed != 0 {
+            let _ = events.push(Event::Press(self.row, pressed as u8));
+        }
+        self.pressed = pressed;
+        events.into_iter()
+    }
+
+    // The layer wanted: a conditional layer whose two layers are active, else
+    // the last held layer, else the highest locked one
+    fn target(&self) -> usize {
+        for &([a, b], layer) in self.rules {
+            if self.is_active(a) && self.is_active(b) && layer < self.block {
+                return layer;
+            }
+        }
+        let held = self.held.iter().rev().find(|h| h.confirmed);
+        let locked = (0..self.block).rev().find(|&layer| self.locked[layer]);
+        held.map(|h| h.layer).or(locked).unwrap_or(0)
+    }
+
+    // The layers the physical keys add in the layout
+    fn physical(&self) -> usize {
+        let held = self.held.iter().filter(|h| h.confirmed && !h.taken);
+        held.map(|h| h.layer).sum()
+    }
+
+    // The layout's current layer is the sum of its active layers, so the
+    // waiting holds are confirmed once some of their layers, added to the
+    // layers known to be active, give the current layer
+    fn confirm(&mut self, current: usize) {
+        let known = self.pressed + self.physical();
+        let waiting: Vec<usize, 8> = (0..self.held.len())
+            .filter(|&i| !self.held[i].confirmed)
+            .collect();
+        let chosen = |mask: u32| {
+            let bits = waiting.iter().enumerate();
+            bits.filter(move |&(bit, _)| mask & 1 << bit != 0)
+                .map(|(_, &i)| i)
+        };
+        // All the waiting holds first
+        let mask = (1..1u32 << waiting.len()).rev().find(|&mask| {
+            known + chosen(mask).map(|i| self.held[i].layer).sum::<usize>() == current
+        });
+        if let Some(mask) = mask {
+            for i in chosen(mask) {
+                self.held[i].confirmed = true;
+            }
+        }
+    }
+
+    fn is_active(&self, layer: usize) -> bool {
+        self.locked.get(layer) == Some(&true)
+            || self.held.iter().any(|h| h.layer == layer && h.confirmed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keyberon::action::{k, l, HoldTapAction, HoldTapConfig};
+    use keyberon::key_code::KeyCode::*;
+    use keyberon::layout::Event::{Press, Release};
+    use std::vec::Vec;
+
+    macro_rules! hold_tap {
+        ($layer:expr) => {
+            Action::HoldTap(&HoldTapAction {
+                timeout: 200,
+                hold: l($layer),
+                tap: k(A),
+                config: HoldTapConfig::Default,
+                tap_hold_interval: 0,
+            })
+        };
+    }
+
+    const T: Action<()> = Action::Trans;
+    const N: Action<()> = Action::NoOp;
+    // Layer 2 and 3 keys on the first row, the virtual layer keys below
+    static BASE: Layers<5, 2, 1, ()> = [[
+        [hold_tap!(2), hold_tap!(3), l(1), N, N],
+        [l(0), l(1), l(2), l(3), l(4)],
+    ]];
+    static ABOVE: Layers<5, 2, 4, ()> = [[[T, T, T, N, N], [T; 5]]; 4];
+    static LAYERS: Layers<5, 2, 10, ()> = stack(&BASE, &ABOVE);
+    static RULES: [([usize; 2], usize); 1] = [([2, 3], 4)];
+
+    // The layer keys held by a keyberon layout, which adds up their layers.
+    // The virtual key in column n holds layer n
+    #[derive(Default)]
+    struct Layout(Vec<((u8, u8), usize)>);
+
+    impl Layout {
+        fn current(&self) -> usize {
+            self.0.iter().map(|&(_, layer)| layer).sum()
+        }
+
+        fn hold(&mut self, coord: (u8, u8), layer: usize) {
+            self.0.push((coord, layer));
+        }
+
+        fn update(&mut self, layers: &mut VirtualLayers<5, 2, 10, ()>) -> Vec<Event> {
+            let events: Vec<Event> = layers.update(self.current()).collect();
+            for &event in &events {
+                match event {
+                    Press(i, j) => self.0.push(((i, j), j as usize)),
+                    Release(i, j) => self.0.retain(|&(coord, _)| coord != (i, j)),
+                }
+            }
+            events
+        }
+    }
+
+    #[test]
+    fn conditional_replaces_held_layers() {
+        let (mut layers, mut layout) = (
+            VirtualLayers::new(&LAYERS, &RULES, 1, 10),
+            Layout::default(),
+        );
+        layers.event(Press(0, 0), 0, 0);
+        layers.event(Press(0, 1), 0, 0);
+        // Both hold-taps still undecided
+        assert_eq!(layout.update(&mut layers), []);
+        // The layout activates layers 2 and 3, which add up to 5
+        layout.hold((0, 0), 2);
+        layout.hold((0, 1), 3);
+        let events = [Release(0, 0), Release(0, 1), Press(1, 4)];
+        assert_eq!(layout.update(&mut layers), events);
+        assert_eq!(layout.current(), 4);
+        assert_eq!(layout.update(&mut layers), []);
+        // Layer 2 is still held
+        layers.event(Release(0, 1), 4, 0);
+        assert_eq!(layout.update(&mut layers), [Release(1, 4), Press(1, 2)]);
+        assert_eq!(layout.current(), 2);
+        layers.event(Release(0, 0), 2, 0);
+        assert_eq!(layout.update(&mut layers), [Release(1, 2)]);
+        assert_eq!(layout.current(), 0);
+    }
+
+    #[test]
+    fn confirms_one_hold_at_a_time() {
+        let (mut layers, mut layout) = (
+            VirtualLayers::new(&LAYERS, &RULES, 1, 10),
+            Layout::default(),
+        );
+        layers.event(Press(0, 0), 0, 0);
+        layers.event(Press(0, 1), 0, 0);
+        layout.hold((0, 0), 2);
+        assert_eq!(layout.update(&mut layers), []);
+        layout.hold((0, 1), 3);
+        let events = [Release(0, 0), Release(0, 1), Press(1, 4)];
+        assert_eq!(layout.update(&mut layers), events);
+        assert_eq!(layout.current(), 4);
+    }
+
+    #[test]
+    fn tops_up_held_layers() {
+        let (mut layers, mut layout) = (
+            VirtualLayers::new(&LAYERS, &RULES, 1, 10),
+            Layout::default(),
+        );
+        layers.toggle(2);
+        assert_eq!(layout.update(&mut layers), [Press(1, 2)]);
+        layers.event(Press(0, 1), 2, 0);
+        // The tap of the hold-tap leaves the layout on the locked layer
+        assert_eq!(layout.update(&mut layers), []);
+        layout.hold((0, 1), 3);
+        assert_eq!(layout.update(&mut layers), [Release(1, 2), Press(1, 1)]);
+        assert_eq!(layout.current(), 4);
+        layers.event(Release(0, 1), 4, 0);
+        layout.0.retain(|&(coord, _)| coord != (0, 1));
+        assert_eq!(layout.update(&mut layers), [Release(1, 1), Press(1, 2)]);
+        assert_eq!(layout.current(), 2);
+    }
+
+    #[test]
+    fn layer_keys_confirm_at_once() {
+        let mut layers = VirtualLayers::new(&LAYERS, &[([1, 2], 3)], 1, 10);
+        let mut layout = Layout::default();
+        layers.event(Press(0, 2), 0, 0);
+        layers.event(Press(0, 0), 0, 0);
+        layout.hold((0, 2), 1);
+        assert_eq!(layout.update(&mut layers), []);
+        // Layers 1 and 2 add up to the conditional layer
+        layout.hold((0, 0), 2);
+        assert_eq!(layout.update(&mut layers), []);
+        assert_eq!(layout.current(), 3);
+    }
+
+    #[test]
+    fn locks_the_held_layer() {
+        let (mut layers, mut layout) = (
+            VirtualLayers::new(&LAYERS, &RULES, 1, 10),
+            Layout::default(),
+        );
+        layers.event(Press(0, 0), 0, 0);
+        layout.hold((0, 0), 2);
+        assert_eq!(layout.update(&mut layers), []);
+        // Locked while the layer key is still held
+        layers.lock();
+        assert_eq!(layout.update(&mut layers), []);
+        assert_eq!(layout.current(), 2);
+        layers.event(Release(0, 0), 2, 0);
+        layout.0.clear();
+        assert_eq!(layout.update(&mut layers), [Press(1, 2)]);
+        assert_eq!(layout.current(), 2);
+        layers.lock();
+        assert_eq!(layout.update(&mut layers), [Release(1, 2)]);
+        assert_eq!(layout.current(), 0);
+    }
+
+    #[test]
+    fn held_layers_show_the_last() {
+        let (mut layers, mut layout) = (
+            VirtualLayers::new(&LAYERS, &RULES, 1, 10),
+            Layout::default(),
+        );
+        layers.event(Press(0, 2), 0, 0);
+        layers.event(Press(0, 0), 0, 0);
+        layout.hold((0, 2), 1);
+        assert_eq!(layout.update(&mut layers), []);
+        // Layers 1 and 2 would add up to layer 3
+        layout.hold((0, 0), 2);
+        let events = [Release(0, 2), Release(0, 0), Press(1, 2)];
+        assert_eq!(layout.update(&mut layers), events);
+        assert_eq!(layout.current(), 2);
+    }
+
+    #[test]
+    fn stacks_the_layers_above_each_base() {
+        static BASES: Layers<1, 1, 2, ()> = [[[k(A)]], [[k(B)]]];
+        static ABOVE: Layers<1, 1, 1, ()> = [[[k(C)]]];
+        static STACKED: Layers<1, 1, 6, ()> = stack(&BASES, &ABOVE);
+        let blocks = [[[k(A)]], [[k(C)]], [[T]], [[k(B)]], [[k(C)]], [[T]]];
+        assert_eq!(STACKED, blocks);
+    }
+}
@@ -0,0 +1,38 @@
+//! Firmware logic shared by the boards: matrix scanning and debouncing, the
+//! layout features and the USB host side. Each board keeps its pins, its
+//! layout and its tables (`LAYERS`, `TYPOS`, `SNIPPETS`...) in its own crate.
+//!
+//! Nothing here touches the hardware directly, so that `cargo test` runs on
+//! the host.
+#![cfg_attr(not(test), no_std)]
+
+pub mod autocorrect;
+pub mod compose;
+pub mod debounce;
+pub mod direct;
+pub mod discovery;
+pub mod duplex;
+pub mod flow_tap;
+pub mod host;
+pub mod intercore;
+pub mod keymap;
+pub mod layers;
+pub mod lock;
+pub mod matrix;
+pub mod mcp23017;
+#[cfg(test)]
+mod mock_host;
+#[cfg(test)]
+mod mock_net;
+pub mod os;
+pub mod os_detect;
+pub mod pio_scan;
+pub mod repeat;
+pub mod self_test;
+pub mod sequence;
+pub mod settings;
+pub mod shift_register;
+pub mod snippets;
+pub mod swap_hands;
+pub mod typing;
+pub mod unicode;
@@ -0,0 +1,156 @@
+use keyberon::key_code::KeyCode;
+
+use crate::sequence::Sequence;
+use crate::typing::{Keys, Press};
+
+/// Keyboard lock: nothing is sent to the host until the unlock sequence is
+/// typed, to clean the keyboard or leave the desk.
+///
+/// The sequence is matched on the keys of the layout, so it is typed as
+/// text whatever the base layer. The keys held when it completes are kept
+/// from the host until released.
+pub struct Lock {
+    unlock: &'static [KeyCode],
+    locked: bool,
+    // keys of the unlock sequence typed so far
+    typed: usize,
+    hidden: Keys,
+}
+
+impl Lock {
+    pub fn new(unlock: &'static [KeyCode]) -> Self {
+        Self {
+            unlock,
+            locked: false,
+            typed: 0,
+            hidden: Keys::new(),
+        }
+    }
+
+    /// Locks the keyboard, unless there is no unlock sequence, dropping the
+    /// rest of the `sequence` the firmware is typing.
+    pub fn lock(&mut self, sequence: &mut Sequence) {
+        self.locked = !self.unlock.is_empty();
+        self.typed = 0;
+        if self.locked {
+            sequence.clear();
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Follows the unlock sequence in the `presses`, emptying the report
+    /// `keys` while locked.
+    pub fn filter(&mut self, keys: &mut Keys, presses: &[Press]) {
+        if self.locked {
+            for press in presses {
+                // Longest start of the sequence ending with this press
+                let typed = &self.unlock[..self.typed];
+                self.typed = (1..=typed.len() + 1)
+                    .rev()
+                    .find(|&n| {
+                        self.unlock[n - 1] == press.key
+                            && self.unlock[..n - 1] == typed[typed.len() + 1 - n..]
+                    })
+                    .unwrap_or(0);
+                if self.typed == self.unlock.len() {
+                    self.locked = false;
+                    self.hidden = keys.clone();
+                    break;
+                }
+            }
+            if self.locked {
+                keys.clear();
+            }
+        }
+        self.hidden.retain(|kc| keys.contains(kc));
+        keys.retain(|kc| !self.hidden.contains(kc));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compose::{Compose, Output};
+    use crate::keymap::HostLayout;
+    use crate::mock_host::{self, strokes};
+    use crate::sequence::Step;
+    use crate::unicode::Method;
+    use keyberon::key_code::KeyCode::*;
+    use std::string::String;
+    use std::vec::Vec;
+
+    static UNLOCK: [KeyCode; 6] = [U, N, L, O, C, K];
+
+    // Host text of the `reports` typed while locked
+    fn typed(reports: Vec<Vec<KeyCode>>) -> String {
+        let mut lock = Lock::new(&UNLOCK);
+        let mut locked = false;
+        mock_host::run(reports, HostLayout::Us, |keys, presses, sequence| {
+            if !locked {
+                lock.lock(sequence);
+                locked = true;
+            }
+            lock.filter(keys, presses);
+        })
+    }
+
+    #[test]
+    fn unlocks_after_the_sequence() {
+        assert_eq!(typed(strokes("hello unlock hi", HostLayout::Us)), " hi");
+    }
+
+    #[test]
+    fn overlapping_sequences() {
+        assert_eq!(typed(strokes("uunlock.", HostLayout::Us)), ".");
+        assert_eq!(typed(strokes("ununlock.", HostLayout::Us)), ".");
+        assert_eq!(typed(strokes("unlocunlock.", HostLayout::Us)), ".");
+        assert_eq!(typed(strokes("unlck.", HostLayout::Us)), "");
+    }
+
+    #[test]
+    fn hides_keys_held_at_unlock() {
+        let mut reports = strokes("unlo", HostLayout::Us);
+        // C still held when K completes the sequence
+        reports.extend([vec![C], vec![C, K], vec![C, K], vec![K], vec![]]);
+        reports.extend(strokes("y", HostLayout::Us));
+        assert_eq!(typed(reports), "y");
+    }
+
+    #[test]
+    fn drops_the_sequence_playing() {
+        let mut lock = Lock::new(&UNLOCK);
+        let mut sequence = Sequence::default();
+        assert!(sequence.push(&[Step::Press(A), Step::Release(A)]));
+        sequence.tick();
+        lock.lock(&mut sequence);
+        assert!(!sequence.is_playing());
+        let mut unlocked = Lock::new(&[]);
+        assert!(sequence.push(&[Step::Press(A)]));
+        unlocked.lock(&mut sequence);
+        assert!(!unlocked.is_locked());
+        assert!(sequence.is_playing());
+    }
+
+    #[test]
+    fn compose_ignores_the_keys_locked() {
+        static TABLE: [([char; 2], Output); 1] = [(['a', 'b'], Output::Char('x'))];
+        let mut lock = Lock::new(&UNLOCK);
+        let mut compose = Compose::new(&TABLE);
+        let mut locked = false;
+        let reports = strokes("aunlockb", HostLayout::Us);
+        let text = mock_host::run(reports, HostLayout::Us, |keys, presses, sequence| {
+            if !locked {
+                lock.lock(sequence);
+                compose.toggle();
+                locked = true;
+            }
+            lock.filter(keys, presses);
+            compose.filter(keys, presses, sequence, Method::Linux, HostLayout::Us);
+        });
+        // The `a` typed while locked is not the first character
+        assert_eq!(text, "");
+    }
+}
@@ -0,0 +1,142 @@
+use core::marker::PhantomData;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+/// Diodes from the columns to the rows: the rows are driven low in turn and
+/// the columns read.
+pub struct Col2Row;
+
+/// Diodes from the rows to the columns: the columns are driven low in turn
+/// and the rows read.
+pub struct Row2Col;
+
+/// Key matrix scanned in the direction `D` of its diodes, replacing
+/// keyberon's `Matrix`.
+///
+/// Unwired columns are given as `None` and read released. After a pin is
+/// driven low the scan waits `settle` CPU cycles, for long wires to settle.
+pub struct Matrix<C, R, D, const CS: usize, const RS: usize> {
+    cols: [Option<C>; CS],
+    rows: [R; RS],
+    settle: u32,
+    diodes: PhantomData<D>,
+}
+
+impl<C, R, E, const CS: usize, const RS: usize> Matrix<C, R, Col2Row, CS, RS>
+where
+    C: InputPin<Error = E>,
+    R: OutputPin<Error = E>,
+{
+    pub fn col2row(cols: [Option<C>; CS], mut rows: [R; RS], settle: u32) -> Result<Self, E> {
+        for row in rows.iter_mut() {
+            row.set_high()?;
+        }
+        Ok(Self {
+            cols,
+            rows,
+            settle,
+            diodes: PhantomData,
+        })
+    }
+
+    pub fn get(&mut self) -> Result<[[bool; CS]; RS], E> {
+        let mut keys = [[false; CS]; RS];
+        for (row, keys) in self.rows.iter_mut().zip(keys.iter_mut()) {
+            row.set_low()?;
+            settle(self.settle);
+            for (key, col) in keys.iter_mut().zip(&self.cols) {
+                if let Some(col) = col {
+                    *key = col.is_low()?;
+                }
+            }
+            row.set_high()?;
+        }
+        Ok(keys)
+    }
+}
+
+impl<C, R, E, const CS: usize, const RS: usize> Matrix<C, R, Row2Col, CS, RS>
+where
+    C: OutputPin<Error = E>,
+    R: InputPin<Error = E>,
+{
+    pub fn row2col(mut cols: [Option<C>; CS], rows: [R; RS], settle: u32) -> Result<Self, E> {
+        for col in cols.iter_mut().flatten() {
+            col.set_high()?;
+        }
+        Ok(Self {
+            cols,
+            rows,
+            settle,
+            diodes: PhantomData,
+        })
+    }
+
+    pub fn get(&mut self) -> Result<[[bool; CS]; RS], E> {
+        let mut keys = [[false; CS]; RS];
+        for (ci, col) in self.cols.iter_mut().enumerate() {
+            let col = match col {
+                Some(col) => col,
+                None => continue,
+            };
+            col.set_low()?;
+            settle(self.settle);
+            for (keys, row) in keys.iter_mut().zip(&self.rows) {
+                keys[ci] = row.is_low()?;
+            }
+            col.set_high()?;
+        }
+        Ok(keys)
+    }
+}
+
+pub(crate) fn settle(cycles: u32) {
+    // Host builds, the tests, have no wires to wait for
+    #[cfg(target_arch = "arm")]
+    if cycles > 0 {
+        cortex_m::asm::delay(cycles);
+    }
+    #[cfg(not(target_arch = "arm"))]
+    let _ = cycles;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_hal_mock::eh0::digital::{Mock, State, Transaction};
+
+    // A pin driven high, then low and high again for its scan
+    fn driven() -> Mock {
+        let high = Transaction::set(State::High);
+        Mock::new(&[high.clone(), Transaction::set(State::Low), high])
+    }
+
+    // A pin read once per line driven, low for the pressed keys
+    fn read(pressed: &[bool]) -> Mock {
+        let state = |&low| if low { State::Low } else { State::High };
+        let reads: std::vec::Vec<_> = pressed.iter().map(|p| Transaction::get(state(p))).collect();
+        Mock::new(&reads)
+    }
+
+    #[test]
+    fn col2row_drives_the_rows() {
+        let cols = [Some(read(&[true, false])), Some(read(&[false, true])), None];
+        let mut matrix = Matrix::col2row(cols, [driven(), driven()], 0).unwrap();
+        let keys = matrix.get().unwrap();
+        assert_eq!(keys, [[true, false, false], [false, true, false]]);
+        for pin in matrix.cols.iter_mut().flatten().chain(&mut matrix.rows) {
+            pin.done();
+        }
+    }
+
+    #[test]
+    fn row2col_drives_the_columns() {
+        let cols = [Some(driven()), None, Some(driven())];
+        let rows = [read(&[false, true]), read(&[true, false])];
+        let mut matrix = Matrix::row2col(cols, rows, 0).unwrap();
+        let keys = matrix.get().unwrap();
+        assert_eq!(keys, [[false, false, true], [true, false, false]]);
+        for pin in matrix.cols.iter_mut().flatten().chain(&mut matrix.rows) {
+            pin.done();
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+// Registers, with IOCON.BANK = 0
+const IODIRA: u8 = 0x00;
+const IODIRB: u8 = 0x01;
+const GPPUA: u8 = 0x0c;
+const GPIOA: u8 = 0x12;
+const OLATB: u8 = 0x15;
+
+/// Key matrix of a half read through an MCP23017 I/O expander, replacing
+/// keyberon's `Matrix`: up to 8 columns on port A, with pull-ups, and up to
+/// 8 rows on port B.
+///
+/// The rows are kept as inputs and made outputs, driving low, one at a time,
+/// so that they never drive high.
+pub struct Mcp23017<I, const CS: usize, const RS: usize> {
+    i2c: I,
+    address: u8,
+}
+
+impl<I, E, const CS: usize, const RS: usize> Mcp23017<I, CS, RS>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+{
+    /// Sets the expander up, `address` being 0x20 plus its A2-A0 pins.
+    pub fn new(i2c: I, address: u8) -> Result<Self, E> {
+        assert!(CS <= 8 && RS <= 8, "an MCP23017 port has 8 pins");
+        let mut mcp = Self { i2c, address };
+        mcp.write(IODIRA, 0xff)?;
+        mcp.write(GPPUA, 0xff)?;
+        mcp.write(OLATB, 0x00)?;
+        mcp.write(IODIRB, 0xff)?;
+        Ok(mcp)
+    }
+
+    fn write(&mut self, register: u8, value: u8) -> Result<(), E> {
+        self.i2c.write(self.address, &[register, value])
+    }
+
+    pub fn get(&mut self) -> Result<[[bool; CS]; RS], E> {
+        let mut keys = [[false; CS]; RS];
+        for (i, keys) in keys.iter_mut().enumerate() {
+            self.write(IODIRB, !(1 << i))?;
+            let mut port = [0];
+            self.i2c.write_read(self.address, &[GPIOA], &mut port)?;
+            for (j, key) in keys.iter_mut().enumerate() {
+                *key = port[0] & (1 << j) == 0;
+            }
+        }
+        self.write(IODIRB, 0xff)?;
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_hal_mock::eh0::i2c::{Mock, Transaction};
+
+    const ADDRESS: u8 = 0x20;
+
+    fn write(register: u8, value: u8) -> Transaction {
+        Transaction::write(ADDRESS, vec![register, value])
+    }
+
+    #[test]
+    fn drives_one_row_at_a_time() {
+        let i2c = Mock::new(&[
+            // Port A inputs with pull-ups, port B inputs latching low
+            write(IODIRA, 0xff),
+            write(GPPUA, 0xff),
+            write(OLATB, 0x00),
+            write(IODIRB, 0xff),
+            // Row 0 driven, column 0 pressed
+            write(IODIRB, 0b1111_1110),
+            Transaction::write_read(ADDRESS, vec![GPIOA], vec![0b1111_1110]),
+            // Row 1 driven, column 2 pressed
+            write(IODIRB, 0b1111_1101),
+            Transaction::write_read(ADDRESS, vec![GPIOA], vec![0b1111_1011]),
+            write(IODIRB, 0xff),
+        ]);
+        let mut mcp: Mcp23017<_, 3, 2> = Mcp23017::new(i2c, ADDRESS).unwrap();
+        let keys = mcp.get().unwrap();
+        assert_eq!(keys, [[true, false, false], [false, false, true]]);
+        mcp.i2c.done();
+    }
+}
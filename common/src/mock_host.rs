@@ -0,0 +1,148 @@
+//! A host for the tests: it types the text of the reports it receives, the
+//! way the software layouts of `keymap` and the Linux Unicode input do.
+
+use keyberon::key_code::KeyCode::{self, *};
+use std::string::String;
+use std::vec::Vec;
+
+use crate::keymap::HostLayout;
+use crate::sequence::Sequence;
+use crate::typing::{self, Backlog, Keys, Press, Typing};
+
+pub struct MockHost {
+    layout: HostLayout,
+    last: Vec<KeyCode>,
+    // dead key waiting for the next key
+    dead: Option<char>,
+    // hex digits typed after Ctrl+Shift+U
+    unicode: Option<String>,
+    pub text: String,
+}
+
+impl MockHost {
+    pub fn new(layout: HostLayout) -> Self {
+        Self {
+            layout,
+            last: Vec::new(),
+            dead: None,
+            unicode: None,
+            text: String::new(),
+        }
+    }
+
+    /// Takes a report, typing its newly pressed keys.
+    pub fn report(&mut self, keys: &[KeyCode]) {
+        let held = |kcs: &[KeyCode]| kcs.iter().any(|kc| keys.contains(kc));
+        let (shift, altgr) = (held(&[LShift, RShift]), held(&[RAlt]));
+        let ctrl = held(&[LCtrl, RCtrl]);
+        let shortcut = ctrl || held(&[LAlt, LGui, RGui]);
+        for &key in keys {
+            if key.is_modifier() || self.last.contains(&key) {
+                continue;
+            }
+            match key {
+                U if ctrl && shift => self.unicode = Some(String::new()),
+                _ if shortcut => (),
+                BSpace => {
+                    self.text.pop();
+                }
+                Enter => self.text.push('\n'),
+                Tab => self.text.push('\t'),
+                _ => self.key(key, shift, altgr),
+            }
+        }
+        self.last = keys.to_vec();
+    }
+
+    fn key(&mut self, key: KeyCode, shift: bool, altgr: bool) {
+        let layout = self.layout;
+        let (c, dead) = match (' '..='~').find_map(|c| {
+            let stroke = layout.stroke(c)?;
+            (stroke.key == key && stroke.shift == shift && stroke.altgr == altgr)
+                .then_some((c, stroke.dead))
+        }) {
+            Some(found) => found,
+            None => return,
+        };
+        if let Some(digits) = &mut self.unicode {
+            if c == ' ' {
+                let code = u32::from_str_radix(digits, 16).ok();
+                self.text.extend(code.and_then(char::from_u32));
+                self.unicode = None;
+            } else {
+                digits.push(c);
+            }
+        } else if let Some(accent) = self.dead.take() {
+            // Space types the accent itself
+            self.text.push(accent);
+            if c != ' ' {
+                self.text.push(c);
+            }
+        } else if dead {
+            self.dead = Some(c);
+        } else {
+            self.text.push(c);
+        }
+    }
+}
+
+/// Reports typing `text`: a press and a release for each character, `\x08`
+/// standing for Backspace.
+pub fn strokes(text: &str, layout: HostLayout) -> Vec<Vec<KeyCode>> {
+    let mut reports = Vec::new();
+    for c in text.chars() {
+        let keys = match (c, layout.stroke(c)) {
+            ('\x08', _) => vec![BSpace],
+            ('\n', _) => vec![Enter],
+            ('\t', _) => vec![Tab],
+            (_, Some(stroke)) => {
+                let mods = [(stroke.shift, LShift), (stroke.altgr, RAlt)];
+                let mut keys: Vec<_> = mods.iter().filter(|m| m.0).map(|m| m.1).collect();
+                keys.push(stroke.key);
+                if stroke.dead {
+                    reports.push(keys);
+                    reports.push(vec![]);
+                    keys = vec![Space];
+                }
+                keys
+            }
+            (_, None) => panic!("{:?} has no key", c),
+        };
+        reports.push(keys);
+        reports.push(vec![]);
+    }
+    reports
+}
+
+/// Sends the `reports` through `filter`, a feature between the layout and
+/// the report as in the boards' `tick`, and returns the text typed on the
+/// host. The reports come one per tick, as from the keyboard, and the
+/// `Backlog` holds them back while the sequence of the firmware plays.
+pub fn run(
+    reports: Vec<Vec<KeyCode>>,
+    layout: HostLayout,
+    mut filter: impl FnMut(&mut Keys, &[Press], &mut Sequence),
+) -> String {
+    let mut typing = Typing::default();
+    let mut backlog = Backlog::default();
+    let mut sequence = Sequence::default();
+    let mut host = MockHost::new(layout);
+    let mut reports = reports.into_iter();
+    loop {
+        let keys = match reports.next() {
+            Some(keys) => typing::keys(keys.into_iter()),
+            None if sequence.is_playing() || !backlog.is_empty() => Keys::new(),
+            None => break,
+        };
+        let mut keys = backlog.next(keys, sequence.is_playing());
+        let presses = typing.update(&keys);
+        filter(&mut keys, &presses, &mut sequence);
+        sequence.tick();
+        if sequence.is_playing() {
+            host.report(&sequence.keycodes().collect::<Vec<_>>());
+        } else {
+            host.report(&keys);
+        }
+    }
+    host.text
+}
@@ -0,0 +1,76 @@
+//! A pin network for the tests: `Probe` pins linked by the keys pressed,
+//! through a diode or not, a driven pin pulling low all the pins it reaches.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::vec::Vec;
+
+use crate::discovery::Probe;
+
+#[derive(Default)]
+struct State {
+    driven: Vec<usize>,
+    // (from, to, diode): `to` reads low while `from` reads low, and the other
+    // way too without a diode
+    links: Vec<(usize, usize, bool)>,
+}
+
+#[derive(Clone, Default)]
+pub struct Net(Rc<RefCell<State>>);
+
+impl Net {
+    pub fn pin(&self, index: usize) -> Pin {
+        Pin {
+            index,
+            net: self.clone(),
+        }
+    }
+
+    pub fn pins<const N: usize>(&self) -> [Pin; N] {
+        core::array::from_fn(|i| self.pin(i))
+    }
+
+    /// Replaces the links between the pins.
+    pub fn link(&self, links: &[(usize, usize, bool)]) {
+        self.0.borrow_mut().links = links.to_vec();
+    }
+}
+
+pub struct Pin {
+    index: usize,
+    net: Net,
+}
+
+impl Probe for Pin {
+    fn drive_low(&mut self) {
+        let mut state = self.net.0.borrow_mut();
+        if !state.driven.contains(&self.index) {
+            state.driven.push(self.index);
+        }
+    }
+
+    fn pull_up(&mut self) {
+        self.net.0.borrow_mut().driven.retain(|&i| i != self.index);
+    }
+
+    fn is_low(&self) -> bool {
+        let state = self.net.0.borrow();
+        // The driven pins pull low every pin they reach through the links
+        let mut low = state.driven.clone();
+        let mut i = 0;
+        while i < low.len() {
+            for &(from, to, diode) in &state.links {
+                let next = match low[i] {
+                    pin if pin == from => to,
+                    pin if pin == to && !diode => from,
+                    _ => continue,
+                };
+                if !low.contains(&next) {
+                    low.push(next);
+                }
+            }
+            i += 1;
+        }
+        low.contains(&self.index)
+    }
+}
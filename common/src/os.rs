@@ -0,0 +1,106 @@
+use heapless::Vec;
+use keyberon::key_code::KeyCode;
+
+/// Host operating system, stored in the `os` setting.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Os {
+    Linux,
+    Windows,
+    MacOs,
+}
+
+/// `os` setting following the host detected at enumeration.
+pub const AUTO: u16 = 3;
+
+/// Keys sent by a shortcut on Linux, Windows and macOS.
+pub type Shortcut = [&'static [KeyCode]; 3];
+
+impl Os {
+    /// The OS of the `os` setting, the detected one in auto mode and Linux
+    /// if it is unknown.
+    pub fn from_setting(value: u16, detected: Option<Os>) -> Os {
+        match value {
+            1 => Os::Windows,
+            2 => Os::MacOs,
+            AUTO => detected.unwrap_or(Os::Linux),
+            _ => Os::Linux,
+        }
+    }
+
+    pub fn setting(self) -> u16 {
+        self as u16
+    }
+
+    /// Swaps Ctrl and Gui on macOS, so that Cmd shortcuts are typed with the
+    /// Ctrl key as on the other systems.
+    pub fn modifier(self, kc: KeyCode) -> KeyCode {
+        match (self, kc) {
+            (Os::MacOs, KeyCode::LCtrl) => KeyCode::LGui,
+            (Os::MacOs, KeyCode::LGui) => KeyCode::LCtrl,
+            (Os::MacOs, KeyCode::RCtrl) => KeyCode::RGui,
+            (Os::MacOs, KeyCode::RGui) => KeyCode::RCtrl,
+            _ => kc,
+        }
+    }
+}
+
+/// Held shortcut keys, reported as is without the Ctrl/Gui swap.
+#[derive(Default)]
+pub struct Shortcuts {
+    held: Vec<(&'static Shortcut, &'static [KeyCode]), 4>,
+}
+
+impl Shortcuts {
+    /// Holds the keys of `shortcut` for `os` until it is released, even if
+    /// the OS mode changes meanwhile.
+    pub fn press(&mut self, shortcut: &'static Shortcut, os: Os) {
+        let _ = self.held.push((shortcut, shortcut[os as usize]));
+    }
+
+    pub fn release(&mut self, shortcut: &'static Shortcut) {
+        self.held.retain(|&(s, _)| !core::ptr::eq(s, shortcut));
+    }
+
+    pub fn keycodes(&self) -> impl Iterator<Item = KeyCode> + '_ {
+        self.held.iter().flat_map(|(_, keys)| keys.iter().copied())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keyberon::key_code::KeyCode::*;
+
+    static CA_D: Shortcut = [
+        &[LCtrl, LAlt, Delete],
+        &[LCtrl, LAlt, Delete],
+        &[LGui, LAlt, Escape],
+    ];
+
+    #[test]
+    fn settings() {
+        assert_eq!(Os::from_setting(2, None), Os::MacOs);
+        assert_eq!(Os::from_setting(AUTO, Some(Os::Windows)), Os::Windows);
+        assert_eq!(Os::from_setting(AUTO, None), Os::Linux);
+        assert_eq!(Os::from_setting(7, Some(Os::MacOs)), Os::Linux);
+        assert_eq!(Os::Windows.setting(), 1);
+    }
+
+    #[test]
+    fn swaps_ctrl_and_gui_on_macos() {
+        assert_eq!(Os::MacOs.modifier(LCtrl), LGui);
+        assert_eq!(Os::MacOs.modifier(RGui), RCtrl);
+        assert_eq!(Os::MacOs.modifier(A), A);
+        assert_eq!(Os::Linux.modifier(LCtrl), LCtrl);
+    }
+
+    #[test]
+    fn shortcuts_keep_their_os() {
+        let mut shortcuts = Shortcuts::default();
+        shortcuts.press(&CA_D, Os::MacOs);
+        let keys: std::vec::Vec<_> = shortcuts.keycodes().collect();
+        assert_eq!(keys, [LGui, LAlt, Escape]);
+        shortcuts.release(&CA_D);
+        assert_eq!(shortcuts.keycodes().count(), 0);
+    }
+}
@@ -0,0 +1,192 @@
+use usb_device::bus::UsbBus;
+use usb_device::class::{ControlIn, UsbClass};
+use usb_device::control::{Request, RequestType};
+
+use crate::os::Os;
+
+const STRING_DESCRIPTOR: u8 = 3;
+
+/// Counts of the string descriptor lengths requested by the host while
+/// enumerating, the same fingerprint as QMK's OS detection.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct Fingerprint {
+    count: u8,
+    len_02: u8,
+    len_04: u8,
+    len_ff: u8,
+    last: u16,
+}
+
+impl Fingerprint {
+    /// Takes a setup packet received by the device, counting the string
+    /// descriptor requests.
+    pub fn setup(&mut self, req: &Request) {
+        if req.request_type == RequestType::Standard
+            && req.request == Request::GET_DESCRIPTOR
+            && (req.value >> 8) as u8 == STRING_DESCRIPTOR
+        {
+            self.request(req.length);
+        }
+    }
+
+    pub fn request(&mut self, length: u16) {
+        self.count = self.count.saturating_add(1);
+        match length {
+            0x02 => self.len_02 = self.len_02.saturating_add(1),
+            0x04 => self.len_04 = self.len_04.saturating_add(1),
+            0xff => self.len_ff = self.len_ff.saturating_add(1),
+            _ => (),
+        }
+        self.last = length;
+    }
+
+    /// The host OS, `None` until enough requests were seen or if they match
+    /// no known host.
+    pub fn guess(&self) -> Option<Os> {
+        if self.count < 3 {
+            return None;
+        }
+        if self.len_ff >= 2 && self.len_04 >= 1 {
+            Some(Os::Windows)
+        } else if self.count == self.len_ff {
+            Some(Os::Linux)
+        } else if self.len_02 >= 2 && (self.len_ff == 0 || self.last == 0xff) {
+            // iOS asks the same as macOS, without the trailing 0xff
+            Some(Os::MacOs)
+        } else if self.len_02 == 0 && self.len_04 >= 1 {
+            // some Linux and Android hosts ask for 4 bytes but never 2
+            Some(Os::Linux)
+        } else {
+            None
+        }
+    }
+}
+
+/// USB class without interface, only watching the control requests to
+/// fingerprint the host. Restarts at each bus reset.
+#[derive(Default)]
+pub struct OsDetect {
+    fingerprint: Fingerprint,
+}
+
+impl OsDetect {
+    pub fn guess(&self) -> Option<Os> {
+        self.fingerprint.guess()
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for OsDetect {
+    fn reset(&mut self) {
+        self.fingerprint = Fingerprint::default();
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        self.fingerprint.setup(xfer.request());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use usb_device::control::Recipient;
+    use usb_device::UsbDirection;
+
+    // Enumeration of a HID keyboard, before the string descriptor requests
+    const ENUMERATION: [[u8; 8]; 5] = [
+        [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00], // device, 64 bytes
+        [0x00, 0x05, 0x0b, 0x00, 0x00, 0x00, 0x00, 0x00], // set address
+        [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00], // device
+        [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0x09, 0x00], // configuration
+        [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0x54, 0x00],
+    ];
+    // After the string descriptor requests
+    const CONFIGURATION: [[u8; 8]; 4] = [
+        [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00], // set configuration
+        [0x21, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // HID set idle
+        [0x81, 0x06, 0x00, 0x22, 0x00, 0x00, 0x41, 0x00], // HID report descriptor
+        [0xa1, 0x01, 0x00, 0x03, 0x00, 0x00, 0x40, 0x00], // HID feature report
+    ];
+
+    // String descriptor requests: languages, then product, manufacturer and
+    // serial number in US English
+    const LINUX: [[u8; 8]; 4] = [
+        [0x80, 0x06, 0x00, 0x03, 0x00, 0x00, 0xff, 0x00],
+        [0x80, 0x06, 0x02, 0x03, 0x09, 0x04, 0xff, 0x00],
+        [0x80, 0x06, 0x01, 0x03, 0x09, 0x04, 0xff, 0x00],
+        [0x80, 0x06, 0x03, 0x03, 0x09, 0x04, 0xff, 0x00],
+    ];
+    const WINDOWS: [[u8; 8]; 7] = [
+        [0x80, 0x06, 0x00, 0x03, 0x00, 0x00, 0xff, 0x00],
+        [0x80, 0x06, 0x03, 0x03, 0x09, 0x04, 0xff, 0x00],
+        [0x80, 0x06, 0xee, 0x03, 0x00, 0x00, 0x12, 0x00], // Microsoft OS
+        [0x80, 0x06, 0x00, 0x03, 0x00, 0x00, 0x04, 0x00],
+        [0x80, 0x06, 0x02, 0x03, 0x09, 0x04, 0x24, 0x00],
+        [0x80, 0x06, 0x00, 0x03, 0x00, 0x00, 0x04, 0x00],
+        [0x80, 0x06, 0x02, 0x03, 0x09, 0x04, 0x24, 0x00],
+    ];
+    const MACOS: [[u8; 8]; 6] = [
+        [0x80, 0x06, 0x00, 0x03, 0x00, 0x00, 0x02, 0x00],
+        [0x80, 0x06, 0x00, 0x03, 0x00, 0x00, 0x04, 0x00],
+        [0x80, 0x06, 0x02, 0x03, 0x09, 0x04, 0x02, 0x00],
+        [0x80, 0x06, 0x02, 0x03, 0x09, 0x04, 0x32, 0x00],
+        [0x80, 0x06, 0x01, 0x03, 0x09, 0x04, 0x24, 0x00],
+        [0x80, 0x06, 0x03, 0x03, 0x09, 0x04, 0xff, 0x00],
+    ];
+    const IPAD: [[u8; 8]; 4] = [
+        [0x80, 0x06, 0x02, 0x03, 0x09, 0x04, 0x02, 0x00],
+        [0x80, 0x06, 0x02, 0x03, 0x09, 0x04, 0x24, 0x00],
+        [0x80, 0x06, 0x01, 0x03, 0x09, 0x04, 0x02, 0x00],
+        [0x80, 0x06, 0x01, 0x03, 0x09, 0x04, 0x24, 0x00],
+    ];
+
+    fn parse(packet: &[u8; 8]) -> Request {
+        let word = |i: usize| u16::from_le_bytes([packet[i], packet[i + 1]]);
+        Request {
+            direction: if packet[0] & 0x80 != 0 {
+                UsbDirection::In
+            } else {
+                UsbDirection::Out
+            },
+            request_type: match packet[0] >> 5 & 3 {
+                0 => RequestType::Standard,
+                1 => RequestType::Class,
+                2 => RequestType::Vendor,
+                _ => RequestType::Reserved,
+            },
+            recipient: match packet[0] & 0x1f {
+                0 => Recipient::Device,
+                1 => Recipient::Interface,
+                2 => Recipient::Endpoint,
+                3 => Recipient::Other,
+                _ => Recipient::Reserved,
+            },
+            request: packet[1],
+            value: word(2),
+            index: word(4),
+            length: word(6),
+        }
+    }
+
+    fn replay(strings: &[[u8; 8]]) -> Option<Os> {
+        let mut fingerprint = Fingerprint::default();
+        let packets = ENUMERATION.iter().chain(strings).chain(&CONFIGURATION);
+        for packet in packets {
+            fingerprint.setup(&parse(packet));
+        }
+        fingerprint.guess()
+    }
+
+    #[test]
+    fn guesses_each_host() {
+        assert_eq!(replay(&LINUX), Some(Os::Linux));
+        assert_eq!(replay(&WINDOWS), Some(Os::Windows));
+        assert_eq!(replay(&MACOS), Some(Os::MacOs));
+        assert_eq!(replay(&IPAD), Some(Os::MacOs));
+    }
+
+    #[test]
+    fn counts_only_string_descriptors() {
+        assert_eq!(replay(&[]), None);
+        assert_eq!(replay(&LINUX[..2]), None);
+    }
+}
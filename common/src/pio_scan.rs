@@ -0,0 +1,115 @@
+/// Matrix scan by a PIO state machine looping over the rows by itself: for
+/// each row it makes the row pin an output driving low, waits for the matrix
+/// to settle and pushes a snapshot of the GPIOs tagged with the row.
+///
+/// The state machine is given the direction masks of the rows once, packed
+/// in a word for `out pindirs` over the span of the row pins, last row
+/// first. Each word it pushes holds the row in its top 2 bits and GPIOs 0 to
+/// 29 below. A column reads pressed when its GPIO is low.
+pub struct PioScan<const CS: usize, const RS: usize> {
+    // GPIO of each column, `None` when unwired
+    cols: [Option<u8>; CS],
+    rows: [u8; RS],
+    // last GPIOs read with each row driven
+    snapshots: [u32; RS],
+}
+
+impl<const CS: usize, const RS: usize> PioScan<CS, RS> {
+    pub const fn new(cols: [Option<u8>; CS], rows: [u8; RS]) -> Self {
+        assert!(RS <= 4, "the PIO scan tags up to 4 rows");
+        Self {
+            cols,
+            rows,
+            snapshots: [!0; RS],
+        }
+    }
+
+    /// The first GPIO and the number of pins spanned by the rows, for the
+    /// `out` pins of the state machine.
+    pub fn out_pins(&self) -> (u8, u8) {
+        let first = self.rows.iter().min().copied().unwrap_or(0);
+        let last = self.rows.iter().max().copied().unwrap_or(0);
+        (first, last - first + 1)
+    }
+
+    /// The direction masks of the rows over the `out_pins`, the last row in
+    /// the low bits as it is driven first.
+    pub fn masks(&self) -> u32 {
+        let (first, count) = self.out_pins();
+        assert!(RS * count as usize <= 32, "the row masks fit a word");
+        self.rows
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(i, &gpio)| 1 << (i * count as usize + (gpio - first) as usize))
+            .sum()
+    }
+
+    /// The keys from the `words` pushed since the last call, keeping the
+    /// last snapshot of each row.
+    pub fn decode(&mut self, words: impl IntoIterator<Item = u32>) -> [[bool; CS]; RS] {
+        for word in words {
+            if let Some(snapshot) = self.snapshots.get_mut((word >> 30) as usize) {
+                *snapshot = word & 0x3fff_ffff;
+            }
+        }
+        let mut keys = [[false; CS]; RS];
+        for (keys, snapshot) in keys.iter_mut().zip(&self.snapshots) {
+            for (key, col) in keys.iter_mut().zip(&self.cols) {
+                if let Some(gpio) = col {
+                    *key = snapshot & 1 << gpio == 0;
+                }
+            }
+        }
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Columns on GPIOs 27, 0 and 20 and an unwired one, rows as on skeletyl
+    fn scan() -> PioScan<4, 3> {
+        PioScan::new([Some(27), Some(0), None, Some(20)], [9, 7, 3])
+    }
+
+    // The word pushed with `row` driven and the GPIOs of `pressed` low
+    fn word(row: u32, pressed: &[u8]) -> u32 {
+        let gpios = pressed.iter().fold(0x3fff_ffff, |g, &p| g & !(1 << p));
+        row << 30 | gpios
+    }
+
+    #[test]
+    fn packs_the_row_masks() {
+        let scan = scan();
+        assert_eq!(scan.out_pins(), (3, 7));
+        // GPIO 3 for row 2, then 7 and 9, 7 bits apart
+        assert_eq!(scan.masks(), 1 | 1 << (7 + 4) | 1 << (14 + 6));
+    }
+
+    #[test]
+    fn decodes_each_row() {
+        let mut scan = scan();
+        assert_eq!(scan.decode([]), [[false; 4]; 3]);
+        let keys = scan.decode([word(2, &[20]), word(1, &[]), word(0, &[27, 0])]);
+        let expected = [
+            [true, true, false, false],
+            [false; 4],
+            [false, false, false, true],
+        ];
+        assert_eq!(keys, expected);
+        // Unwired columns and GPIOs outside the columns read released
+        assert_eq!(scan.decode([word(1, &[4, 29])]), expected);
+    }
+
+    #[test]
+    fn keeps_the_last_snapshot() {
+        let mut scan = scan();
+        let words = [word(2, &[0]), word(1, &[]), word(0, &[]), word(2, &[])];
+        assert_eq!(scan.decode(words), [[false; 4]; 3]);
+        // Rows missing from the words keep their snapshot
+        let keys = scan.decode([word(0, &[20])]);
+        assert_eq!(keys, [[false, false, false, true], [false; 4], [false; 4]]);
+    }
+}
@@ -0,0 +1,123 @@
+use heapless::Vec;
+use keyberon::key_code::KeyCode;
+
+use crate::os::Os;
+use crate::typing::Press;
+
+/// Keys sent by the alternate repeat key after the other keys of the pair,
+/// both ways, written for Linux and Windows.
+pub type AltRepeat = (&'static [KeyCode], &'static [KeyCode]);
+
+/// Repeat keys: while held they hold the last key pressed with its
+/// modifiers, or the counterpart of it in the alternate repeat table.
+///
+/// The last press is taken from the report, after hold-taps and layers,
+/// so that a tap of `A_LS` is repeated as `A`.
+#[derive(Default)]
+pub struct Repeat {
+    last: Option<Press>,
+    held: Option<Press>,
+}
+
+impl Repeat {
+    /// Remembers the last of the `presses` of the report.
+    pub fn update(&mut self, presses: &[Press]) {
+        if let Some(&press) = presses.last() {
+            self.last = Some(press);
+        }
+    }
+
+    pub fn press(&mut self) {
+        self.held = self.last;
+    }
+
+    /// Holds the counterpart of the last press in `table`, if any.
+    pub fn press_alt(&mut self, table: &[AltRepeat], os: Os) {
+        let last = match self.last {
+            Some(last) => last,
+            None => return,
+        };
+        // Modifiers are compared without their side
+        let side_free = |press: Press| (press.key, (press.mods | press.mods >> 4) & 0x0f);
+        self.held = table.iter().find_map(|&(a, b)| {
+            let (a, b) = (press(a, os), press(b, os));
+            if side_free(a) == side_free(last) {
+                Some(b)
+            } else if side_free(b) == side_free(last) {
+                Some(a)
+            } else {
+                None
+            }
+        });
+    }
+
+    pub fn release(&mut self) {
+        self.held = None;
+    }
+
+    pub fn keycodes(&self) -> impl Iterator<Item = KeyCode> {
+        self.held.into_iter().flat_map(Press::keycodes)
+    }
+}
+
+// Press of `keys` as held in the report, with Ctrl and Gui swapped on macOS
+fn press(keys: &[KeyCode], os: Os) -> Press {
+    let mut report = Vec::<KeyCode, 8>::new();
+    for &kc in keys {
+        let _ = report.push(os.modifier(kc));
+    }
+    Press::from_keys(&report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::typing::{keys, Typing};
+    use keyberon::key_code::KeyCode::*;
+
+    static TABLE: [AltRepeat; 2] = [(&[LShift, Kb9], &[LShift, Kb0]), (&[LCtrl, Z], &[LCtrl, Y])];
+
+    fn typed(repeat: &mut Repeat, report: &[KeyCode]) {
+        let mut typing = Typing::default();
+        for report in [report, &[]] {
+            repeat.update(&typing.update(&keys(report.iter().copied())));
+        }
+    }
+
+    fn held(repeat: &Repeat) -> std::vec::Vec<KeyCode> {
+        repeat.keycodes().collect()
+    }
+
+    #[test]
+    fn repeats_with_modifiers() {
+        let mut repeat = Repeat::default();
+        repeat.press();
+        assert_eq!(held(&repeat), []);
+        typed(&mut repeat, &[RShift, Kb9]);
+        repeat.press();
+        assert_eq!(held(&repeat), [RShift, Kb9]);
+        repeat.release();
+        assert_eq!(held(&repeat), []);
+    }
+
+    #[test]
+    fn alt_repeats_both_ways() {
+        let mut repeat = Repeat::default();
+        typed(&mut repeat, &[RShift, Kb9]);
+        repeat.press_alt(&TABLE, Os::Linux);
+        assert_eq!(held(&repeat), [LShift, Kb0]);
+        typed(&mut repeat, &[LCtrl, Y]);
+        repeat.press_alt(&TABLE, Os::Linux);
+        assert_eq!(held(&repeat), [LCtrl, Z]);
+    }
+
+    #[test]
+    fn alt_repeat_on_macos() {
+        let mut repeat = Repeat::default();
+        typed(&mut repeat, &[LGui, Y]);
+        repeat.press_alt(&TABLE, Os::MacOs);
+        assert_eq!(held(&repeat), [LGui, Z]);
+        repeat.press_alt(&TABLE, Os::Linux);
+        assert_eq!(held(&repeat), []);
+    }
+}
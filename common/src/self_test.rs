@@ -0,0 +1,223 @@
+use core::fmt;
+use heapless::Vec;
+
+use crate::discovery::Probe;
+use crate::matrix::settle;
+
+// Scans that must all read a key pressed for it to be a fault
+const SCANS: usize = 10;
+
+/// A fault found by the self-test, in matrix coordinates.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Fault {
+    /// A key reads pressed, stuck or bridged to its row.
+    Key(u8, u8),
+    /// Most keys of a row read pressed.
+    Row(u8),
+    /// Every key of a column reads pressed, the column is shorted.
+    Column(u8),
+    /// Two rows shorted together.
+    Rows(u8, u8),
+    /// Two columns shorted together.
+    Columns(u8, u8),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fault::Key(i, j) => write!(f, "stuck key {} {}", i, j),
+            Fault::Row(i) => write!(f, "shorted row {}", i),
+            Fault::Column(j) => write!(f, "shorted column {}", j),
+            Fault::Rows(a, b) => write!(f, "bridged rows {} {}", a, b),
+            Fault::Columns(a, b) => write!(f, "bridged columns {} {}", a, b),
+        }
+    }
+}
+
+/// Power-on self-test of the matrix: as no key should be pressed at boot,
+/// the keys reading pressed are faults, and are ignored until the next
+/// reset. So are the keys of rows or columns bridged together, as a key
+/// pressed on one of them also reads pressed on the other.
+#[derive(Clone)]
+pub struct SelfTest<const C: usize, const R: usize> {
+    bad: [[bool; C]; R],
+    faults: Vec<Fault, 8>,
+}
+
+impl<const C: usize, const R: usize> Default for SelfTest<C, R> {
+    fn default() -> Self {
+        Self {
+            bad: [[false; C]; R],
+            faults: Vec::new(),
+        }
+    }
+}
+
+impl<const C: usize, const R: usize> SelfTest<C, R> {
+    /// Looks for bridges on the matrix pins, before they are given to the
+    /// matrix: each row is driven low in turn while the other rows are read,
+    /// then each column. `cols` are the wired columns, in matrix order.
+    pub fn bridges<P: Probe>(rows: &mut [P], cols: &mut [P], settle_cycles: u32) -> Self {
+        let mut self_test = Self::default();
+        for (a, b) in bridged(rows, settle_cycles) {
+            let _ = self_test.faults.push(Fault::Rows(a, b));
+            for i in [a, b].iter().map(|&i| i as usize).filter(|&i| i < R) {
+                self_test.bad[i] = [true; C];
+            }
+        }
+        for (a, b) in bridged(cols, settle_cycles) {
+            let _ = self_test.faults.push(Fault::Columns(a, b));
+            for j in [a, b].iter().map(|&j| j as usize).filter(|&j| j < C) {
+                for row in self_test.bad.iter_mut() {
+                    row[j] = true;
+                }
+            }
+        }
+        self_test
+    }
+
+    /// Runs the test on a few `scan`s of the matrix.
+    pub fn run(&mut self, mut scan: impl FnMut() -> [[bool; C]; R]) {
+        let mut bad = [[true; C]; R];
+        for _ in 0..SCANS {
+            let scan = scan();
+            for (bad, &pressed) in bad.iter_mut().flatten().zip(scan.iter().flatten()) {
+                *bad &= pressed;
+            }
+        }
+        let mut rows = [false; R];
+        for (fault, bad) in rows.iter_mut().zip(&bad) {
+            *fault = 2 * bad.iter().filter(|&&b| b).count() > C;
+        }
+        let mut cols = [false; C];
+        for (j, fault) in cols.iter_mut().enumerate() {
+            *fault = R > 1 && bad.iter().all(|row| row[j]);
+        }
+        let faults = &mut self.faults;
+        for (i, _) in rows.iter().enumerate().filter(|(_, &f)| f) {
+            let _ = faults.push(Fault::Row(i as u8));
+        }
+        for (j, _) in cols.iter().enumerate().filter(|(_, &f)| f) {
+            let _ = faults.push(Fault::Column(j as u8));
+        }
+        for (i, row) in bad.iter().enumerate().filter(|&(i, _)| !rows[i]) {
+            for (j, _) in row.iter().enumerate().filter(|&(j, &b)| b && !cols[j]) {
+                let _ = faults.push(Fault::Key(i as u8, j as u8));
+            }
+        }
+        for (bad, &found) in self.bad.iter_mut().flatten().zip(bad.iter().flatten()) {
+            *bad |= found;
+        }
+    }
+
+    pub fn faults(&self) -> impl Iterator<Item = Fault> + '_ {
+        self.faults.iter().copied()
+    }
+
+    /// Number of PC13 blinks showing the worst fault: 4 for stuck keys, 5
+    /// for a shorted row, 6 for a shorted column, 7 for bridged rows or
+    /// columns, 0 without fault.
+    pub fn blinks(&self) -> u16 {
+        self.faults
+            .iter()
+            .map(|fault| match fault {
+                Fault::Key(..) => 4,
+                Fault::Row(_) => 5,
+                Fault::Column(_) => 6,
+                Fault::Rows(..) | Fault::Columns(..) => 7,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The `scan` without the faulty keys.
+    pub fn filter(&self, mut scan: [[bool; C]; R]) -> [[bool; C]; R] {
+        for (pressed, &bad) in scan.iter_mut().flatten().zip(self.bad.iter().flatten()) {
+            *pressed &= !bad;
+        }
+        scan
+    }
+}
+
+// Pairs of lines reading low together, each line pulled up but the one
+// driven low
+fn bridged<P: Probe>(lines: &mut [P], settle_cycles: u32) -> Vec<(u8, u8), 8> {
+    for line in lines.iter_mut() {
+        line.pull_up();
+    }
+    settle(settle_cycles);
+    let mut pairs = Vec::new();
+    for a in 0..lines.len() {
+        lines[a].drive_low();
+        settle(settle_cycles);
+        for (b, line) in lines.iter().enumerate().skip(a + 1) {
+            if line.is_low() {
+                let _ = pairs.push((a as u8, b as u8));
+            }
+        }
+        lines[a].pull_up();
+        settle(settle_cycles);
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_net::{Net, Pin};
+    use std::vec::Vec;
+
+    // Rows on pins 0 to 2, columns on pins 3 to 6
+    fn bridges(links: &[(usize, usize, bool)]) -> Vec<Fault> {
+        let net = Net::default();
+        net.link(links);
+        let mut rows: [Pin; 3] = core::array::from_fn(|i| net.pin(i));
+        let mut cols: [Pin; 4] = core::array::from_fn(|j| net.pin(3 + j));
+        let self_test = SelfTest::<4, 3>::bridges(&mut rows, &mut cols, 0);
+        self_test.faults().collect()
+    }
+
+    #[test]
+    fn finds_bridged_lines() {
+        assert_eq!(bridges(&[]), []);
+        assert_eq!(bridges(&[(2, 0, false)]), [Fault::Rows(0, 2)]);
+        assert_eq!(
+            bridges(&[(4, 6, false), (3, 6, false), (1, 5, true)]),
+            [
+                Fault::Columns(0, 1),
+                Fault::Columns(0, 3),
+                Fault::Columns(1, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn bridged_lines_give_no_presses() {
+        // Rows 0 and 1 bridged, then columns 1 and 2
+        let net = Net::default();
+        net.link(&[(0, 1, false), (4, 5, false)]);
+        let mut rows: [Pin; 3] = core::array::from_fn(|i| net.pin(i));
+        let mut cols: [Pin; 3] = core::array::from_fn(|j| net.pin(3 + j));
+        let mut self_test = SelfTest::<3, 3>::bridges(&mut rows, &mut cols, 0);
+        self_test.run(|| [[false; 3]; 3]);
+        assert_eq!(self_test.blinks(), 7);
+        // A key pressed at (0, 1) also reads pressed at (1, 1) and (0, 2)
+        let scan = [[false, true, true], [false, true, false], [false; 3]];
+        assert_eq!(self_test.filter(scan), [[false; 3]; 3]);
+        let scan = [[false; 3], [false; 3], [true, false, false]];
+        assert_eq!(self_test.filter(scan), scan);
+    }
+
+    #[test]
+    fn finds_stuck_keys_and_shorts() {
+        let mut self_test = SelfTest::<3, 2>::default();
+        self_test.run(|| [[false, true, false], [true, true, false]]);
+        let faults: Vec<_> = self_test.faults().collect();
+        assert_eq!(faults, [Fault::Row(1), Fault::Column(1)]);
+        assert_eq!(self_test.blinks(), 6);
+        assert_eq!(
+            self_test.filter([[true; 3]; 2]),
+            [[true, false, true], [false, false, true]]
+        );
+    }
+}
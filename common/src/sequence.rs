@@ -0,0 +1,78 @@
+use heapless::{Deque, Vec};
+use keyberon::key_code::KeyCode;
+
+/// One change of the keys held by a `Sequence`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Step {
+    Press(KeyCode),
+    Release(KeyCode),
+}
+
+/// Keys typed by the firmware itself, one step per report.
+///
+/// While a sequence is playing its keys replace the layout's in the report,
+/// so held modifiers do not change what the host receives.
+#[derive(Default)]
+pub struct Sequence {
+    steps: Deque<Step, 256>,
+    held: Vec<KeyCode, 8>,
+}
+
+impl Sequence {
+    /// Queues `steps`, or nothing if they do not all fit.
+    pub fn push(&mut self, steps: &[Step]) -> bool {
+        if self.steps.capacity() - self.steps.len() < steps.len() {
+            return false;
+        }
+        for &step in steps {
+            let _ = self.steps.push_back(step);
+        }
+        true
+    }
+
+    /// Queues the steps still to play in `other`, or nothing if they do not
+    /// all fit.
+    pub fn append(&mut self, other: &Sequence) -> bool {
+        if self.steps.capacity() - self.steps.len() < other.steps.len() {
+            return false;
+        }
+        for &step in other.steps.iter() {
+            let _ = self.steps.push_back(step);
+        }
+        true
+    }
+
+    /// Plays the next step, once per report.
+    pub fn tick(&mut self) {
+        match self.steps.pop_front() {
+            Some(Step::Press(kc)) if !self.held.contains(&kc) => {
+                let _ = self.held.push(kc);
+            }
+            Some(Step::Press(_)) => (),
+            Some(Step::Release(kc)) => self.held.retain(|&k| k != kc),
+            None => (),
+        }
+    }
+
+    /// Drops the steps still to play, releasing the keys held.
+    pub fn clear(&mut self) {
+        self.steps.clear();
+        self.held.clear();
+    }
+
+    pub fn is_playing(&self) -> bool {
+        !self.steps.is_empty() || !self.held.is_empty()
+    }
+
+    pub fn keycodes(&self) -> impl Iterator<Item = KeyCode> + '_ {
+        self.held.iter().copied()
+    }
+}
+
+/// Steps built before being pushed at once to a `Sequence`.
+pub type Steps = Vec<Step, 64>;
+
+/// Adds a press and a release of `kc`.
+pub fn tap(steps: &mut Steps, kc: KeyCode) -> bool {
+    steps.push(Step::Press(kc)).is_ok() && steps.push(Step::Release(kc)).is_ok()
+}
@@ -0,0 +1,349 @@
+use heapless::Vec;
+use keyberon::key_code::KeyCode;
+
+/// Settings that can be changed at runtime and are persisted to flash.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Settings {
+    pub tapping_term: u16,
+    pub debounce: u16,
+    pub default_layer: u16,
+    pub os: u16,
+    pub host_layout: u16,
+}
+
+/// Longest `tapping_term`, the timeout the layouts give their hold-taps.
+pub const MAX_TAPPING_TERM: u16 = 1000;
+
+// name, min, max
+const FIELDS: [(&str, u16, u16); 5] = [
+    ("tapping_term", 50, MAX_TAPPING_TERM),
+    ("debounce", 1, 50),
+    // 0 QWERTY, 1 Colemak-DH, 2 Dvorak
+    ("default_layer", 0, 2),
+    // 0 Linux, 1 Windows, 2 macOS, 3 detected
+    ("os", 0, 3),
+    // 0 US, 1 UK, 2 DE, 3 FR, 4 Nordic
+    ("host_layout", 0, 4),
+];
+
+const MAGIC: u16 = 0x4b53;
+pub const SIZE: usize = 4 + 2 * FIELDS.len() + 2;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Error {
+    UnknownName,
+    OutOfRange,
+}
+
+impl Settings {
+    fn values(&self) -> [u16; FIELDS.len()] {
+        [
+            self.tapping_term,
+            self.debounce,
+            self.default_layer,
+            self.os,
+            self.host_layout,
+        ]
+    }
+
+    fn values_mut(&mut self) -> [&mut u16; FIELDS.len()] {
+        [
+            &mut self.tapping_term,
+            &mut self.debounce,
+            &mut self.default_layer,
+            &mut self.os,
+            &mut self.host_layout,
+        ]
+    }
+
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        FIELDS.iter().map(|&(name, _, _)| name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<u16> {
+        index(name).map(|i| self.values()[i])
+    }
+
+    pub fn set(&mut self, name: &str, value: u16) -> Result<(), Error> {
+        let i = index(name).ok_or(Error::UnknownName)?;
+        let (_, min, max) = FIELDS[i];
+        if value < min || value > max {
+            return Err(Error::OutOfRange);
+        }
+        *self.values_mut()[i] = value;
+        Ok(())
+    }
+
+    pub fn step_tapping_term(&mut self, up: bool) {
+        self.step("tapping_term", 10, up);
+    }
+
+    pub fn step_debounce(&mut self, up: bool) {
+        self.step("debounce", 1, up);
+    }
+
+    // Stops at the bounds rather than refusing the step
+    fn step(&mut self, name: &str, step: u16, up: bool) {
+        if let Some(i) = index(name) {
+            let (_, min, max) = FIELDS[i];
+            let value = self.values()[i];
+            let value = if up {
+                value.saturating_add(step)
+            } else {
+                value.saturating_sub(step)
+            };
+            *self.values_mut()[i] = value.max(min).min(max);
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; SIZE] {
+        let mut bytes = [0; SIZE];
+        bytes[0..2].copy_from_slice(&MAGIC.to_le_bytes());
+        bytes[2..4].copy_from_slice(&(FIELDS.len() as u16).to_le_bytes());
+        for (i, value) in self.values().iter().enumerate() {
+            bytes[4 + 2 * i..6 + 2 * i].copy_from_slice(&value.to_le_bytes());
+        }
+        let sum = checksum(&bytes[..SIZE - 2]);
+        bytes[SIZE - 2..].copy_from_slice(&sum.to_le_bytes());
+        bytes
+    }
+
+    /// Reads settings saved by `to_bytes`, possibly by a firmware with fewer
+    /// fields. Missing or invalid fields keep their value from `defaults`.
+    pub fn from_bytes(bytes: &[u8], defaults: Settings) -> Settings {
+        let mut settings = defaults;
+        let word = |i: usize| {
+            bytes
+                .get(i..i + 2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        };
+        if word(0) != Some(MAGIC) {
+            return settings;
+        }
+        let count = word(2).unwrap_or(0) as usize;
+        let end = 4 + 2 * count;
+        match word(end) {
+            Some(sum) if sum == checksum(&bytes[..end]) => (),
+            _ => return settings,
+        }
+        for (i, &(name, _, _)) in FIELDS.iter().enumerate().take(count) {
+            if let Some(value) = word(4 + 2 * i) {
+                let _ = settings.set(name, value);
+            }
+        }
+        settings
+    }
+}
+
+fn index(name: &str) -> Option<usize> {
+    FIELDS.iter().position(|&(n, _, _)| n == name)
+}
+
+fn checksum(bytes: &[u8]) -> u16 {
+    bytes
+        .iter()
+        .fold(0xffffu16, |sum, &b| sum.rotate_left(5) ^ b as u16)
+}
+
+/// Shortens the hold-tap timeouts, declared as `MAX_TAPPING_TERM`, to
+/// `Settings::tapping_term` ms without rebuilding the layers. Called once per
+/// 1 ms scan, it gives the `Layout::tick` calls to run: one, or more while a
+/// hold-tap is held so that its timeout runs faster. The other keys never
+/// wait for a tick.
+#[derive(Default)]
+pub struct TapClock {
+    acc: u32,
+}
+
+impl TapClock {
+    pub fn ticks(&mut self, holding: bool, tapping_term: u16) -> Ticks {
+        if !holding {
+            self.acc = 0;
+            return Ticks::new(1);
+        }
+        let term = tapping_term.clamp(1, MAX_TAPPING_TERM) as u32;
+        self.acc += MAX_TAPPING_TERM as u32;
+        let ticks = self.acc / term;
+        self.acc %= term;
+        Ticks::new(ticks)
+    }
+}
+
+/// The `Layout::tick` calls of one scan. The layout unstacks one event per
+/// tick, so the extra ticks stop once a tick changes the keys to report or
+/// returns a custom event: a key tapped behind a hold-tap is still reported
+/// rather than pressed and released between two reports.
+pub struct Ticks {
+    left: u32,
+    keys: Option<Vec<KeyCode, 16>>,
+}
+
+impl Ticks {
+    fn new(left: u32) -> Self {
+        Self { left, keys: None }
+    }
+
+    /// Whether to run another tick, given the keycodes of the layout.
+    pub fn next(&mut self, keycodes: impl Iterator<Item = KeyCode>) -> bool {
+        let mut keys = Vec::new();
+        let mut all = true;
+        for kc in keycodes {
+            all &= keys.push(kc).is_ok();
+        }
+        let changed = match &self.keys {
+            Some(before) => !all || *before != keys,
+            None => false,
+        };
+        if changed || self.left == 0 {
+            return false;
+        }
+        self.left -= 1;
+        self.keys = Some(keys);
+        true
+    }
+
+    /// Ends the ticks after a custom event.
+    pub fn stop(&mut self) {
+        self.left = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keyberon::key_code::KeyCode::J;
+
+    const DEFAULTS: Settings = Settings {
+        tapping_term: 200,
+        debounce: 5,
+        default_layer: 0,
+        os: 3,
+        host_layout: 0,
+    };
+    const SAVED: Settings = Settings {
+        tapping_term: 180,
+        debounce: 12,
+        default_layer: 2,
+        os: 2,
+        host_layout: 4,
+    };
+
+    #[test]
+    fn bytes_round_trip() {
+        assert_eq!(Settings::from_bytes(&SAVED.to_bytes(), DEFAULTS), SAVED);
+    }
+
+    #[test]
+    fn bad_bytes_give_defaults() {
+        assert_eq!(Settings::from_bytes(&[0xff; SIZE], DEFAULTS), DEFAULTS);
+        let mut bytes = SAVED.to_bytes();
+        bytes[4] ^= 1;
+        assert_eq!(Settings::from_bytes(&bytes, DEFAULTS), DEFAULTS);
+        assert_eq!(Settings::from_bytes(&bytes[..8], DEFAULTS), DEFAULTS);
+    }
+
+    #[test]
+    fn older_firmware_bytes() {
+        // Saved with the first 2 fields only
+        let mut bytes = [0; 10];
+        bytes[0..2].copy_from_slice(&MAGIC.to_le_bytes());
+        bytes[2..4].copy_from_slice(&2u16.to_le_bytes());
+        bytes[4..6].copy_from_slice(&300u16.to_le_bytes());
+        bytes[6..8].copy_from_slice(&100u16.to_le_bytes());
+        let sum = checksum(&bytes[..8]);
+        bytes[8..].copy_from_slice(&sum.to_le_bytes());
+        let settings = Settings::from_bytes(&bytes, DEFAULTS);
+        // debounce out of range keeps its default
+        assert_eq!(
+            settings,
+            Settings {
+                tapping_term: 300,
+                ..DEFAULTS
+            }
+        );
+    }
+
+    #[test]
+    fn set_and_step() {
+        let mut settings = DEFAULTS;
+        assert_eq!(settings.set("debounce", 20), Ok(()));
+        assert_eq!(settings.get("debounce"), Some(20));
+        assert_eq!(settings.set("debounce", 51), Err(Error::OutOfRange));
+        assert_eq!(settings.set("speed", 1), Err(Error::UnknownName));
+        settings.step_tapping_term(true);
+        assert_eq!(settings.tapping_term, 210);
+        settings.tapping_term = 55;
+        settings.step_tapping_term(false);
+        assert_eq!(settings.tapping_term, 50);
+        settings.debounce = 50;
+        settings.step_debounce(true);
+        assert_eq!(settings.debounce, 50);
+    }
+
+    #[test]
+    fn tap_clock_ticks_once_when_idle() {
+        let mut clock = TapClock::default();
+        for _ in 0..10 {
+            assert_eq!(count(clock.ticks(false, 180)), 1);
+        }
+    }
+
+    #[test]
+    fn tap_clock_times_out_after_the_term() {
+        for term in (50..=MAX_TAPPING_TERM).step_by(10) {
+            let mut clock = TapClock::default();
+            let mut ticks = 0;
+            let mut ms = 0;
+            while ticks < MAX_TAPPING_TERM as u32 {
+                let n = count(clock.ticks(true, term));
+                assert!(n >= 1);
+                ticks += n;
+                ms += 1;
+            }
+            assert_eq!(ms, term, "tapping_term {}", term);
+        }
+    }
+
+    #[test]
+    fn tap_clock_restarts_after_a_hold() {
+        let mut clock = TapClock::default();
+        assert_eq!(count(clock.ticks(true, 300)), 3);
+        assert_eq!(count(clock.ticks(false, 300)), 1);
+        // No leftover from the previous hold
+        assert_eq!(count(clock.ticks(true, 300)), 3);
+    }
+
+    #[test]
+    fn tap_clock_reports_keys_tapped_behind_a_hold() {
+        // A hold-tap resolved as a hold, with a tap of J stacked behind it
+        // that the layout unstacks one event per tick
+        let mut stacked = [true, false].into_iter();
+        let mut j = false;
+        let mut clock = TapClock::default();
+        let mut reports = std::vec::Vec::new();
+        for _ in 0..3 {
+            let mut ticks = clock.ticks(true, 200);
+            while ticks.next(Some(J).filter(|_| j).into_iter()) {
+                j = stacked.next().unwrap_or(j);
+            }
+            reports.push(j);
+        }
+        assert_eq!(reports, [true, false, false]);
+    }
+
+    #[test]
+    fn tap_clock_stops_after_a_custom_event() {
+        let mut ticks = TapClock::default().ticks(true, 200);
+        assert!(ticks.next(None.into_iter()));
+        ticks.stop();
+        assert!(!ticks.next(None.into_iter()));
+    }
+
+    fn count(mut ticks: Ticks) -> u32 {
+        let mut n = 0;
+        while ticks.next(None.into_iter()) {
+            n += 1;
+        }
+        n
+    }
+}
@@ -0,0 +1,191 @@
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+use crate::matrix::settle;
+
+/// Error of a matrix read through a bus.
+#[derive(Debug)]
+pub enum Error<B, P> {
+    Bus(B),
+    Pin(P),
+}
+
+impl<B, P> From<P> for Error<B, P> {
+    fn from(e: P) -> Self {
+        Error::Pin(e)
+    }
+}
+
+/// The columns of a matrix, read all at once.
+pub trait Columns<const CS: usize> {
+    type Error;
+    fn read(&mut self) -> Result<[bool; CS], Self::Error>;
+}
+
+/// 74HC165 chain read by bit-banging: `load` low latches the columns, then
+/// each rising edge of `clock` shifts the next one to `data`.
+///
+/// The first bit shifted out is column 0, a key reads low.
+pub struct BitBang<L, K, D> {
+    load: L,
+    clock: K,
+    data: D,
+}
+
+impl<L, K, D, E> BitBang<L, K, D>
+where
+    L: OutputPin<Error = E>,
+    K: OutputPin<Error = E>,
+{
+    pub fn new(mut load: L, mut clock: K, data: D) -> Result<Self, E> {
+        load.set_high()?;
+        clock.set_low()?;
+        Ok(Self { load, clock, data })
+    }
+}
+
+impl<L, K, D, E, const CS: usize> Columns<CS> for BitBang<L, K, D>
+where
+    L: OutputPin<Error = E>,
+    K: OutputPin<Error = E>,
+    D: InputPin<Error = E>,
+{
+    type Error = E;
+
+    fn read(&mut self) -> Result<[bool; CS], E> {
+        let mut cols = [false; CS];
+        self.load.set_low()?;
+        self.load.set_high()?;
+        for col in cols.iter_mut() {
+            *col = self.data.is_low()?;
+            self.clock.set_high()?;
+            self.clock.set_low()?;
+        }
+        Ok(cols)
+    }
+}
+
+/// 74HC165 chain of up to 64 columns read over SPI, mode 0, with `load` on
+/// a GPIO.
+///
+/// The bytes are read MSB first, the first bit shifted out is column 0 and
+/// a key reads low.
+pub struct Spi<S, L> {
+    spi: S,
+    load: L,
+}
+
+impl<S, L: OutputPin> Spi<S, L> {
+    pub fn new(spi: S, mut load: L) -> Result<Self, L::Error> {
+        load.set_high()?;
+        Ok(Self { spi, load })
+    }
+}
+
+impl<S, L, const CS: usize> Columns<CS> for Spi<S, L>
+where
+    S: Transfer<u8>,
+    L: OutputPin,
+{
+    type Error = Error<S::Error, L::Error>;
+
+    fn read(&mut self) -> Result<[bool; CS], Self::Error> {
+        assert!(CS <= 64, "the SPI chain reads up to 64 columns");
+        self.load.set_low()?;
+        self.load.set_high()?;
+        let mut bytes = [0xff; 8];
+        let len = (CS.max(1) - 1) / 8 + 1;
+        let bytes = self.spi.transfer(&mut bytes[..len]).map_err(Error::Bus)?;
+        let mut cols = [false; CS];
+        for (j, col) in cols.iter_mut().enumerate() {
+            *col = bytes[j / 8] & (0x80 >> (j % 8)) == 0;
+        }
+        Ok(cols)
+    }
+}
+
+/// Key matrix with its rows on GPIOs and its columns read through shift
+/// registers, replacing keyberon's `Matrix`.
+pub struct ShiftMatrix<C, R, const CS: usize, const RS: usize> {
+    cols: C,
+    rows: [R; RS],
+    settle: u32,
+}
+
+impl<C, R, const CS: usize, const RS: usize> ShiftMatrix<C, R, CS, RS>
+where
+    C: Columns<CS>,
+    C::Error: From<R::Error>,
+    R: OutputPin,
+{
+    pub fn new(cols: C, mut rows: [R; RS], settle: u32) -> Result<Self, C::Error> {
+        for row in rows.iter_mut() {
+            row.set_high()?;
+        }
+        Ok(Self { cols, rows, settle })
+    }
+
+    pub fn get(&mut self) -> Result<[[bool; CS]; RS], C::Error> {
+        let mut keys = [[false; CS]; RS];
+        for (row, keys) in self.rows.iter_mut().zip(keys.iter_mut()) {
+            row.set_low()?;
+            settle(self.settle);
+            *keys = self.cols.read()?;
+            row.set_high()?;
+        }
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_hal_mock::eh0::digital::{Mock, State, Transaction};
+    use embedded_hal_mock::eh0::spi;
+
+    // A pin set to `states` in turn
+    fn set(states: &[State]) -> Mock {
+        let sets: std::vec::Vec<_> = states.iter().map(|&s| Transaction::set(s)).collect();
+        Mock::new(&sets)
+    }
+
+    // The load pin, high from `new`, then pulsed low once per read
+    fn load() -> Mock {
+        set(&[State::High, State::Low, State::High])
+    }
+
+    #[test]
+    fn bit_bang_shifts_column_0_first() {
+        use State::*;
+        let clock = set(&[Low, High, Low, High, Low, High, Low]);
+        let data = Mock::new(&[Low, High, Low].map(Transaction::get));
+        let mut chain = BitBang::new(load(), clock, data).unwrap();
+        assert_eq!(chain.read().unwrap(), [true, false, true]);
+        chain.load.done();
+        chain.clock.done();
+        chain.data.done();
+    }
+
+    #[test]
+    fn spi_reads_msb_first() {
+        // Columns 0, 7 and 9 pressed, the bytes of the 10 columns shifted in
+        let bytes = vec![0b0111_1110, 0b1011_1111];
+        let bus = spi::Mock::new(&[spi::Transaction::transfer(vec![0xff; 2], bytes)]);
+        let mut chain = Spi::new(bus, load()).unwrap();
+        let mut expected = [false; 10];
+        for j in [0, 7, 9] {
+            expected[j] = true;
+        }
+        assert_eq!(chain.read().unwrap(), expected);
+        chain.spi.done();
+        chain.load.done();
+    }
+
+    #[test]
+    #[should_panic(expected = "64 columns")]
+    fn spi_reads_up_to_64_columns() {
+        let bus = spi::Mock::new(&[]);
+        let mut chain = Spi::new(bus, set(&[State::High])).unwrap();
+        let _: Result<[bool; 65], _> = chain.read();
+    }
+}
@@ -0,0 +1,190 @@
+use heapless::Deque;
+use keyberon::key_code::KeyCode;
+
+use crate::keymap::HostLayout;
+use crate::sequence::{tap, Sequence, Steps};
+use crate::typing::{Keys, Press};
+use crate::unicode::{self, Method};
+
+// Characters remembered, the longest trigger
+const BUFFER: usize = 16;
+
+/// Checks a snippet table at build time: triggers are printable ASCII
+/// without spaces, and none of them ends another one.
+pub const fn check(snippets: &[(&str, &str)]) {
+    let mut i = 0;
+    while i < snippets.len() {
+        let trigger = snippets[i].0.as_bytes();
+        assert!(
+            !trigger.is_empty() && trigger.len() <= BUFFER,
+            "bad trigger length"
+        );
+        let mut k = 0;
+        while k < trigger.len() {
+            assert!(trigger[k].is_ascii_graphic(), "trigger not printable ASCII");
+            k += 1;
+        }
+        let mut j = 0;
+        while j < snippets.len() {
+            assert!(
+                i == j || !ends_with(snippets[j].0.as_bytes(), trigger),
+                "ambiguous snippet trigger"
+            );
+            j += 1;
+        }
+        i += 1;
+    }
+}
+
+const fn ends_with(text: &[u8], suffix: &[u8]) -> bool {
+    if text.len() < suffix.len() {
+        return false;
+    }
+    let mut k = 0;
+    while k < suffix.len() {
+        if text[text.len() - suffix.len() + k] != suffix[k] {
+            return false;
+        }
+        k += 1;
+    }
+    true
+}
+
+/// Text expansion: a trigger followed by a space is replaced by its
+/// expansion, the space included. An expansion too long for the sequence
+/// queue leaves the trigger and the space as typed.
+///
+/// The characters typed recently are kept in a rolling buffer, Backspace
+/// removes the last one and keys without a character (arrows, shortcuts)
+/// clear it.
+pub struct Snippets {
+    table: &'static [(&'static str, &'static str)],
+    typed: Deque<u8, BUFFER>,
+    hidden: Option<KeyCode>,
+}
+
+impl Snippets {
+    pub fn new(table: &'static [(&'static str, &'static str)]) -> Self {
+        Self {
+            table,
+            typed: Deque::new(),
+            hidden: None,
+        }
+    }
+
+    fn push(&mut self, c: u8) {
+        if self.typed.is_full() {
+            self.typed.pop_front();
+        }
+        let _ = self.typed.push_back(c);
+    }
+
+    fn ends_with(&self, trigger: &str) -> bool {
+        trigger.len() <= self.typed.len()
+            && trigger
+                .bytes()
+                .rev()
+                .zip(self.typed.iter().rev())
+                .all(|(a, &b)| a == b)
+    }
+
+    /// Follows the `presses` reaching the report `keys`, expanding a
+    /// trigger when it is followed by a space.
+    pub fn filter(
+        &mut self,
+        keys: &mut Keys,
+        presses: &[Press],
+        sequence: &mut Sequence,
+        method: Method,
+        layout: HostLayout,
+    ) {
+        for press in presses.iter().filter(|press| keys.contains(&press.key)) {
+            match (press.char(layout), press.key) {
+                (Some(' '), _) => {
+                    let table = self.table;
+                    match table.iter().find(|(trigger, _)| self.ends_with(trigger)) {
+                        Some(&(trigger, expansion)) => {
+                            // Sized up before erasing the trigger
+                            let mut expanded = Sequence::default();
+                            let mut steps = Steps::new();
+                            if trigger.bytes().all(|_| tap(&mut steps, KeyCode::BSpace))
+                                && expanded.push(&steps)
+                                && unicode::type_str(&mut expanded, method, layout, expansion)
+                                && sequence.append(&expanded)
+                            {
+                                self.hidden = Some(press.key);
+                            }
+                            self.typed.clear();
+                        }
+                        None => self.push(b' '),
+                    }
+                }
+                (Some(c), _) => self.push(c as u8),
+                (None, KeyCode::BSpace) => {
+                    self.typed.pop_back();
+                }
+                (None, _) => self.typed.clear(),
+            }
+        }
+        if let Some(kc) = self.hidden {
+            if keys.contains(&kc) {
+                keys.retain(|&k| k != kc);
+            } else {
+                self.hidden = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_host::{self, strokes};
+    use std::string::String;
+
+    static SNIPPETS: [(&str, &str); 4] = [
+        (";em", "cam@example.com"),
+        (";shrug", "¯\\_(ツ)_/¯"),
+        (";ty", "Thank you!"),
+        (";long", "éééééééééééééééééééééééééééééé"),
+    ];
+
+    fn typed(text: &str) -> String {
+        let mut snippets = Snippets::new(&SNIPPETS);
+        let layout = HostLayout::Us;
+        mock_host::run(strokes(text, layout), layout, |keys, presses, sequence| {
+            snippets.filter(keys, presses, sequence, Method::Linux, layout)
+        })
+    }
+
+    #[test]
+    fn expands_triggers() {
+        check(&SNIPPETS);
+        assert_eq!(typed("mail ;em x"), "mail cam@example.comx");
+        assert_eq!(typed(";ty ;shrug "), "Thank you!¯\\_(ツ)_/¯");
+        assert_eq!(typed(";tx\x08y "), "Thank you!");
+        assert_eq!(typed("x;ty "), "xThank you!");
+        assert_eq!(typed(";ty\n "), ";ty\n ");
+    }
+
+    #[test]
+    fn keeps_the_keys_typed_during_an_expansion() {
+        assert_eq!(typed(";shrug and more"), "¯\\_(ツ)_/¯and more");
+    }
+
+    #[test]
+    fn keeps_a_trigger_too_long_to_expand() {
+        assert_eq!(typed(";long x"), ";long x");
+    }
+
+    #[test]
+    fn prefixes_are_not_ambiguous() {
+        check(&[(";a", "x"), (";ab", "y")]);
+    }
+
+    #[test]
+    #[should_panic(expected = "ambiguous snippet trigger")]
+    fn rejects_a_trigger_ending_another() {
+        check(&[("ty", "x"), (";ty", "y")]);
+    }
+}
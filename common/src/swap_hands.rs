@@ -0,0 +1,105 @@
+use heapless::Vec;
+use keyberon::layout::Event;
+
+/// Swap-hands mode: while it is held or toggled on, every key acts as its
+/// mirror on the other hand.
+///
+/// The mirror map pairs the columns of the matrix, rows are kept. A key
+/// pressed while swapped is released swapped, even if the mode ends while
+/// it is held.
+pub struct SwapHands {
+    mirror: &'static [(u8, u8)],
+    held: bool,
+    toggled: bool,
+    // matrix coordinates of the keys pressed while swapped
+    pressed: Vec<(u8, u8), 16>,
+}
+
+impl SwapHands {
+    pub fn new(mirror: &'static [(u8, u8)]) -> Self {
+        Self {
+            mirror,
+            held: false,
+            toggled: false,
+            pressed: Vec::new(),
+        }
+    }
+
+    pub fn hold(&mut self, held: bool) {
+        self.held = held;
+    }
+
+    pub fn toggle(&mut self) {
+        self.toggled = !self.toggled;
+    }
+
+    fn mirror(&self, i: u8, j: u8) -> (u8, u8) {
+        let mirrored = self.mirror.iter().find_map(|&(a, b)| {
+            if j == a {
+                Some(b)
+            } else if j == b {
+                Some(a)
+            } else {
+                None
+            }
+        });
+        (i, mirrored.unwrap_or(j))
+    }
+
+    /// The event of the debouncer as seen by the layout.
+    pub fn event(&mut self, event: Event) -> Event {
+        let coord = event.coord();
+        let swap = match event {
+            Event::Press(..) => {
+                let swap = self.held || self.toggled;
+                if swap && self.pressed.push(coord).is_err() {
+                    return event;
+                }
+                swap
+            }
+            Event::Release(..) => match self.pressed.iter().position(|&c| c == coord) {
+                Some(index) => {
+                    self.pressed.swap_remove(index);
+                    true
+                }
+                None => false,
+            },
+        };
+        if swap {
+            event.transform(|i, j| self.mirror(i, j))
+        } else {
+            event
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keyberon::layout::Event::{Press, Release};
+
+    static MIRROR: [(u8, u8); 2] = [(0, 3), (1, 2)];
+
+    #[test]
+    fn mirrors_while_held() {
+        let mut swap = SwapHands::new(&MIRROR);
+        assert_eq!(swap.event(Press(0, 0)), Press(0, 0));
+        swap.hold(true);
+        assert_eq!(swap.event(Press(1, 1)), Press(1, 2));
+        assert_eq!(swap.event(Release(0, 0)), Release(0, 0));
+        swap.hold(false);
+        // Released as it was pressed
+        assert_eq!(swap.event(Release(1, 1)), Release(1, 2));
+        assert_eq!(swap.event(Press(1, 3)), Press(1, 3));
+    }
+
+    #[test]
+    fn mirrors_while_toggled() {
+        let mut swap = SwapHands::new(&MIRROR);
+        swap.toggle();
+        assert_eq!(swap.event(Press(2, 3)), Press(2, 0));
+        swap.toggle();
+        assert_eq!(swap.event(Release(2, 3)), Release(2, 0));
+        assert_eq!(swap.event(Press(2, 3)), Press(2, 3));
+    }
+}
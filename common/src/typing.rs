@@ -0,0 +1,166 @@
+use heapless::{Deque, Vec};
+use keyberon::key_code::KeyCode::{self, *};
+
+use crate::keymap::HostLayout;
+use crate::sequence::{tap, Step, Steps};
+
+/// Keys of one report.
+pub type Keys = Vec<KeyCode, 24>;
+
+/// Collects the keys of a report, dropping the ones past the capacity.
+pub fn keys(keycodes: impl Iterator<Item = KeyCode>) -> Keys {
+    let mut keys = Keys::new();
+    for kc in keycodes {
+        let _ = keys.push(kc);
+    }
+    keys
+}
+
+/// Reports of the layout held back while a `Sequence` plays, so that the keys
+/// typed meanwhile reach the host after it rather than being lost.
+#[derive(Default)]
+pub struct Backlog {
+    reports: Deque<Keys, 32>,
+    last: Keys,
+}
+
+impl Backlog {
+    /// The report to pass on in place of `keys`: the last one passed on while
+    /// a sequence plays, then the ones held back, one per report.
+    pub fn next(&mut self, keys: Keys, playing: bool) -> Keys {
+        if !playing && self.reports.is_empty() {
+            self.last = keys;
+            return self.last.clone();
+        }
+        if *self.reports.back().unwrap_or(&self.last) != keys {
+            // Past the capacity the latest report replaces the newest one held,
+            // so no key is left pressed
+            if self.reports.is_full() {
+                self.reports.pop_back();
+            }
+            let _ = self.reports.push_back(keys);
+        }
+        if !playing {
+            if let Some(keys) = self.reports.pop_front() {
+                self.last = keys;
+            }
+        }
+        self.last.clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reports.is_empty()
+    }
+}
+
+// In the order of the report modifier bits
+const MODIFIERS: [KeyCode; 8] = [LCtrl, LShift, LAlt, LGui, RCtrl, RShift, RAlt, RGui];
+const SHIFT: u8 = 0x22;
+const ALTGR: u8 = 0x40;
+
+/// A key newly pressed in the report, with the modifiers held at the time.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Press {
+    pub key: KeyCode,
+    /// Report modifier bits
+    pub mods: u8,
+}
+
+impl Press {
+    /// The press of the last non-modifier of `keys` with their modifiers.
+    pub fn from_keys(keys: &[KeyCode]) -> Press {
+        let mods = MODIFIERS
+            .iter()
+            .enumerate()
+            .filter(|&(_, kc)| keys.contains(kc))
+            .fold(0, |mods, (i, _)| mods | 1 << i);
+        let key = keys.iter().rev().find(|kc| !kc.is_modifier());
+        Press {
+            key: key.copied().unwrap_or(No),
+            mods,
+        }
+    }
+
+    /// The modifiers and the key, as held in a report.
+    pub fn keycodes(self) -> impl Iterator<Item = KeyCode> {
+        let mods = MODIFIERS.iter().enumerate();
+        mods.filter(move |&(i, _)| self.mods & (1 << i) != 0)
+            .map(|(_, &kc)| kc)
+            .chain(core::iter::once(self.key))
+    }
+
+    /// The character typed on the host, `None` for shortcuts and keys
+    /// without a character.
+    pub fn char(&self, layout: HostLayout) -> Option<char> {
+        if self.mods & !(SHIFT | ALTGR) != 0 {
+            return None;
+        }
+        let (shift, altgr) = (self.mods & SHIFT != 0, self.mods & ALTGR != 0);
+        layout.char_of(self.key, shift, altgr)
+    }
+
+    /// Adds the steps typing this press again.
+    pub fn replay(&self, steps: &mut Steps) -> bool {
+        let mods = MODIFIERS.iter().enumerate();
+        let mut mods = mods
+            .filter(|&(i, _)| self.mods & (1 << i) != 0)
+            .map(|(_, &kc)| kc);
+        mods.clone().all(|kc| steps.push(Step::Press(kc)).is_ok())
+            && tap(steps, self.key)
+            && mods.all(|kc| steps.push(Step::Release(kc)).is_ok())
+    }
+}
+
+/// Finds the keys pressed since the previous report, the input of the
+/// features watching what is typed.
+#[derive(Default)]
+pub struct Typing {
+    last: Keys,
+}
+
+impl Typing {
+    pub fn update(&mut self, keys: &Keys) -> Vec<Press, 8> {
+        let mods = Press::from_keys(keys).mods;
+        let mut presses = Vec::new();
+        for &key in keys.iter() {
+            if !key.is_modifier() && !self.last.contains(&key) {
+                let _ = presses.push(Press { key, mods });
+            }
+        }
+        self.last = keys.clone();
+        presses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backlog_holds_reports_back_while_playing() {
+        let mut backlog = Backlog::default();
+        let a = keys([A].into_iter());
+        assert_eq!(backlog.next(a.clone(), false), a);
+        assert_eq!(backlog.next(Keys::new(), true), a);
+        assert_eq!(backlog.next(keys([B].into_iter()), true), a);
+        assert_eq!(backlog.next(keys([B].into_iter()), false), Keys::new());
+        assert_eq!(backlog.next(Keys::new(), false), keys([B].into_iter()));
+        assert_eq!(backlog.next(Keys::new(), false), Keys::new());
+        assert!(backlog.is_empty());
+    }
+
+    #[test]
+    fn backlog_keeps_the_latest_report_when_full() {
+        let mut backlog = Backlog::default();
+        for i in 0..40 {
+            let kc = if i % 2 == 0 { A } else { B };
+            backlog.next(keys([kc].into_iter()), true);
+        }
+        backlog.next(Keys::new(), true);
+        let mut last = Keys::new();
+        while !backlog.is_empty() {
+            last = backlog.next(Keys::new(), false);
+        }
+        assert_eq!(last, Keys::new());
+    }
+}
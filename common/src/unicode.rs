@@ -0,0 +1,110 @@
+use keyberon::key_code::KeyCode::{self, *};
+
+use crate::keymap::{type_stroke, HostLayout};
+use crate::os::Os;
+use crate::sequence::{tap, Sequence, Step, Steps};
+
+/// Host input method used to type code points.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Method {
+    /// Ctrl+Shift+U, hex digits, Space (IBus, GTK)
+    Linux,
+    /// Compose (right Alt), `u`, hex digits, Enter
+    WinCompose,
+    /// Alt held while typing keypad plus and hex digits, needs the
+    /// EnableHexNumpad registry key, limited to the BMP
+    AltCodes,
+    /// Option held while typing the UTF-16 units, "Unicode Hex Input"
+    MacOs,
+}
+
+impl Method {
+    /// The method of the host `os`, `windows` being the board's choice
+    /// between WinCompose and Alt codes.
+    pub fn for_os(os: Os, windows: Method) -> Method {
+        match os {
+            Os::Linux => Method::Linux,
+            Os::Windows => windows,
+            Os::MacOs => Method::MacOs,
+        }
+    }
+}
+
+/// Queues the key strokes typing `c`, returns false if they do not fit.
+/// The hex digits are typed for the host `layout`.
+pub fn type_char(sequence: &mut Sequence, method: Method, layout: HostLayout, c: char) -> bool {
+    let mut steps = Steps::new();
+    let ok = match method {
+        Method::Linux => {
+            [LCtrl, LShift, U].iter().all(|&kc| press(&mut steps, kc))
+                && [U, LShift, LCtrl].iter().all(|&kc| release(&mut steps, kc))
+                && hex(&mut steps, c as u32, 1, layout, false)
+                && tap(&mut steps, Space)
+        }
+        Method::WinCompose => {
+            tap(&mut steps, RAlt)
+                && text(&mut steps, layout, 'u')
+                && hex(&mut steps, c as u32, 1, layout, false)
+                && tap(&mut steps, Enter)
+        }
+        Method::AltCodes => {
+            (c as u32) < 0x10000
+                && press(&mut steps, LAlt)
+                && tap(&mut steps, KpPlus)
+                && hex(&mut steps, c as u32, 1, layout, true)
+                && release(&mut steps, LAlt)
+        }
+        Method::MacOs => {
+            // Unicode Hex Input is a layout of its own, based on US
+            let mut units = [0; 2];
+            press(&mut steps, LAlt)
+                && c.encode_utf16(&mut units)
+                    .iter()
+                    .all(|&unit| hex(&mut steps, unit as u32, 4, HostLayout::Us, false))
+                && release(&mut steps, LAlt)
+        }
+    };
+    ok && sequence.push(&steps)
+}
+
+fn press(steps: &mut Steps, kc: KeyCode) -> bool {
+    steps.push(Step::Press(kc)).is_ok()
+}
+
+fn release(steps: &mut Steps, kc: KeyCode) -> bool {
+    steps.push(Step::Release(kc)).is_ok()
+}
+
+fn text(steps: &mut Steps, layout: HostLayout, c: char) -> bool {
+    matches!(layout.stroke(c), Some(stroke) if type_stroke(steps, stroke))
+}
+
+// Types the hex digits of `value`, at least `width` of them, 0-9 on the
+// keypad if `keypad`
+fn hex(steps: &mut Steps, value: u32, width: usize, layout: HostLayout, keypad: bool) -> bool {
+    const PAD: [KeyCode; 10] = [Kp0, Kp1, Kp2, Kp3, Kp4, Kp5, Kp6, Kp7, Kp8, Kp9];
+    let digits = (8 - value.leading_zeros() as usize / 4).max(width);
+    (0..digits).rev().all(|i| {
+        let digit = (value >> (4 * i)) & 0xf;
+        match char::from_digit(digit, 16) {
+            Some(_) if keypad && digit < 10 => tap(steps, PAD[digit as usize]),
+            Some(c) => text(steps, layout, c),
+            None => false,
+        }
+    })
+}
+
+/// Queues `text`: ASCII through the host `layout`, other characters with
+/// the Unicode `method`. Returns false if some of it did not fit.
+pub fn type_str(sequence: &mut Sequence, method: Method, layout: HostLayout, text: &str) -> bool {
+    text.chars().fold(true, |ok, c| {
+        let mut steps = Steps::new();
+        let typed = match (c, layout.stroke(c)) {
+            ('\n', _) => tap(&mut steps, Enter) && sequence.push(&steps),
+            ('\t', _) => tap(&mut steps, Tab) && sequence.push(&steps),
+            (_, Some(stroke)) => type_stroke(&mut steps, stroke) && sequence.push(&steps),
+            (_, None) => type_char(sequence, method, layout, c),
+        };
+        ok && typed
+    })
+}
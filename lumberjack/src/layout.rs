@@ -1,10 +1,38 @@
 use keyberon::action::{k, l, Action, HoldTapAction, HoldTapConfig};
 use keyberon::key_code::{KeyCode, KeyCode::*};
 
+use keebs_common::autocorrect::{trie, trie_len};
+use keebs_common::compose::Output;
+use keebs_common::layers::stack;
+use keebs_common::os::{Os, Shortcut};
+use keebs_common::repeat::AltRepeat;
+use keebs_common::settings::MAX_TAPPING_TERM;
+use keebs_common::unicode::Method;
+use keyberon::layout::Layers;
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum CustomActions {
     Bootload,
     Reset,
+    TappingTermUp,
+    TappingTermDown,
+    DebounceUp,
+    DebounceDown,
+    ResetSettings,
+    LayerLock,
+    ToggleLayer(usize),
+    SetDefaultLayer(usize),
+    SetOs(Os),
+    DetectOs,
+    Shortcut(&'static Shortcut),
+    Unicode(char),
+    Compose,
+    ToggleAutocorrect,
+    Repeat,
+    AltRepeat,
+    SwapHands,
+    ToggleSwapHands,
+    Lock,
 }
 #[allow(dead_code)]
 const BOOTLOAD: Action<CustomActions> = Action::Custom(CustomActions::Bootload);
@@ -12,161 +40,293 @@ const BOOTLOAD: Action<CustomActions> = Action::Custom(CustomActions::Bootload);
 const RESET: Action<CustomActions> = Action::Custom(CustomActions::Reset);
 
 #[allow(dead_code)]
-const A_LS: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: k(LShift),
-    tap: k(A),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-#[allow(dead_code)]
-const L5_S: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: l(5),
-    tap: k(S),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-#[allow(dead_code)]
-const D_LA: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: k(LAlt),
-    tap: k(D),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-#[allow(dead_code)]
-const L2_F: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: l(2),
-    tap: k(F),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-#[allow(dead_code)]
-const DT_R: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: k(RAlt),
-    tap: k(Dot),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-#[allow(dead_code)]
-const X_LA: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: k(LAlt),
-    tap: k(X),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-#[allow(dead_code)]
-const SL_R: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: k(RCtrl),
-    tap: k(Slash),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-#[allow(dead_code)]
-const Z_LC: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: k(LCtrl),
-    tap: k(Z),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-#[allow(dead_code)]
-const L4_C: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: l(4),
-    tap: k(C),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-#[allow(dead_code)]
-const SM_R: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: k(RShift),
-    tap: k(SColon),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-#[allow(dead_code)]
-const L7_S: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: l(7),
-    tap: k(Space),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-#[allow(dead_code)]
-const L4_O: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: l(4),
-    tap: k(Comma),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-#[allow(dead_code)]
-const L3_D: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: l(3),
-    tap: k(D),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-#[allow(dead_code)]
-const L4_S: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: l(4),
-    tap: k(S),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-
-#[allow(dead_code)]
-const CT_T: Action<CustomActions> =
-    Action::MultipleKeyCodes(&[KeyCode::LCtrl, KeyCode::Tab].as_slice());
-#[allow(dead_code)]
-const SC_T: Action<CustomActions> =
-    Action::MultipleKeyCodes(&[KeyCode::LShift, KeyCode::LCtrl, KeyCode::Tab].as_slice());
-#[allow(dead_code)]
-const CA_D: Action<CustomActions> = Action::MultipleKeyCodes(&[LCtrl, LAlt, Delete].as_slice());
-
-pub static LAYERS: keyberon::layout::Layers<12, 5, 5, CustomActions> = keyberon::layout::layout! {
-    { // 0
+const TERM_UP: Action<CustomActions> = Action::Custom(CustomActions::TappingTermUp);
+#[allow(dead_code)]
+const TERM_DN: Action<CustomActions> = Action::Custom(CustomActions::TappingTermDown);
+#[allow(dead_code)]
+const DEB_UP: Action<CustomActions> = Action::Custom(CustomActions::DebounceUp);
+#[allow(dead_code)]
+const DEB_DN: Action<CustomActions> = Action::Custom(CustomActions::DebounceDown);
+#[allow(dead_code)]
+const DEFAULTS: Action<CustomActions> = Action::Custom(CustomActions::ResetSettings);
+#[allow(dead_code)]
+const LLOCK: Action<CustomActions> = Action::Custom(CustomActions::LayerLock);
+#[allow(dead_code)]
+const TG_NUM: Action<CustomActions> = Action::Custom(CustomActions::ToggleLayer(2));
+#[allow(dead_code)]
+const QWERTY: Action<CustomActions> = Action::Custom(CustomActions::SetDefaultLayer(0));
+#[allow(dead_code)]
+const COLEMAK: Action<CustomActions> = Action::Custom(CustomActions::SetDefaultLayer(1));
+#[allow(dead_code)]
+const DVORAK: Action<CustomActions> = Action::Custom(CustomActions::SetDefaultLayer(2));
+#[allow(dead_code)]
+const OS_LNX: Action<CustomActions> = Action::Custom(CustomActions::SetOs(Os::Linux));
+#[allow(dead_code)]
+const OS_WIN: Action<CustomActions> = Action::Custom(CustomActions::SetOs(Os::Windows));
+#[allow(dead_code)]
+const OS_MAC: Action<CustomActions> = Action::Custom(CustomActions::SetOs(Os::MacOs));
+#[allow(dead_code)]
+const OS_AUT: Action<CustomActions> = Action::Custom(CustomActions::DetectOs);
+
+#[allow(dead_code)]
+const U_ARR: Action<CustomActions> = Action::Custom(CustomActions::Unicode('→'));
+#[allow(dead_code)]
+const U_EUR: Action<CustomActions> = Action::Custom(CustomActions::Unicode('€'));
+#[allow(dead_code)]
+const U_LAM: Action<CustomActions> = Action::Custom(CustomActions::Unicode('λ'));
+#[allow(dead_code)]
+const U_NEQ: Action<CustomActions> = Action::Custom(CustomActions::Unicode('≠'));
+#[allow(dead_code)]
+const CMPS: Action<CustomActions> = Action::Custom(CustomActions::Compose);
+#[allow(dead_code)]
+const AC_TG: Action<CustomActions> = Action::Custom(CustomActions::ToggleAutocorrect);
+#[allow(dead_code)]
+const REP: Action<CustomActions> = Action::Custom(CustomActions::Repeat);
+#[allow(dead_code)]
+const AREP: Action<CustomActions> = Action::Custom(CustomActions::AltRepeat);
+#[allow(dead_code)]
+const SWAP: Action<CustomActions> = Action::Custom(CustomActions::SwapHands);
+#[allow(dead_code)]
+const SW_TG: Action<CustomActions> = Action::Custom(CustomActions::ToggleSwapHands);
+#[allow(dead_code)]
+const LOCK: Action<CustomActions> = Action::Custom(CustomActions::Lock);
+
+// Unicode input method used in Windows mode
+pub const WINDOWS_UNICODE: Method = Method::AltCodes;
+
+// Default tapping_term setting. The hold-taps are declared with the longest
+// one and shortened at runtime by `TapClock`
+pub const TAPPING_TERM: u16 = 200;
+
+// Hold-tap with this board's timeout and config, used for the mods and layer
+// keys of every base layer
+macro_rules! hold_tap {
+    ($hold:expr, $tap:expr) => {
+        Action::HoldTap(&HoldTapAction {
+            timeout: MAX_TAPPING_TERM,
+            hold: $hold,
+            tap: $tap,
+            config: HoldTapConfig::PermissiveHold,
+            tap_hold_interval: 0,
+        })
+    };
+}
+
+#[allow(dead_code)]
+const A_LS: Action<CustomActions> = hold_tap!(k(LShift), k(A));
+#[allow(dead_code)]
+const L5_S: Action<CustomActions> = hold_tap!(l(5), k(S));
+#[allow(dead_code)]
+const D_LA: Action<CustomActions> = hold_tap!(k(LAlt), k(D));
+#[allow(dead_code)]
+const L2_F: Action<CustomActions> = hold_tap!(l(2), k(F));
+#[allow(dead_code)]
+const DT_R: Action<CustomActions> = hold_tap!(k(RAlt), k(Dot));
+#[allow(dead_code)]
+const X_LA: Action<CustomActions> = hold_tap!(k(LAlt), k(X));
+#[allow(dead_code)]
+const SL_R: Action<CustomActions> = hold_tap!(k(RCtrl), k(Slash));
+#[allow(dead_code)]
+const Z_LC: Action<CustomActions> = hold_tap!(k(LCtrl), k(Z));
+#[allow(dead_code)]
+const L4_C: Action<CustomActions> = hold_tap!(l(4), k(C));
+#[allow(dead_code)]
+const SM_R: Action<CustomActions> = hold_tap!(k(RShift), k(SColon));
+#[allow(dead_code)]
+const L7_S: Action<CustomActions> = hold_tap!(l(7), k(Space));
+#[allow(dead_code)]
+const L4_O: Action<CustomActions> = hold_tap!(l(4), k(Comma));
+#[allow(dead_code)]
+const L3_D: Action<CustomActions> = hold_tap!(l(3), k(D));
+#[allow(dead_code)]
+const L4_S: Action<CustomActions> = hold_tap!(l(4), k(S));
+#[allow(dead_code)]
+const L3_S: Action<CustomActions> = hold_tap!(l(3), k(S));
+#[allow(dead_code)]
+const L2_T: Action<CustomActions> = hold_tap!(l(2), k(T));
+#[allow(dead_code)]
+const L3_E: Action<CustomActions> = hold_tap!(l(3), k(E));
+#[allow(dead_code)]
+const L2_U: Action<CustomActions> = hold_tap!(l(2), k(U));
+
+// Next and previous tab, Cmd+Shift+] and Cmd+Shift+[ on macOS
+#[allow(dead_code)]
+const CT_T: Action<CustomActions> = Action::Custom(CustomActions::Shortcut(&[
+    &[LCtrl, Tab],
+    &[LCtrl, Tab],
+    &[LGui, LShift, RBracket],
+]));
+#[allow(dead_code)]
+const SC_T: Action<CustomActions> = Action::Custom(CustomActions::Shortcut(&[
+    &[LShift, LCtrl, Tab],
+    &[LShift, LCtrl, Tab],
+    &[LGui, LShift, LBracket],
+]));
+// Ctrl+Alt+Delete, force quit on macOS
+#[allow(dead_code)]
+const CA_D: Action<CustomActions> = Action::Custom(CustomActions::Shortcut(&[
+    &[LCtrl, LAlt, Delete],
+    &[LCtrl, LAlt, Delete],
+    &[LGui, LAlt, Escape],
+]));
+
+// Row past the matrix holding layer n in column n, pressed by the firmware
+// to keep a layer active
+pub const VIRTUAL_ROW: u8 = 5;
+
+// Layer activated while both layers of the pair are active, here
+// symbols + navigation = function keys
+pub const CONDITIONAL_LAYERS: [([usize; 2], usize); 1] = [([3, 4], 1)];
+
+// Keys typed to unlock the keyboard after the lock key
+pub const UNLOCK: [KeyCode; 6] = [U, N, L, O, C, K];
+
+// Matrix columns swapped by the swap-hands keys
+pub const MIRROR: [(u8, u8); 6] = [(0, 11), (1, 10), (2, 9), (3, 8), (4, 7), (5, 6)];
+
+// Hold-taps pressed within this many ms of the previous key press are taps
+pub const FLOW_TAP_TERM: u16 = 120;
+// Per hold-tap overrides of FLOW_TAP_TERM, 0 never forces a tap
+pub const FLOW_TAP: [(Action<CustomActions>, u16); 7] = [
+    (L3_D, 150),
+    (L2_F, 150),
+    (L3_S, 150),
+    (L2_T, 150),
+    (L3_E, 150),
+    (L2_U, 150),
+    (L4_S, 0),
+];
+
+// Pairs of characters typed after the compose key, ASCII output is typed
+// through the host layout and the rest with Unicode input
+#[rustfmt::skip]
+pub const COMPOSE: [([char; 2], Output); 24] = [
+    (['a', '\''], Output::Char('á')), (['e', '\''], Output::Char('é')),
+    (['i', '\''], Output::Char('í')), (['o', '\''], Output::Char('ó')),
+    (['u', '\''], Output::Char('ú')), (['a', '`'], Output::Char('à')),
+    (['e', '`'], Output::Char('è')), (['a', '"'], Output::Char('ä')),
+    (['o', '"'], Output::Char('ö')), (['u', '"'], Output::Char('ü')),
+    (['s', 's'], Output::Char('ß')), (['c', ','], Output::Char('ç')),
+    (['n', '~'], Output::Char('ñ')), (['e', '='], Output::Char('€')),
+    (['o', 'c'], Output::Char('©')), (['+', '-'], Output::Char('±')),
+    (['-', '>'], Output::Char('→')), (['<', '-'], Output::Char('←')),
+    (['<', '='], Output::Char('≤')), (['>', '='], Output::Char('≥')),
+    (['=', '/'], Output::Char('≠')), (['.', '.'], Output::Char('…')),
+    (['m', 'm'], Output::Keys(&[Mute])), (['p', 'p'], Output::Keys(&[MediaPlayPause])),
+];
+
+// Typos corrected when the word ends, in lowercase ASCII letters
+#[rustfmt::skip]
+pub const TYPOS: [(&str, &str); 44] = [
+    ("teh", "the"), ("hte", "the"), ("taht", "that"), ("thier", "their"),
+    ("thsi", "this"), ("wiht", "with"), ("adn", "and"), ("jsut", "just"),
+    ("yuo", "you"), ("waht", "what"), ("wich", "which"), ("whihc", "which"),
+    ("becuase", "because"), ("recieve", "receive"), ("beleive", "believe"),
+    ("seperate", "separate"), ("definately", "definitely"), ("occured", "occurred"),
+    ("occurence", "occurrence"), ("untill", "until"), ("wierd", "weird"),
+    ("freind", "friend"), ("acheive", "achieve"), ("accomodate", "accommodate"),
+    ("adress", "address"), ("begining", "beginning"), ("calender", "calendar"),
+    ("comming", "coming"), ("enviroment", "environment"), ("existance", "existence"),
+    ("goverment", "government"), ("happend", "happened"), ("independant", "independent"),
+    ("knowlege", "knowledge"), ("neccessary", "necessary"), ("persistant", "persistent"),
+    ("posible", "possible"), ("realy", "really"), ("recomend", "recommend"),
+    ("refered", "referred"), ("succesful", "successful"), ("tommorow", "tomorrow"),
+    ("truely", "truly"), ("wether", "whether"),
+];
+// `TYPOS` compiled at build time
+pub static TYPO_TRIE: [u8; trie_len(&TYPOS)] = trie(&TYPOS);
+
+// Triggers typed before a space, replaced with their expansion
+pub const SNIPPETS: [(&str, &str); 4] = [
+    (";em", "cam@example.com"),
+    (";sig", "Best regards,\nCam"),
+    (";shrug", "¯\\_(ツ)_/¯"),
+    (";ty", "Thank you!"),
+];
+const _: () = keebs_common::snippets::check(&SNIPPETS);
+
+// Pairs of keys the alternate repeat key turns into each other, for a US
+// host layout
+pub const ALT_REPEAT: [AltRepeat; 10] = [
+    (&[LShift, Kb9], &[LShift, Kb0]),
+    (&[LBracket], &[RBracket]),
+    (&[LShift, LBracket], &[LShift, RBracket]),
+    (&[LCtrl, Z], &[LCtrl, Y]),
+    (&[Left], &[Right]),
+    (&[Up], &[Down]),
+    (&[Home], &[End]),
+    (&[PgUp], &[PgDown]),
+    (&[Tab], &[LShift, Tab]),
+    (&[LCtrl, Tab], &[LShift, LCtrl, Tab]),
+];
+
+// Base layers, picked by the default_layer setting
+pub const BASE_LAYERS: usize = 3;
+// Layers from a base layer to the next: itself, the 4 layers above it and
+// transparent layers up to the sum of two of them
+pub const BLOCK: usize = 9;
+pub const LAYER_COUNT: usize = BASE_LAYERS * BLOCK;
+
+pub static LAYERS: Layers<12, 6, LAYER_COUNT, CustomActions> = stack(&BASES, &ABOVE);
+
+const BASES: Layers<12, 6, BASE_LAYERS, CustomActions> = keyberon::layout::layout! {
+    { // 0: QWERTY
         [ '`'    1    2    3      4      5      6      7 8    9    0  -     ]
         [ Tab    Q    W    E      R      T      Y      U I    O    P  '\\'  ]
         [ Escape A    S    {L3_D} {L2_F} G      H      J K    L    ;  Quote ]
         [ LShift Z    X    C      V      B      N      M ,    .    /  Enter ]
         [ LCtrl  LGui LAlt (2)    (1)    BSpace {L4_S} A Left Down Up Right ]
+        [ t (1) (2) (3) (4) t t t t t t t ]
+    }
+    { // 1: Colemak-DH
+        [ '`'    1    2    3      4      5      6      7 8    9    0  -     ]
+        [ Tab    Q    W    F      P      B      J      L U    Y    ;  '\\'  ]
+        [ Escape A    R    {L3_S} {L2_T} G      M      N E    I    O  Quote ]
+        [ LShift Z    X    C      D      V      K      H ,    .    /  Enter ]
+        [ LCtrl  LGui LAlt (2)    (1)    BSpace {L4_S} A Left Down Up Right ]
+        [ t (1) (2) (3) (4) t t t t t t t ]
+    }
+    { // 2: Dvorak
+        [ '`'    1     2    3      4      5      6      7 8    9    0  '['   ]
+        [ Tab    Quote ,    .      P      Y      F      G C    R    L  '\\'  ]
+        [ Escape A     O    {L3_E} {L2_U} I      D      H T    N    S  -     ]
+        [ LShift ;     Q    J      K      X      B      M W    V    Z  Enter ]
+        [ LCtrl  LGui  LAlt (2)    (1)    BSpace {L4_S} A Left Down Up Right ]
+        [ t (1) (2) (3) (4) t t t t t t t ]
     }
+};
+
+const ABOVE: Layers<12, 6, 4, CustomActions> = keyberon::layout::layout! {
     { // 1
-        [ t F1 F2 F3 F4     F5 F6 F7 F8 F9 F10 t ]
-        [ t t  t  t  t      t  t  t  t  t  t   t ]
-        [ t t  t  t  {CA_D} t  t  t  t  t  t   t ]
-        [ t t  t  t  t      t  t  t  t  t  t   t ]
-        [ t t  t  t  t      t  t  t  t  t  t   t ]
+        [ t         F1        F2      F3       F4     F5 F6 F7 F8 F9 F10 t ]
+        [ t         t         t       t        t      t  t  t  t  t  t   t ]
+        [ t         t         t       t        {CA_D} t  t  t  t  t  t   t ]
+        [ {TG_NUM}  t         t       t        t      t  t  t  t  t  t   t ]
+        [ t         t         {LLOCK} t        t      t  t  t  t  t  t   t ]
+        [ t (1) (2) (3) (4) t t t t t t t ]
     }
     { // 2
-        [ {BOOTLOAD}  t t t t t t t t t t t ]
-        [ t           t t t t t t 7 8 9 t t ]
-        [ t           t t t t t t 4 5 6 t t ]
-        [ t           t t t t t t 1 2 3 . t ]
-        [ t           t t t t t 0 t t t t t ]
+        [ {BOOTLOAD} {TERM_DN} {TERM_UP} {DEB_DN} {DEB_UP} {DEFAULTS} t {QWERTY} {COLEMAK} {DVORAK} t t ]
+        [ t          {OS_LNX}  {OS_WIN}  {OS_MAC} {OS_AUT} t          t 7 8 9 t t ]
+        [ t          {AC_TG}   t         t        t        t          t 4 5 6 t t ]
+        [ t          {SW_TG}   {SWAP}    t        t        t          t 1 2 3 . t ]
+        [ t          {LOCK}    {LLOCK}   t        t        t          0 t t t t t ]
+        [ t (1) (2) (3) (4) t t t t t t t ]
     }
     { // 3
-        [ t t   t   t t t t   t   t    t   t     t ]
-        [ t '(' ')' t t t t   '_' |    =   +     t ]
-        [ t '{' '}' t t t '`' ~   /    '"' Quote t ]
-        [ t '[' ']' ^ & * t   -   '\\' t   t     t ]
-        [ t t   t   t t t t   t   t    t   t     t ]
+        [ t t   t       t       t       t      t   t   t    t       t       t ]
+        [ t '(' ')'     {U_ARR} {U_EUR} {CMPS} t   '_' |    =       +       t ]
+        [ t '{' '}'     t       t       t      '`' ~   /    '"'     Quote   t ]
+        [ t '[' ']'     ^       &       *      t   -   '\\' {U_NEQ} {U_LAM} t ]
+        [ t t   {LLOCK} t       t       t      t   t   t    t       t       t ]
+        [ t (1) (2) (3) (4) t t t t t t t ]
     }
     { // 4
-        [ t t t t      t t t    t    t      t     t       t ]
-        [ t t t t      t t t    t    PgUp   t     PScreen t ]
-        [ t t t t      t t Left Down Up     Right t       t ]
-        [ t t t t      t t t    Home PgDown End   t       t ]
-        [ t t t Delete t t t    t    t      t     t       t ]
+        [ t t t       t      t      t     t    t    t      t     t       t ]
+        [ t t t       t      t      t     t    t    PgUp   t     PScreen t ]
+        [ t t t       t      t      t     Left Down Up     Right t       t ]
+        [ t t t       t      t      t     t    Home PgDown End   t       t ]
+        [ t t {LLOCK} Delete {AREP} {REP} t    t    t      t     t       t ]
+        [ t (1) (2) (3) (4) t t t t t t t ]
     }
 };
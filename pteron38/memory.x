@@ -1,5 +1,9 @@
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 256K 
+  FLASH : ORIGIN = 0x08000000, LENGTH = 256K
   RAM : ORIGIN = 0x20000000, LENGTH = 64K
 }
+
+/* The vector table stays in sector 0 and the code starts after sector 1, the
+   16K sector at 0x08004000 that holds the settings */
+_stext = ORIGIN(FLASH) + 32K;
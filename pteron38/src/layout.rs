@@ -1,104 +1,146 @@
 use keyberon::action::{k, l, Action, HoldTapAction, HoldTapConfig};
 use keyberon::key_code::{KeyCode, KeyCode::*};
+use keyberon::layout::Layers;
+
+use keebs_common::autocorrect::{trie, trie_len};
+use keebs_common::compose::Output;
+use keebs_common::layers::stack;
+use keebs_common::os::{Os, Shortcut};
+use keebs_common::repeat::AltRepeat;
+use keebs_common::settings::MAX_TAPPING_TERM;
+use keebs_common::unicode::Method;
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum CustomActions {
     Bootload,
     Reset,
+    TappingTermUp,
+    TappingTermDown,
+    DebounceUp,
+    DebounceDown,
+    ResetSettings,
+    LayerLock,
+    ToggleLayer(usize),
+    SetDefaultLayer(usize),
+    SetOs(Os),
+    DetectOs,
+    Shortcut(&'static Shortcut),
+    Unicode(char),
+    Compose,
+    ToggleAutocorrect,
+    Repeat,
+    AltRepeat,
+    SwapHands,
+    ToggleSwapHands,
+    Lock,
 }
 const BOOTLOAD: Action<CustomActions> = Action::Custom(CustomActions::Bootload);
 const RESET: Action<CustomActions> = Action::Custom(CustomActions::Reset);
 
-const A_LS: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: k(LShift),
-    tap: k(A),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-const L5_S: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: l(5),
-    tap: k(S),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-const D_LA: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: k(LAlt),
-    tap: k(D),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-const L2_F: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: l(2),
-    tap: k(F),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-const DT_R: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: k(RAlt),
-    tap: k(Dot),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-const X_LA: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: k(LAlt),
-    tap: k(X),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-const SL_R: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: k(RCtrl),
-    tap: k(Slash),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-const Z_LC: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: k(LCtrl),
-    tap: k(Z),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-const L4_C: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: l(4),
-    tap: k(C),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-const SM_R: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: k(RShift),
-    tap: k(SColon),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-const L7_S: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: l(7),
-    tap: k(Space),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-const L4_O: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: l(4),
-    tap: k(Comma),
-    config: HoldTapConfig::PermissiveHold,
-    tap_hold_interval: 0,
-});
-
-const CT_T: Action<CustomActions> =
-    Action::MultipleKeyCodes(&[KeyCode::LCtrl, KeyCode::Tab].as_slice());
-const SC_T: Action<CustomActions> =
-    Action::MultipleKeyCodes(&[KeyCode::LShift, KeyCode::LCtrl, KeyCode::Tab].as_slice());
-const CA_D: Action<CustomActions> = Action::MultipleKeyCodes(&[LCtrl, LAlt, Delete].as_slice());
+const TERM_UP: Action<CustomActions> = Action::Custom(CustomActions::TappingTermUp);
+const TERM_DN: Action<CustomActions> = Action::Custom(CustomActions::TappingTermDown);
+const DEB_UP: Action<CustomActions> = Action::Custom(CustomActions::DebounceUp);
+const DEB_DN: Action<CustomActions> = Action::Custom(CustomActions::DebounceDown);
+const DEFAULTS: Action<CustomActions> = Action::Custom(CustomActions::ResetSettings);
+const LLOCK: Action<CustomActions> = Action::Custom(CustomActions::LayerLock);
+const TG_NUM: Action<CustomActions> = Action::Custom(CustomActions::ToggleLayer(3));
+const QWERTY: Action<CustomActions> = Action::Custom(CustomActions::SetDefaultLayer(0));
+const COLEMAK: Action<CustomActions> = Action::Custom(CustomActions::SetDefaultLayer(1));
+const DVORAK: Action<CustomActions> = Action::Custom(CustomActions::SetDefaultLayer(2));
+const OS_LNX: Action<CustomActions> = Action::Custom(CustomActions::SetOs(Os::Linux));
+const OS_WIN: Action<CustomActions> = Action::Custom(CustomActions::SetOs(Os::Windows));
+const OS_MAC: Action<CustomActions> = Action::Custom(CustomActions::SetOs(Os::MacOs));
+const OS_AUT: Action<CustomActions> = Action::Custom(CustomActions::DetectOs);
+
+const U_ARR: Action<CustomActions> = Action::Custom(CustomActions::Unicode('→'));
+const U_EUR: Action<CustomActions> = Action::Custom(CustomActions::Unicode('€'));
+const U_LAM: Action<CustomActions> = Action::Custom(CustomActions::Unicode('λ'));
+const U_NEQ: Action<CustomActions> = Action::Custom(CustomActions::Unicode('≠'));
+const CMPS: Action<CustomActions> = Action::Custom(CustomActions::Compose);
+const AC_TG: Action<CustomActions> = Action::Custom(CustomActions::ToggleAutocorrect);
+const REP: Action<CustomActions> = Action::Custom(CustomActions::Repeat);
+const AREP: Action<CustomActions> = Action::Custom(CustomActions::AltRepeat);
+const SWAP: Action<CustomActions> = Action::Custom(CustomActions::SwapHands);
+const SW_TG: Action<CustomActions> = Action::Custom(CustomActions::ToggleSwapHands);
+const LOCK: Action<CustomActions> = Action::Custom(CustomActions::Lock);
+
+// Unicode input method used in Windows mode
+pub const WINDOWS_UNICODE: Method = Method::WinCompose;
+
+// Default tapping_term setting. The hold-taps are declared with the longest
+// one and shortened at runtime by `TapClock`
+pub const TAPPING_TERM: u16 = 200;
+
+// Hold-tap with this board's timeout and config, used for the mods and layer
+// keys of every base layer
+macro_rules! hold_tap {
+    ($hold:expr, $tap:expr) => {
+        Action::HoldTap(&HoldTapAction {
+            timeout: MAX_TAPPING_TERM,
+            hold: $hold,
+            tap: $tap,
+            config: HoldTapConfig::PermissiveHold,
+            tap_hold_interval: 0,
+        })
+    };
+}
+
+const A_LS: Action<CustomActions> = hold_tap!(k(LShift), k(A));
+const L5_S: Action<CustomActions> = hold_tap!(l(5), k(S));
+const D_LA: Action<CustomActions> = hold_tap!(k(LAlt), k(D));
+const L2_F: Action<CustomActions> = hold_tap!(l(2), k(F));
+const DT_R: Action<CustomActions> = hold_tap!(k(RAlt), k(Dot));
+const X_LA: Action<CustomActions> = hold_tap!(k(LAlt), k(X));
+const SL_R: Action<CustomActions> = hold_tap!(k(RCtrl), k(Slash));
+const Z_LC: Action<CustomActions> = hold_tap!(k(LCtrl), k(Z));
+const L4_C: Action<CustomActions> = hold_tap!(l(4), k(C));
+const SM_R: Action<CustomActions> = hold_tap!(k(RShift), k(SColon));
+const L7_S: Action<CustomActions> = hold_tap!(l(7), k(Space));
+const L4_O: Action<CustomActions> = hold_tap!(l(4), k(Comma));
+
+// Colemak-DH home row
+const L5_R: Action<CustomActions> = hold_tap!(l(5), k(R));
+const S_LA: Action<CustomActions> = hold_tap!(k(LAlt), k(S));
+const L2_T: Action<CustomActions> = hold_tap!(l(2), k(T));
+const O_RS: Action<CustomActions> = hold_tap!(k(RShift), k(O));
+
+// Dvorak home and bottom rows
+const L5_O: Action<CustomActions> = hold_tap!(l(5), k(O));
+const E_LA: Action<CustomActions> = hold_tap!(k(LAlt), k(E));
+const L2_U: Action<CustomActions> = hold_tap!(l(2), k(U));
+const S_RS: Action<CustomActions> = hold_tap!(k(RShift), k(S));
+const SM_LC: Action<CustomActions> = hold_tap!(k(LCtrl), k(SColon));
+const Q_LA: Action<CustomActions> = hold_tap!(k(LAlt), k(Q));
+const L4_J: Action<CustomActions> = hold_tap!(l(4), k(J));
+const L4_W: Action<CustomActions> = hold_tap!(l(4), k(W));
+const V_RA: Action<CustomActions> = hold_tap!(k(RAlt), k(V));
+const Z_RC: Action<CustomActions> = hold_tap!(k(RCtrl), k(Z));
+
+// Next and previous tab, Cmd+Shift+] and Cmd+Shift+[ on macOS
+const CT_T: Action<CustomActions> = Action::Custom(CustomActions::Shortcut(&[
+    &[LCtrl, Tab],
+    &[LCtrl, Tab],
+    &[LGui, LShift, RBracket],
+]));
+const SC_T: Action<CustomActions> = Action::Custom(CustomActions::Shortcut(&[
+    &[LShift, LCtrl, Tab],
+    &[LShift, LCtrl, Tab],
+    &[LGui, LShift, LBracket],
+]));
+// Ctrl+Alt+Delete, force quit on macOS
+const CA_D: Action<CustomActions> = Action::Custom(CustomActions::Shortcut(&[
+    &[LCtrl, LAlt, Delete],
+    &[LCtrl, LAlt, Delete],
+    &[LGui, LAlt, Escape],
+]));
+
+// Row past the matrix holding layer n in column n, pressed by the firmware
+// to keep a layer active
+pub const VIRTUAL_ROW: u8 = 4;
+
+// Layer activated while both layers of the pair are active, here
+// symbols + navigation = function keys
+pub const CONDITIONAL_LAYERS: [([usize; 2], usize); 1] = [([4, 5], 6)];
 
 pub const CHORDS: [keyberon::chording::ChordDef; 3] = [
     ((3, 4), &[(0, 6), (1, 6)]),
@@ -106,53 +148,162 @@ pub const CHORDS: [keyberon::chording::ChordDef; 3] = [
     ((3, 5), &[(0, 8), (1, 8)]),
 ];
 
-pub static LAYERS: keyberon::layout::Layers<10, 4, 8, CustomActions> = keyberon::layout::layout! {
-    { // 0
+// Keys typed to unlock the keyboard after the lock key
+pub const UNLOCK: [KeyCode; 6] = [U, N, L, O, C, K];
+
+// Matrix columns swapped by the swap-hands keys
+pub const MIRROR: [(u8, u8); 5] = [(0, 9), (1, 8), (2, 7), (3, 6), (4, 5)];
+
+// Hold-taps pressed within this many ms of the previous key press are taps
+pub const FLOW_TAP_TERM: u16 = 120;
+// Per hold-tap overrides of FLOW_TAP_TERM, 0 never forces a tap
+pub const FLOW_TAP: [(Action<CustomActions>, u16); 4] =
+    [(A_LS, 150), (Z_LC, 150), (SM_LC, 150), (L7_S, 0)];
+
+// Pairs of characters typed after the compose key, ASCII output is typed
+// through the host layout and the rest with Unicode input
+#[rustfmt::skip]
+pub const COMPOSE: [([char; 2], Output); 24] = [
+    (['a', '\''], Output::Char('á')), (['e', '\''], Output::Char('é')),
+    (['i', '\''], Output::Char('í')), (['o', '\''], Output::Char('ó')),
+    (['u', '\''], Output::Char('ú')), (['a', '`'], Output::Char('à')),
+    (['e', '`'], Output::Char('è')), (['a', '"'], Output::Char('ä')),
+    (['o', '"'], Output::Char('ö')), (['u', '"'], Output::Char('ü')),
+    (['s', 's'], Output::Char('ß')), (['c', ','], Output::Char('ç')),
+    (['n', '~'], Output::Char('ñ')), (['e', '='], Output::Char('€')),
+    (['o', 'c'], Output::Char('©')), (['+', '-'], Output::Char('±')),
+    (['-', '>'], Output::Char('→')), (['<', '-'], Output::Char('←')),
+    (['<', '='], Output::Char('≤')), (['>', '='], Output::Char('≥')),
+    (['=', '/'], Output::Char('≠')), (['.', '.'], Output::Char('…')),
+    (['m', 'm'], Output::Keys(&[Mute])), (['p', 'p'], Output::Keys(&[MediaPlayPause])),
+];
+
+// Typos corrected when the word ends, in lowercase ASCII letters
+#[rustfmt::skip]
+pub const TYPOS: [(&str, &str); 44] = [
+    ("teh", "the"), ("hte", "the"), ("taht", "that"), ("thier", "their"),
+    ("thsi", "this"), ("wiht", "with"), ("adn", "and"), ("jsut", "just"),
+    ("yuo", "you"), ("waht", "what"), ("wich", "which"), ("whihc", "which"),
+    ("becuase", "because"), ("recieve", "receive"), ("beleive", "believe"),
+    ("seperate", "separate"), ("definately", "definitely"), ("occured", "occurred"),
+    ("occurence", "occurrence"), ("untill", "until"), ("wierd", "weird"),
+    ("freind", "friend"), ("acheive", "achieve"), ("accomodate", "accommodate"),
+    ("adress", "address"), ("begining", "beginning"), ("calender", "calendar"),
+    ("comming", "coming"), ("enviroment", "environment"), ("existance", "existence"),
+    ("goverment", "government"), ("happend", "happened"), ("independant", "independent"),
+    ("knowlege", "knowledge"), ("neccessary", "necessary"), ("persistant", "persistent"),
+    ("posible", "possible"), ("realy", "really"), ("recomend", "recommend"),
+    ("refered", "referred"), ("succesful", "successful"), ("tommorow", "tomorrow"),
+    ("truely", "truly"), ("wether", "whether"),
+];
+// `TYPOS` compiled at build time
+pub static TYPO_TRIE: [u8; trie_len(&TYPOS)] = trie(&TYPOS);
+
+// Triggers typed before a space, replaced with their expansion
+pub const SNIPPETS: [(&str, &str); 4] = [
+    (";em", "cam@example.com"),
+    (";sig", "Best regards,\nCam"),
+    (";shrug", "¯\\_(ツ)_/¯"),
+    (";ty", "Thank you!"),
+];
+const _: () = keebs_common::snippets::check(&SNIPPETS);
+
+// Pairs of keys the alternate repeat key turns into each other, for a US
+// host layout
+pub const ALT_REPEAT: [AltRepeat; 10] = [
+    (&[LShift, Kb9], &[LShift, Kb0]),
+    (&[LBracket], &[RBracket]),
+    (&[LShift, LBracket], &[LShift, RBracket]),
+    (&[LCtrl, Z], &[LCtrl, Y]),
+    (&[Left], &[Right]),
+    (&[Up], &[Down]),
+    (&[Home], &[End]),
+    (&[PgUp], &[PgDown]),
+    (&[Tab], &[LShift, Tab]),
+    (&[LCtrl, Tab], &[LShift, LCtrl, Tab]),
+];
+
+// Base layers, picked by the default_layer setting
+pub const BASE_LAYERS: usize = 3;
+// Layers from a base layer to the next: itself, the 7 layers above it and
+// transparent layers up to the sum of two of them
+pub const BLOCK: usize = 15;
+pub const LAYER_COUNT: usize = BASE_LAYERS * BLOCK;
+
+pub static LAYERS: Layers<10, 5, LAYER_COUNT, CustomActions> = stack(&BASES, &ABOVE);
+
+const BASES: Layers<10, 5, BASE_LAYERS, CustomActions> = keyberon::layout::layout! {
+    { // 0: QWERTY
         [Q          W      E      R      T Y U      I      O      P       ]
         [{A_LS}     {L5_S} {D_LA} {L2_F} G H J      K      L      {SM_R}  ]
         [{Z_LC}     {X_LA} {L4_C} V      B N M      {L4_O} {DT_R} {SL_R}  ]
         [LGui {SC_T} BSpace {CT_T} Escape Enter {CA_D} {L7_S} LAlt Tab ]
+        [ t (1) (2) (3) (4) (5) (6) (7) t t ]
+    }
+    { // 1: Colemak-DH
+        [Q          W      F      P      B J L      U      Y      ;       ]
+        [{A_LS}     {L5_R} {S_LA} {L2_T} G M N      E      I      {O_RS}  ]
+        [{Z_LC}     {X_LA} {L4_C} D      V K H      {L4_O} {DT_R} {SL_R}  ]
+        [LGui {SC_T} BSpace {CT_T} Escape Enter {CA_D} {L7_S} LAlt Tab ]
+        [ t (1) (2) (3) (4) (5) (6) (7) t t ]
     }
+    { // 2: Dvorak
+        [Quote      ,      .      P      Y F G      C      R      L       ]
+        [{A_LS}     {L5_O} {E_LA} {L2_U} I D H      T      N      {S_RS}  ]
+        [{SM_LC}    {Q_LA} {L4_J} K      X B M      {L4_W} {V_RA} {Z_RC}  ]
+        [LGui {SC_T} BSpace {CT_T} Escape Enter {CA_D} {L7_S} LAlt Tab ]
+        [ t (1) (2) (3) (4) (5) (6) (7) t t ]
+    }
+};
+
+const ABOVE: Layers<10, 5, 7, CustomActions> = keyberon::layout::layout! {
     { // 1
         [ t t t t t t t t t t  ]
         [ t t t t t t t t t t  ]
         [ t t t t t t t t t t  ]
         [ t t t t t t t t t t  ]
+        [ t (1) (2) (3) (4) (5) (6) (7) t t ]
     }
     { // 2
-        [ t t t   t t * 7 8 9 + ]
-        [ t t t   t t / 4 5 6 - ]
-        [ t t (6) t t t 1 2 3 . ]
-        [ t t t   t t t t 0 t t ]
+        [ t t       t   t t * 7 8 9 + ]
+        [ t t       t   t t / 4 5 6 - ]
+        [ t t       (6) t t t 1 2 3 . ]
+        [ t {LLOCK} t   t t t t 0 t t ]
+        [ t (1) (2) (3) (4) (5) (6) (7) t t ]
     }
     { // 3
-        [  * 7 8 9 + t t t t t  ]
-        [  / 4 5 6 - t t t t t  ]
-        [  t 1 2 3 . t t t t t  ]
-        [  t t 0 t t t t t t t  ]
+        [  * 7       8 9 + t t t t t  ]
+        [  / 4       5 6 - t t t t t  ]
+        [  t 1       2 3 . t t t t t  ]
+        [  t {LLOCK} 0 t t t t t t t  ]
+        [ t (1) (2) (3) (4) (5) (6) (7) t t ]
     }
     { // 4
-        [ !   @   #   $   % t ~   |    '`' +     ]
-        [ '{' '}' '(' ')' t = '_' -    '"' Quote ]
-        [ '[' ']' ^   &   * t /   '\\' t   t     ]
-        [ t   t   t   t   t t t   t    t   t     ]
+        [ !   @       #   $   %       {U_EUR} ~   |    '`'     +       ]
+        [ '{' '}'     '(' ')' {U_ARR} =       '_' -    '"'     Quote   ]
+        [ '[' ']'     ^   &   *       {CMPS}  /   '\\' {U_NEQ} {U_LAM} ]
+        [ t   {LLOCK} t   t   {REP}   {AREP}  t   t    t       t       ]
+        [ t (1) (2) (3) (4) (5) (6) (7) t t ]
     }
     { // 5
-        [  t t t t t t    t    PgUp t     t      ]
-        [  t t t t t Left Down Up   Right Enter  ]
-        [  t t t t t t    Home Down End   t      ]
-        [  t t t t t t    t    t    t     t      ]
+        [  t t       t t t t    t    PgUp t     t      ]
+        [  t t       t t t Left Down Up   Right Enter  ]
+        [  t t       t t t t    Home Down End   t      ]
+        [  t {LLOCK} t t t t    t    t    t     t      ]
+        [ t (1) (2) (3) (4) (5) (6) (7) t t ]
     }
     { // 6
-        [ {RESET} {BOOTLOAD} t t t t F7 F8 F9 MediaSleep ]
-        [ t       t          t t t t F4 F5 F6 t          ]
-        [ t       t          t t t t F1 F2 F3 t          ]
-        [ t       t          t t t t t  t  t  t          ]
+        [ {RESET}    {BOOTLOAD} {QWERTY} {COLEMAK} {DVORAK} t        F7 F8 F9 MediaSleep ]
+        [ {TERM_DN}  {TERM_UP}  {DEB_DN} {DEB_UP}  {AC_TG}  {SW_TG}  F4 F5 F6 {LOCK}     ]
+        [ {DEFAULTS} {TG_NUM}   {OS_LNX} {OS_WIN}  {OS_MAC} {OS_AUT} F1 F2 F3 t          ]
+        [ t          {LLOCK}    {SWAP}   t         t        t        t  t  t  t          ]
+        [ t (1) (2) (3) (4) (5) (6) (7) t t ]
     }
     { // 7
-        [ t t t      t t MediaNextSong MediaPlayPause MediaVolDown MediaVolUp PScreen ]
-        [ t t t      t t t             Escape         Tab          Enter      t       ]
-        [ t t t      t t t             Home           PgDown       PgUp       End     ]
-        [ t t Delete t t t             t              t            t          t       ]
+        [ t t       t      t t MediaNextSong MediaPlayPause MediaVolDown MediaVolUp PScreen ]
+        [ t t       t      t t t             Escape         Tab          Enter      t       ]
+        [ t t       t      t t t             Home           PgDown       PgUp       End     ]
+        [ t {LLOCK} Delete t t t             t              t            t          t       ]
+        [ t (1) (2) (3) (4) (5) (6) (7) t t ]
     }
 };
@@ -5,6 +5,8 @@ use panic_halt as _;
 use stm32f4xx_hal as hal;
 
 mod layout;
+mod probe;
+mod storage;
 
 #[rtic::app(device = crate::hal::pac, peripherals = true)]
 mod app {
@@ -12,47 +14,174 @@ mod app {
     use hal::otg_fs::{UsbBusType, USB};
     use hal::prelude::*;
     use hal::{pac, timer};
-    use keyberon::debounce::Debouncer;
     use keyberon::key_code::KbHidReport;
     use keyberon::layout;
-    use keyberon::matrix::Matrix;
     use stm32f4xx_hal as hal;
     use usb_device::bus::UsbBusAllocator;
     use usb_device::class::UsbClass as _;
+    use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+    use usbd_serial::SerialPort;
 
-    use crate::layout::CustomActions;
+    use keebs_common::autocorrect::Autocorrect;
+    use keebs_common::compose::Compose;
+    use keebs_common::debounce::{Debouncer, DeferredPerKey};
+    use keebs_common::flow_tap::FlowTap;
+    use keebs_common::host::Host;
+    use keebs_common::keymap::HostLayout;
+    use keebs_common::layers::VirtualLayers;
+    use keebs_common::lock::Lock;
+    use keebs_common::matrix::{Col2Row, Matrix};
+    use keebs_common::os::{self, Os, Shortcuts};
+    use keebs_common::os_detect::OsDetect;
+    use keebs_common::repeat::Repeat;
+    use keebs_common::self_test::SelfTest;
+    use keebs_common::sequence::Sequence;
+    use keebs_common::settings::{Settings, TapClock};
+    use keebs_common::snippets::Snippets;
+    use keebs_common::swap_hands::SwapHands;
+    use keebs_common::typing::{self, Backlog, Typing};
+    use keebs_common::unicode::{self, Method};
+
+    use crate::layout::{CustomActions, BASE_LAYERS, BLOCK, LAYER_COUNT};
+    use crate::probe::ProbePin;
+    use crate::storage::Storage;
 
     type UsbClass = keyberon::Class<'static, UsbBusType, Leds>;
     type UsbDevice = usb_device::device::UsbDevice<'static, UsbBusType>;
     static mut USB_BUS: Option<UsbBusAllocator<UsbBusType>> = None;
 
+    const DEFAULT_SETTINGS: Settings = Settings {
+        tapping_term: crate::layout::TAPPING_TERM,
+        debounce: 15,
+        default_layer: 0,
+        os: os::AUTO,
+        host_layout: 0,
+    };
+    // Cycles waited after driving a matrix pin, 1 µs at 84 MHz
+    const MATRIX_SETTLE: u32 = 84;
+    // Period of the PC13 blinks showing the OS mode
+    const OS_BLINK_MS: u16 = 400;
+    // Period of the PC13 blinks while the keyboard is locked
+    const LOCK_BLINK_MS: u16 = 1000;
+    // Times the self-test fault code is blinked
+    const FAULT_REPEAT: u16 = 3;
+
     pub struct Leds {
         caps_lock: gpio::gpioc::PC13<gpio::Output<gpio::PushPull>>,
+        caps: bool,
+        // ms left showing the OS mode instead of caps lock
+        os_blinks: u16,
+        // ms into the blink period, while the keyboard is locked
+        lock_blink: Option<u16>,
+        // blinks of the self-test fault code, and ms left showing it
+        fault: u16,
+        fault_blinks: u16,
     }
-    impl keyberon::keyboard::Leds for Leds {
-        fn caps_lock(&mut self, status: bool) {
-            if status {
+    impl Leds {
+        // Blinks once for Linux, twice for Windows, three times for macOS
+        fn show_os(&mut self, os: Os) {
+            self.os_blinks = (os.setting() + 1) * OS_BLINK_MS;
+        }
+
+        // Blinks the fault code, each time followed by a pause of two blinks
+        fn show_fault(&mut self, blinks: u16) {
+            self.fault = blinks;
+            self.fault_blinks = if blinks == 0 {
+                0
+            } else {
+                FAULT_REPEAT * (blinks + 2) * OS_BLINK_MS
+            };
+        }
+
+        fn show_lock(&mut self, locked: bool) {
+            if locked == self.lock_blink.is_some() {
+                return;
+            }
+            self.lock_blink = if locked { Some(0) } else { None };
+            self.set(self.caps);
+        }
+
+        fn tick(&mut self) {
+            if let Some(ms) = self.lock_blink {
+                self.lock_blink = Some((ms + 1) % LOCK_BLINK_MS);
+                self.set(ms < LOCK_BLINK_MS / 2);
+                return;
+            }
+            if self.fault_blinks > 0 {
+                self.fault_blinks -= 1;
+                let ms = self.fault_blinks % ((self.fault + 2) * OS_BLINK_MS);
+                let on = if self.fault_blinks == 0 {
+                    self.caps
+                } else {
+                    ms >= 2 * OS_BLINK_MS && ms % OS_BLINK_MS >= OS_BLINK_MS / 2
+                };
+                self.set(on);
+                return;
+            }
+            if self.os_blinks == 0 {
+                return;
+            }
+            self.os_blinks -= 1;
+            let on = if self.os_blinks == 0 {
+                self.caps
+            } else {
+                self.os_blinks % OS_BLINK_MS >= OS_BLINK_MS / 2
+            };
+            self.set(on);
+        }
+
+        fn set(&mut self, on: bool) {
+            if on {
                 self.caps_lock.set_low()
             } else {
                 self.caps_lock.set_high()
             }
         }
     }
+    impl keyberon::keyboard::Leds for Leds {
+        fn caps_lock(&mut self, status: bool) {
+            self.caps = status;
+            if self.os_blinks == 0 && self.fault_blinks == 0 && self.lock_blink.is_none() {
+                self.set(status);
+            }
+        }
+    }
 
     #[shared]
     struct Shared {
         usb_dev: UsbDevice,
         usb_class: UsbClass,
+        usb_serial: SerialPort<'static, UsbBusType>,
+        os_detect: OsDetect,
     }
 
     #[local]
     struct Local {
-        matrix: Matrix<ErasedPin<Input<PullUp>>, ErasedPin<Output<PushPull>>, 10, 4>,
-        debouncer: Debouncer<[[bool; 10]; 4]>,
+        matrix: Matrix<ErasedPin<Input<PullUp>>, ErasedPin<Output<PushPull>>, Col2Row, 10, 4>,
+        self_test: SelfTest<10, 4>,
+        debouncer: Debouncer<DeferredPerKey<10, 4>, 10, 4>,
         chording: keyberon::chording::Chording<3_usize>,
         timer: timer::CountDownTimer<pac::TIM3>,
         watchdog: hal::watchdog::IndependentWatchdog,
-        layout: layout::Layout<10, 4, 8, CustomActions>,
+        settings: Settings,
+        applied: Settings,
+        storage: Storage,
+        host: Host,
+        tap_clock: TapClock,
+        shortcuts: Shortcuts,
+        sequence: Sequence,
+        typing: Typing,
+        backlog: Backlog,
+        compose: Compose,
+        snippets: Snippets,
+        autocorrect: Autocorrect,
+        repeat: Repeat,
+        swap_hands: SwapHands,
+        lock: Lock,
+        host_os: Os,
+        virtual_layers: VirtualLayers<10, 5, LAYER_COUNT, CustomActions>,
+        flow_tap: FlowTap<10, 5, LAYER_COUNT, CustomActions>,
+        layout: layout::Layout<10, 5, LAYER_COUNT, CustomActions>,
     }
 
     #[init]
@@ -72,7 +201,14 @@ mod app {
 
         let mut led = gpioc.pc13.into_push_pull_output();
         led.set_low();
-        let leds = Leds { caps_lock: led };
+        let leds = Leds {
+            caps_lock: led,
+            caps: false,
+            os_blinks: 0,
+            lock_blink: None,
+            fault: 0,
+            fault_blinks: 0,
+        };
 
         let usb = USB {
             usb_global: c.device.OTG_FS_GLOBAL,
@@ -86,24 +222,53 @@ mod app {
         unsafe {
             USB_BUS = Some(UsbBusType::new(usb, &mut EP_MEMORY));
         }
-        let usb_class = keyberon::new_class(unsafe { USB_BUS.as_ref().unwrap() }, leds);
-        let usb_dev = keyberon::new_device(unsafe { USB_BUS.as_ref().unwrap() });
+        let mut usb_class = keyberon::new_class(unsafe { USB_BUS.as_ref().unwrap() }, leds);
+        let usb_serial = SerialPort::new(unsafe { USB_BUS.as_ref().unwrap() });
+        let usb_dev = UsbDeviceBuilder::new(
+            unsafe { USB_BUS.as_ref().unwrap() },
+            UsbVidPid(0x16c0, 0x27db),
+        )
+        .manufacturer("RIIR Task Force")
+        .product("Keyberon")
+        .serial_number(env!("CARGO_PKG_VERSION"))
+        .composite_with_iads()
+        .build();
 
         let mut timer = timer::Timer::new(c.device.TIM3, &clocks).start_count_down(1000.hz());
         timer.listen(timer::Event::TimeOut);
 
-        let matrix = Matrix::new(
+        // The bridges are looked for before the HAL configures the pins
+        let mut cols = [
+            ProbePin::new('A', 6),
+            ProbePin::new('A', 7),
+            ProbePin::new('B', 0),
+            ProbePin::new('B', 1),
+            ProbePin::new('A', 2),
+            ProbePin::new('B', 14),
+            ProbePin::new('B', 15),
+            ProbePin::new('A', 5),
+            ProbePin::new('A', 4),
+            ProbePin::new('A', 3),
+        ];
+        let mut rows = [
+            ProbePin::new('B', 10),
+            ProbePin::new('A', 1),
+            ProbePin::new('A', 0),
+            ProbePin::new('B', 9),
+        ];
+        let mut self_test = SelfTest::bridges(&mut rows, &mut cols, MATRIX_SETTLE);
+        let mut matrix = Matrix::col2row(
             [
-                gpioa.pa6.into_pull_up_input().erase(),
-                gpioa.pa7.into_pull_up_input().erase(),
-                gpiob.pb0.into_pull_up_input().erase(),
-                gpiob.pb1.into_pull_up_input().erase(),
-                gpioa.pa2.into_pull_up_input().erase(),
-                gpiob.pb14.into_pull_up_input().erase(),
-                gpiob.pb15.into_pull_up_input().erase(),
-                gpioa.pa5.into_pull_up_input().erase(),
-                gpioa.pa4.into_pull_up_input().erase(),
-                gpioa.pa3.into_pull_up_input().erase(),
+                Some(gpioa.pa6.into_pull_up_input().erase()),
+                Some(gpioa.pa7.into_pull_up_input().erase()),
+                Some(gpiob.pb0.into_pull_up_input().erase()),
+                Some(gpiob.pb1.into_pull_up_input().erase()),
+                Some(gpioa.pa2.into_pull_up_input().erase()),
+                Some(gpiob.pb14.into_pull_up_input().erase()),
+                Some(gpiob.pb15.into_pull_up_input().erase()),
+                Some(gpioa.pa5.into_pull_up_input().erase()),
+                Some(gpioa.pa4.into_pull_up_input().erase()),
+                Some(gpioa.pa3.into_pull_up_input().erase()),
             ],
             [
                 gpiob.pb10.into_push_pull_output().erase(),
@@ -111,69 +276,275 @@ mod app {
                 gpioa.pa0.into_push_pull_output().erase(),
                 gpiob.pb9.into_push_pull_output().erase(),
             ],
-        );
+            MATRIX_SETTLE,
+        )
+        .unwrap();
+        self_test.run(|| matrix.get().unwrap());
+        usb_class
+            .device_mut()
+            .leds_mut()
+            .show_fault(self_test.blinks());
+
+        let storage = Storage::new(c.device.FLASH, DEFAULT_SETTINGS);
+        let mut settings = storage.load();
+        if settings.default_layer as usize >= BASE_LAYERS {
+            settings.default_layer = 0;
+        }
+        let mut layout = keyberon::layout::Layout::new(&crate::layout::LAYERS);
+        layout.set_default_layer(settings.default_layer as usize * BLOCK);
 
         let mut watchdog = hal::watchdog::IndependentWatchdog::new(c.device.IWDG);
         watchdog.start(hal::time::MilliSeconds(10));
 
         (
-            Shared { usb_dev, usb_class },
+            Shared {
+                usb_dev,
+                usb_class,
+                usb_serial,
+                os_detect: OsDetect::default(),
+            },
             Local {
                 timer,
                 watchdog,
-                debouncer: Debouncer::new([[false; 10]; 4], [[false; 10]; 4], 15),
+                settings,
+                applied: settings,
+                storage,
+                host: Host::new(DEFAULT_SETTINGS),
+                tap_clock: TapClock::default(),
+                virtual_layers: VirtualLayers::new(
+                    &crate::layout::LAYERS,
+                    &crate::layout::CONDITIONAL_LAYERS,
+                    crate::layout::VIRTUAL_ROW,
+                    BLOCK,
+                ),
+                shortcuts: Shortcuts::default(),
+                sequence: Sequence::default(),
+                typing: Typing::default(),
+                backlog: Backlog::default(),
+                compose: Compose::new(&crate::layout::COMPOSE),
+                snippets: Snippets::new(&crate::layout::SNIPPETS),
+                autocorrect: Autocorrect::new(&crate::layout::TYPOS, &crate::layout::TYPO_TRIE),
+                repeat: Repeat::default(),
+                swap_hands: SwapHands::new(&crate::layout::MIRROR),
+                lock: Lock::new(&crate::layout::UNLOCK),
+                host_os: Os::from_setting(settings.os, None),
+                debouncer: Debouncer::new(DeferredPerKey::new(settings.debounce)),
                 chording: keyberon::chording::Chording::new(&crate::layout::CHORDS),
-                matrix: matrix.unwrap(),
-                layout: keyberon::layout::Layout::new(&crate::layout::LAYERS),
+                matrix,
+                self_test,
+                flow_tap: FlowTap::new(
+                    &crate::layout::LAYERS,
+                    &crate::layout::FLOW_TAP,
+                    crate::layout::FLOW_TAP_TERM,
+                ),
+                layout,
             },
             init::Monotonics(),
         )
     }
 
-    #[task(binds = OTG_FS, priority = 2, shared = [usb_dev, usb_class])]
+    #[task(
+        binds = OTG_FS,
+        priority = 2,
+        shared = [usb_dev, usb_class, usb_serial, os_detect],
+    )]
     fn usb_tx(c: usb_tx::Context) {
-        (c.shared.usb_dev, c.shared.usb_class).lock(|u, k| {
-            if u.poll(&mut [k]) {
-                k.poll();
-            }
-        });
+        let shared = c.shared;
+        (
+            shared.usb_dev,
+            shared.usb_class,
+            shared.usb_serial,
+            shared.os_detect,
+        )
+            .lock(|u, k, s, d| {
+                if u.poll(&mut [k, s, d]) {
+                    k.poll();
+                }
+            });
     }
 
-    #[task(binds = OTG_FS_WKUP, priority = 2, shared = [usb_dev, usb_class])]
+    #[task(
+        binds = OTG_FS_WKUP,
+        priority = 2,
+        shared = [usb_dev, usb_class, usb_serial, os_detect],
+    )]
     fn usb_rx(c: usb_rx::Context) {
-        (c.shared.usb_dev, c.shared.usb_class).lock(|u, k| {
-            if u.poll(&mut [k]) {
-                k.poll();
-            }
-        });
+        let shared = c.shared;
+        (
+            shared.usb_dev,
+            shared.usb_class,
+            shared.usb_serial,
+            shared.os_detect,
+        )
+            .lock(|u, k, s, d| {
+                if u.poll(&mut [k, s, d]) {
+                    k.poll();
+                }
+            });
     }
 
-    #[task(binds = TIM3, priority = 1, shared = [usb_class], local = [layout, matrix, debouncer, chording, timer, watchdog])]
+    #[task(
+        binds = TIM3,
+        priority = 1,
+        shared = [usb_class, usb_serial, os_detect],
+        local = [
+            layout, matrix, self_test, debouncer, chording, timer, watchdog, flow_tap,
+            settings, applied, storage, host, tap_clock, virtual_layers, shortcuts,
+            host_os, sequence, typing, backlog, compose,
+            snippets, autocorrect, repeat, swap_hands, lock,
+        ],
+    )]
     fn tick(c: tick::Context) {
         c.local.timer.clear_interrupt(timer::Event::TimeOut);
         c.local.watchdog.feed();
 
+        let settings = c.local.settings;
+        let host = c.local.host;
+        let mut usb_serial = c.shared.usb_serial;
+        let chatter = c.local.debouncer.chatter();
+        let faults = c.local.self_test.faults();
+        usb_serial.lock(|s| host.poll(s, settings, chatter, faults));
+        if settings.default_layer != c.local.applied.default_layer {
+            if (settings.default_layer as usize) < BASE_LAYERS {
+                c.local
+                    .layout
+                    .set_default_layer(settings.default_layer as usize * BLOCK);
+                c.local.virtual_layers.clear();
+            } else {
+                settings.default_layer = c.local.applied.default_layer;
+            }
+        }
+        if settings.debounce != c.local.applied.debounce {
+            c.local.debouncer.set_time(settings.debounce);
+        }
+        let mut os_detect = c.shared.os_detect;
+        let os = Os::from_setting(settings.os, os_detect.lock(|d| d.guess()));
+        let method = Method::for_os(os, crate::layout::WINDOWS_UNICODE);
+        let host_layout = HostLayout::from_setting(settings.host_layout);
+        let mut usb_class = c.shared.usb_class;
+        if os != *c.local.host_os {
+            *c.local.host_os = os;
+            usb_class.lock(|k| k.device_mut().leds_mut().show_os(os));
+        }
+        *c.local.applied = *settings;
+
+        let swap_hands = &mut *c.local.swap_hands;
         for event in c.local.chording.tick(
             c.local
                 .debouncer
-                .events(c.local.matrix.get().unwrap())
+                .events(c.local.self_test.filter(c.local.matrix.get().unwrap()))
+                .map(|event| swap_hands.event(event))
                 .collect(),
         ) {
-            c.local.layout.event(event);
+            let layer = c.local.layout.current_layer();
+            let default = settings.default_layer as usize * BLOCK;
+            c.local.virtual_layers.event(event, layer, default);
+            if let Some(event) = c.local.flow_tap.event(event, layer, default) {
+                c.local.layout.event(event);
+            }
         }
-        match c.local.layout.tick() {
-            layout::CustomEvent::Press(event) => match event {
-                CustomActions::Bootload => unsafe { cortex_m::asm::bootload(0x1FFF0000 as _) },
-                CustomActions::Reset => {
-                    cortex_m::peripheral::SCB::sys_reset();
+        c.local.flow_tap.tick();
+        let holding = c.local.flow_tap.is_holding();
+        let mut ticks = c.local.tap_clock.ticks(holding, settings.tapping_term);
+        while ticks.next(c.local.layout.keycodes()) {
+            let custom = c.local.layout.tick();
+            if custom != layout::CustomEvent::NoEvent {
+                ticks.stop();
+            }
+            match custom {
+                // Only the unlock sequence is followed while locked
+                layout::CustomEvent::Press(_) if c.local.lock.is_locked() => (),
+                layout::CustomEvent::Press(event) => match event {
+                    CustomActions::Bootload => unsafe { cortex_m::asm::bootload(0x1FFF0000 as _) },
+                    CustomActions::Reset => {
+                        cortex_m::peripheral::SCB::sys_reset();
+                    }
+                    CustomActions::TappingTermUp => settings.step_tapping_term(true),
+                    CustomActions::TappingTermDown => settings.step_tapping_term(false),
+                    CustomActions::DebounceUp => settings.step_debounce(true),
+                    CustomActions::DebounceDown => settings.step_debounce(false),
+                    CustomActions::ResetSettings => *settings = DEFAULT_SETTINGS,
+                    CustomActions::LayerLock => c.local.virtual_layers.lock(),
+                    CustomActions::ToggleLayer(layer) => {
+                        c.local.virtual_layers.toggle(*layer);
+                    }
+                    CustomActions::SetDefaultLayer(layer) => {
+                        settings.default_layer = *layer as u16;
+                    }
+                    CustomActions::SetOs(os) => settings.os = os.setting(),
+                    CustomActions::DetectOs => settings.os = os::AUTO,
+                    CustomActions::Shortcut(shortcut) => c.local.shortcuts.press(*shortcut, os),
+                    CustomActions::Unicode(ch) => {
+                        unicode::type_char(c.local.sequence, method, host_layout, *ch);
+                    }
+                    CustomActions::Compose => c.local.compose.toggle(),
+                    CustomActions::ToggleAutocorrect => c.local.autocorrect.toggle(),
+                    CustomActions::Repeat => c.local.repeat.press(),
+                    CustomActions::AltRepeat => {
+                        c.local.repeat.press_alt(&crate::layout::ALT_REPEAT, os);
+                    }
+                    CustomActions::SwapHands => c.local.swap_hands.hold(true),
+                    CustomActions::ToggleSwapHands => c.local.swap_hands.toggle(),
+                    CustomActions::Lock => c.local.lock.lock(c.local.sequence),
+                },
+                layout::CustomEvent::Release(CustomActions::Shortcut(shortcut)) => {
+                    c.local.shortcuts.release(*shortcut);
                 }
-            },
-            _ => (),
+                layout::CustomEvent::Release(CustomActions::Repeat)
+                | layout::CustomEvent::Release(CustomActions::AltRepeat) => {
+                    c.local.repeat.release()
+                }
+                layout::CustomEvent::Release(CustomActions::SwapHands) => {
+                    c.local.swap_hands.hold(false)
+                }
+                _ => (),
+            }
         }
-        let mut usb_class = c.shared.usb_class;
-        let report: KbHidReport = c.local.layout.keycodes().collect();
+        let current = c.local.layout.current_layer();
+        for event in c.local.virtual_layers.update(current) {
+            c.local.layout.event(event);
+        }
+
+        let keys = typing::keys(
+            c.local
+                .layout
+                .keycodes()
+                .chain(c.local.flow_tap.keycodes())
+                .map(|kc| os.modifier(kc))
+                .chain(c.local.shortcuts.keycodes())
+                .chain(c.local.repeat.keycodes()),
+        );
+        let mut keys = c.local.backlog.next(keys, c.local.sequence.is_playing());
+        let presses = c.local.typing.update(&keys);
+        c.local.lock.filter(&mut keys, &presses);
+        c.local.repeat.update(&presses);
+        let sequence = c.local.sequence;
+        c.local
+            .compose
+            .filter(&mut keys, &presses, sequence, method, host_layout);
+        c.local
+            .snippets
+            .filter(&mut keys, &presses, sequence, method, host_layout);
+        c.local
+            .autocorrect
+            .filter(&mut keys, &presses, sequence, method, host_layout);
+
+        let locked = c.local.lock.is_locked();
+        usb_class.lock(|k| {
+            let leds = k.device_mut().leds_mut();
+            leds.show_lock(locked);
+            leds.tick();
+        });
+        sequence.tick();
+        let report: KbHidReport = if sequence.is_playing() {
+            sequence.keycodes().collect()
+        } else {
+            keys.iter().copied().collect()
+        };
         if usb_class.lock(|k| k.device_mut().set_keyboard_report(report.clone())) {
             while let Ok(0) = usb_class.lock(|k| k.write(report.as_bytes())) {}
         }
+
+        c.local.storage.tick(settings, c.local.watchdog);
     }
 }
@@ -0,0 +1,59 @@
+use keebs_common::discovery::Probe;
+
+// GPIO ports of the STM32F401, 0x400 apart from GPIOA, and the offsets of
+// their registers (RM0368 8.4)
+const GPIOA: usize = 0x4002_0000;
+const MODER: usize = 0x00;
+const PUPDR: usize = 0x0c;
+const IDR: usize = 0x10;
+const BSRR: usize = 0x18;
+
+/// A matrix pin probed by the self-test through the GPIO registers, before
+/// the HAL configures it for the matrix. The port must be clocked, as after
+/// its `split`.
+pub struct ProbePin {
+    port: u8,
+    pin: u8,
+}
+
+impl ProbePin {
+    /// Pin `pin` of port `port`, from 'A'.
+    pub const fn new(port: char, pin: u8) -> Self {
+        Self {
+            port: port as u8 - b'A',
+            pin,
+        }
+    }
+
+    fn register(&self, offset: usize) -> *mut u32 {
+        (GPIOA + 0x400 * self.port as usize + offset) as *mut u32
+    }
+
+    // Sets the 2 bits of the pin in MODER or PUPDR
+    fn set_bits(&mut self, offset: usize, value: u32) {
+        let shift = 2 * self.pin as u32;
+        let register = self.register(offset);
+        unsafe {
+            let bits = register.read_volatile() & !(0b11 << shift);
+            register.write_volatile(bits | value << shift);
+        }
+    }
+}
+
+impl Probe for ProbePin {
+    fn drive_low(&mut self) {
+        let reset = 1 << (16 + self.pin as u32);
+        unsafe { self.register(BSRR).write_volatile(reset) };
+        // General purpose output
+        self.set_bits(MODER, 0b01);
+    }
+
+    fn pull_up(&mut self) {
+        self.set_bits(MODER, 0b00);
+        self.set_bits(PUPDR, 0b01);
+    }
+
+    fn is_low(&self) -> bool {
+        unsafe { self.register(IDR).read_volatile() & 1 << self.pin == 0 }
+    }
+}
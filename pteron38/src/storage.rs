@@ -0,0 +1,56 @@
+use stm32f4xx_hal::flash::FlashExt;
+use stm32f4xx_hal::pac::FLASH;
+use stm32f4xx_hal::time::MilliSeconds;
+use stm32f4xx_hal::watchdog::IndependentWatchdog;
+
+use keebs_common::settings::{self, Settings};
+
+// Sector 1, 16K at 0x08004000, skipped by `_stext` in memory.x
+const SECTOR: u8 = 1;
+const OFFSET: usize = 0x4000;
+// Wait for the settings to stop changing before wearing the flash
+const SAVE_DELAY_MS: u16 = 2000;
+
+pub struct Storage {
+    flash: FLASH,
+    saved: Settings,
+    quiet: u16,
+}
+
+impl Storage {
+    pub fn new(flash: FLASH, defaults: Settings) -> Self {
+        let bytes = &flash.read()[OFFSET..OFFSET + settings::SIZE];
+        Self {
+            saved: Settings::from_bytes(bytes, defaults),
+            flash,
+            quiet: 0,
+        }
+    }
+
+    pub fn load(&self) -> Settings {
+        self.saved
+    }
+
+    pub fn tick(&mut self, settings: &Settings, watchdog: &mut IndependentWatchdog) {
+        if *settings == self.saved {
+            self.quiet = 0;
+            return;
+        }
+        self.quiet += 1;
+        if self.quiet < SAVE_DELAY_MS {
+            return;
+        }
+
+        // Erasing a 16K sector stalls the CPU for up to 500 ms
+        watchdog.start(MilliSeconds(1000));
+        let mut flash = self.flash.unlocked();
+        let _ = flash
+            .erase(SECTOR)
+            .and_then(|()| flash.program(OFFSET, settings.to_bytes().iter()));
+        drop(flash);
+        watchdog.start(MilliSeconds(10));
+
+        self.saved = *settings;
+        self.quiet = 0;
+    }
+}
@@ -0,0 +1,85 @@
+use rp2040_hal::{
+    gpio::DynPin,
+    multicore::Stack,
+    pac,
+    sio::{Sio, SioFifo},
+};
+
+use keebs_common::debounce::{Debouncer, EagerPerKey};
+use keebs_common::direct::DirectPins;
+use keebs_common::intercore::{Command, Message, Outbox, RESUME};
+use keebs_common::self_test::SelfTest;
+
+use crate::app::Scanner;
+use crate::layout::SCAN_ROWS;
+
+pub static mut STACK: Stack<4096> = Stack::new();
+
+const SCAN_TIME_US: u32 = 1000;
+
+/// Scans and debounces the matrix every ms on core 1, sending the key events
+/// to core 0 through the SIO FIFO, and waiting for it when they do not fit.
+pub fn run(
+    mut matrix: Scanner,
+    direct: DirectPins<DynPin, 2>,
+    self_test: SelfTest<14, SCAN_ROWS>,
+    mut debouncer: Debouncer<EagerPerKey<14, SCAN_ROWS>, 14, SCAN_ROWS>,
+) -> ! {
+    // Only this core's side of the FIFO and the timer counter are used
+    let pac = unsafe { pac::Peripherals::steal() };
+    let mut fifo = Sio::new(pac.SIO).fifo;
+    let timer = pac.TIMER;
+    let mut outbox: Outbox<14, SCAN_ROWS, 64> = Outbox::default();
+    let mut next = timer.timerawl.read().bits();
+    loop {
+        while let Some(word) = fifo.read() {
+            match Command::from_word(word) {
+                Some(Command::Debounce(time)) => debouncer.set_time(time),
+                Some(Command::Pause) => {
+                    fifo.write_blocking(Message::Paused.to_word());
+                    wait_resume();
+                }
+                None => (),
+            }
+        }
+
+        let scan = self_test.filter(direct.merge(matrix.get()).unwrap());
+        for event in debouncer.events(scan) {
+            while !outbox.push(Message::Event(event)) {
+                flush(&mut outbox, &mut fifo);
+            }
+        }
+        outbox.chatter(debouncer.chatter());
+        flush(&mut outbox, &mut fifo);
+
+        next = next.wrapping_add(SCAN_TIME_US);
+        while (timer.timerawl.read().bits().wrapping_sub(next) as i32) < 0 {}
+    }
+}
+
+// Writes the queued words while the FIFO has room
+fn flush(outbox: &mut Outbox<14, SCAN_ROWS, 64>, fifo: &mut SioFifo) {
+    outbox.flush(|word| {
+        let ready = fifo.is_write_ready();
+        if ready {
+            fifo.write(word);
+        }
+        ready
+    });
+}
+
+/// Waits for `RESUME` while core 0 writes the flash, running from RAM.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+fn wait_resume() {
+    // FIFO_ST, valid data in bit 0, and FIFO_RD of the SIO
+    const FIFO_ST: *const u32 = 0xd000_0050 as _;
+    const FIFO_RD: *const u32 = 0xd000_0058 as _;
+    loop {
+        unsafe {
+            if FIFO_ST.read_volatile() & 1 != 0 && FIFO_RD.read_volatile() == RESUME {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use rp2040_hal::pac;
+
+// DMA channel 0 and the offsets of its registers (RP2040 datasheet 2.5.7)
+const CH0: usize = 0x5000_0000;
+const READ_ADDR: usize = 0x00;
+const WRITE_ADDR: usize = 0x04;
+const TRANS_COUNT: usize = 0x08;
+const CTRL_TRIG: usize = 0x0c;
+// RX FIFO of the state machine 0 of PIO1, and its data request
+const PIO1_RXF0: u32 = 0x5030_0020;
+const DREQ_PIO1_RX0: u32 = 12;
+
+// CTRL_TRIG: enabled, word transfers, incrementing the write address in a
+// ring of 16 bytes, chained to itself so never chained, paced by the PIO
+const CTRL: u32 = 1 | 2 << 2 | 1 << 5 | 4 << 6 | 1 << 10 | DREQ_PIO1_RX0 << 15;
+const BUSY: u32 = 1 << 24;
+
+// The ring wraps on its size, so it is aligned on it
+#[repr(align(16))]
+struct Ring([u32; 4]);
+
+static mut RING: Ring = Ring([!0; 4]);
+
+/// Copies the words pushed by the PIO scan to a ring of 4 words in RAM with
+/// DMA channel 0, so that the state machine never waits for the CPU.
+pub struct ScanDma;
+
+impl ScanDma {
+    pub fn new(resets: &mut pac::RESETS) -> Self {
+        resets.reset.modify(|_, w| w.dma().clear_bit());
+        while resets.reset_done.read().dma().bit_is_clear() {}
+        unsafe {
+            register(READ_ADDR).write_volatile(PIO1_RXF0);
+            register(WRITE_ADDR).write_volatile(ring() as u32);
+            register(TRANS_COUNT).write_volatile(u32::MAX);
+            register(CTRL_TRIG).write_volatile(CTRL);
+        }
+        Self
+    }
+
+    /// The last 4 words pushed, restarting the channel once its count of
+    /// 2^32 - 1 words ran out.
+    pub fn words(&mut self) -> [u32; 4] {
+        unsafe {
+            if register(CTRL_TRIG).read_volatile() & BUSY == 0 {
+                register(CTRL_TRIG).write_volatile(CTRL);
+            }
+            core::array::from_fn(|i| ring().add(i).read_volatile())
+        }
+    }
+}
+
+fn ring() -> *mut u32 {
+    unsafe { core::ptr::addr_of_mut!(RING) as *mut u32 }
+}
+
+fn register(offset: usize) -> *mut u32 {
+    (CH0 + offset) as *mut u32
+}
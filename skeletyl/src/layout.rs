@@ -1,139 +1,294 @@
 use keyberon::action::{k, l, Action, HoldTapAction, HoldTapConfig};
 use keyberon::key_code::{KeyCode, KeyCode::*};
 
+use keebs_common::autocorrect::{trie, trie_len};
+use keebs_common::compose::Output;
+use keebs_common::layers::stack;
+use keebs_common::os::{Os, Shortcut};
+use keebs_common::repeat::AltRepeat;
+use keebs_common::settings::MAX_TAPPING_TERM;
+use keebs_common::unicode::Method;
+use keyberon::layout::Layers;
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum CustomActions {
     Bootload,
     Reset,
+    TappingTermUp,
+    TappingTermDown,
+    DebounceUp,
+    DebounceDown,
+    ResetSettings,
+    LayerLock,
+    ToggleLayer(usize),
+    SetDefaultLayer(usize),
+    SetOs(Os),
+    DetectOs,
+    Shortcut(&'static Shortcut),
+    Unicode(char),
+    Compose,
+    ToggleAutocorrect,
+    Repeat,
+    AltRepeat,
+    SwapHands,
+    ToggleSwapHands,
+    Lock,
 }
 const BOOTLOAD: Action<CustomActions> = Action::Custom(CustomActions::Bootload);
 const RESET: Action<CustomActions> = Action::Custom(CustomActions::Reset);
 
-const A_LS: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: k(LShift),
-    tap: k(A),
-    config: HoldTapConfig::Default,
-    tap_hold_interval: 0,
-});
-const L3_S: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: l(3),
-    tap: k(S),
-    config: HoldTapConfig::Default,
-    tap_hold_interval: 0,
-});
-const D_LA: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: k(LAlt),
-    tap: k(D),
-    config: HoldTapConfig::Default,
-    tap_hold_interval: 0,
-});
-const L1_F: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: l(1),
-    tap: k(F),
-    config: HoldTapConfig::Default,
-    tap_hold_interval: 0,
-});
-const DT_R: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: k(RAlt),
-    tap: k(Dot),
-    config: HoldTapConfig::Default,
-    tap_hold_interval: 0,
-});
-const X_LA: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: k(LAlt),
-    tap: k(X),
-    config: HoldTapConfig::Default,
-    tap_hold_interval: 0,
-});
-const SL_R: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: k(RCtrl),
-    tap: k(Slash),
-    config: HoldTapConfig::Default,
-    tap_hold_interval: 0,
-});
-const Z_LC: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: k(LCtrl),
-    tap: k(Z),
-    config: HoldTapConfig::Default,
-    tap_hold_interval: 0,
-});
-const L2_C: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: l(2),
-    tap: k(C),
-    config: HoldTapConfig::Default,
-    tap_hold_interval: 0,
-});
-const SM_R: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: k(RShift),
-    tap: k(SColon),
-    config: HoldTapConfig::Default,
-    tap_hold_interval: 0,
-});
-const L4_S: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: l(4),
-    tap: k(Space),
-    config: HoldTapConfig::Default,
-    tap_hold_interval: 0,
-});
-const L2_O: Action<CustomActions> = Action::HoldTap(&HoldTapAction {
-    timeout: 200,
-    hold: l(2),
-    tap: k(Comma),
-    config: HoldTapConfig::Default,
-    tap_hold_interval: 0,
-});
-
-const CT_T: Action<CustomActions> =
-    Action::MultipleKeyCodes(&[KeyCode::LCtrl, KeyCode::Tab].as_slice());
-const SC_T: Action<CustomActions> =
-    Action::MultipleKeyCodes(&[KeyCode::LShift, KeyCode::LCtrl, KeyCode::Tab].as_slice());
+const TERM_UP: Action<CustomActions> = Action::Custom(CustomActions::TappingTermUp);
+const TERM_DN: Action<CustomActions> = Action::Custom(CustomActions::TappingTermDown);
+const DEB_UP: Action<CustomActions> = Action::Custom(CustomActions::DebounceUp);
+const DEB_DN: Action<CustomActions> = Action::Custom(CustomActions::DebounceDown);
+const DEFAULTS: Action<CustomActions> = Action::Custom(CustomActions::ResetSettings);
+const LLOCK: Action<CustomActions> = Action::Custom(CustomActions::LayerLock);
+const TG_NUM: Action<CustomActions> = Action::Custom(CustomActions::ToggleLayer(1));
+const QWERTY: Action<CustomActions> = Action::Custom(CustomActions::SetDefaultLayer(0));
+const COLEMAK: Action<CustomActions> = Action::Custom(CustomActions::SetDefaultLayer(1));
+const DVORAK: Action<CustomActions> = Action::Custom(CustomActions::SetDefaultLayer(2));
+const OS_LNX: Action<CustomActions> = Action::Custom(CustomActions::SetOs(Os::Linux));
+const OS_WIN: Action<CustomActions> = Action::Custom(CustomActions::SetOs(Os::Windows));
+const OS_MAC: Action<CustomActions> = Action::Custom(CustomActions::SetOs(Os::MacOs));
+const OS_AUT: Action<CustomActions> = Action::Custom(CustomActions::DetectOs);
+
+const U_ARR: Action<CustomActions> = Action::Custom(CustomActions::Unicode('→'));
+const U_EUR: Action<CustomActions> = Action::Custom(CustomActions::Unicode('€'));
+const U_LAM: Action<CustomActions> = Action::Custom(CustomActions::Unicode('λ'));
+const U_NEQ: Action<CustomActions> = Action::Custom(CustomActions::Unicode('≠'));
+const CMPS: Action<CustomActions> = Action::Custom(CustomActions::Compose);
+const AC_TG: Action<CustomActions> = Action::Custom(CustomActions::ToggleAutocorrect);
+const REP: Action<CustomActions> = Action::Custom(CustomActions::Repeat);
+const AREP: Action<CustomActions> = Action::Custom(CustomActions::AltRepeat);
+const SWAP: Action<CustomActions> = Action::Custom(CustomActions::SwapHands);
+const SW_TG: Action<CustomActions> = Action::Custom(CustomActions::ToggleSwapHands);
+const LOCK: Action<CustomActions> = Action::Custom(CustomActions::Lock);
+
+// Unicode input method used in Windows mode
+pub const WINDOWS_UNICODE: Method = Method::WinCompose;
+
+// Default tapping_term setting. The hold-taps are declared with the longest
+// one and shortened at runtime by `TapClock`
+pub const TAPPING_TERM: u16 = 200;
+
+// Hold-tap with this board's timeout and config, used for the mods and layer
+// keys of every base layer
+macro_rules! hold_tap {
+    ($hold:expr, $tap:expr) => {
+        Action::HoldTap(&HoldTapAction {
+            timeout: MAX_TAPPING_TERM,
+            hold: $hold,
+            tap: $tap,
+            config: HoldTapConfig::Default,
+            tap_hold_interval: 0,
+        })
+    };
+}
+
+const A_LS: Action<CustomActions> = hold_tap!(k(LShift), k(A));
+const L3_S: Action<CustomActions> = hold_tap!(l(3), k(S));
+const D_LA: Action<CustomActions> = hold_tap!(k(LAlt), k(D));
+const L1_F: Action<CustomActions> = hold_tap!(l(1), k(F));
+const DT_R: Action<CustomActions> = hold_tap!(k(RAlt), k(Dot));
+const X_LA: Action<CustomActions> = hold_tap!(k(LAlt), k(X));
+const SL_R: Action<CustomActions> = hold_tap!(k(RCtrl), k(Slash));
+const Z_LC: Action<CustomActions> = hold_tap!(k(LCtrl), k(Z));
+const L2_C: Action<CustomActions> = hold_tap!(l(2), k(C));
+const SM_R: Action<CustomActions> = hold_tap!(k(RShift), k(SColon));
+const L4_S: Action<CustomActions> = hold_tap!(l(4), k(Space));
+const L2_O: Action<CustomActions> = hold_tap!(l(2), k(Comma));
+
+// Colemak-DH home row
+const L3_R: Action<CustomActions> = hold_tap!(l(3), k(R));
+const S_LA: Action<CustomActions> = hold_tap!(k(LAlt), k(S));
+const L1_T: Action<CustomActions> = hold_tap!(l(1), k(T));
+const O_RS: Action<CustomActions> = hold_tap!(k(RShift), k(O));
+
+// Dvorak home and bottom rows
+const L3_O: Action<CustomActions> = hold_tap!(l(3), k(O));
+const E_LA: Action<CustomActions> = hold_tap!(k(LAlt), k(E));
+const L1_U: Action<CustomActions> = hold_tap!(l(1), k(U));
+const S_RS: Action<CustomActions> = hold_tap!(k(RShift), k(S));
+const SM_LC: Action<CustomActions> = hold_tap!(k(LCtrl), k(SColon));
+const Q_LA: Action<CustomActions> = hold_tap!(k(LAlt), k(Q));
+const L2_J: Action<CustomActions> = hold_tap!(l(2), k(J));
+const L2_W: Action<CustomActions> = hold_tap!(l(2), k(W));
+const V_RA: Action<CustomActions> = hold_tap!(k(RAlt), k(V));
+const Z_RC: Action<CustomActions> = hold_tap!(k(RCtrl), k(Z));
+
+// Next and previous tab, Cmd+Shift+] and Cmd+Shift+[ on macOS
+const CT_T: Action<CustomActions> = Action::Custom(CustomActions::Shortcut(&[
+    &[LCtrl, Tab],
+    &[LCtrl, Tab],
+    &[LGui, LShift, RBracket],
+]));
+const SC_T: Action<CustomActions> = Action::Custom(CustomActions::Shortcut(&[
+    &[LShift, LCtrl, Tab],
+    &[LShift, LCtrl, Tab],
+    &[LGui, LShift, LBracket],
+]));
 const SF_T: Action<CustomActions> =
     Action::MultipleKeyCodes(&[KeyCode::LShift, KeyCode::Tab].as_slice());
 
+// Rows of the matrix, then the row of the direct keys scanned with it
+pub const MATRIX_ROWS: usize = 3;
+pub const DIRECT_ROW: u8 = MATRIX_ROWS as u8;
+pub const SCAN_ROWS: usize = MATRIX_ROWS + 1;
+
+// Row past the matrix and the direct keys holding layer n in column n,
+// pressed by the firmware to keep a layer active
+pub const VIRTUAL_ROW: u8 = SCAN_ROWS as u8;
+pub const ROWS: usize = SCAN_ROWS + 1;
+
+// Layer activated while both layers of the pair are active, here
+// symbols + navigation = function keys
+pub const CONDITIONAL_LAYERS: [([usize; 2], usize); 1] = [([2, 3], 4)];
+
 pub const CHORDS: [keyberon::chording::ChordDef; 6] = [
     ((0, 12), &[(0, 8), (1, 8)]),   // Escape
     ((1, 12), &[(0, 9), (1, 9)]),   // Tab
     ((2, 12), &[(0, 10), (1, 10)]), // Enter
-    ((0, 12), &[(0, 12), (0, 13)]), // unused
-    ((1, 12), &[(1, 12), (1, 13)]), // unused
+    ((0, 13), &[(1, 8), (2, 8)]),   // Repeat
+    ((1, 13), &[(1, 9), (2, 9)]),   // Alternate repeat
     ((2, 12), &[(2, 12), (2, 13)]), // unused
 ];
 
-pub static LAYERS: keyberon::layout::Layers<14, 3, 5, CustomActions> = keyberon::layout::layout! {
-    { // 0
-        [Q      W      E      R      T {SC_T} LGui Y U I      O      P      Escape t]
-        [{A_LS} {L3_S} {D_LA} {L1_F} G BSpace {L4_S} H J K      L      {SM_R} Tab    t]
+// Keys typed to unlock the keyboard after the lock key
+pub const UNLOCK: [KeyCode; 6] = [U, N, L, O, C, K];
+
+// Matrix columns swapped by the swap-hands keys
+pub const MIRROR: [(u8, u8); 6] = [(0, 11), (1, 10), (2, 9), (3, 8), (4, 7), (5, 6)];
+
+// Hold-taps pressed within this many ms of the previous key press are taps
+pub const FLOW_TAP_TERM: u16 = 120;
+// Per hold-tap overrides of FLOW_TAP_TERM, 0 never forces a tap
+pub const FLOW_TAP: [(Action<CustomActions>, u16); 4] =
+    [(A_LS, 150), (Z_LC, 150), (SM_LC, 150), (L4_S, 0)];
+
+// Pairs of characters typed after the compose key, ASCII output is typed
+// through the host layout and the rest with Unicode input
+#[rustfmt::skip]
+pub const COMPOSE: [([char; 2], Output); 24] = [
+    (['a', '\''], Output::Char('á')), (['e', '\''], Output::Char('é')),
+    (['i', '\''], Output::Char('í')), (['o', '\''], Output::Char('ó')),
+    (['u', '\''], Output::Char('ú')), (['a', '`'], Output::Char('à')),
+    (['e', '`'], Output::Char('è')), (['a', '"'], Output::Char('ä')),
+    (['o', '"'], Output::Char('ö')), (['u', '"'], Output::Char('ü')),
+    (['s', 's'], Output::Char('ß')), (['c', ','], Output::Char('ç')),
+    (['n', '~'], Output::Char('ñ')), (['e', '='], Output::Char('€')),
+    (['o', 'c'], Output::Char('©')), (['+', '-'], Output::Char('±')),
+    (['-', '>'], Output::Char('→')), (['<', '-'], Output::Char('←')),
+    (['<', '='], Output::Char('≤')), (['>', '='], Output::Char('≥')),
+    (['=', '/'], Output::Char('≠')), (['.', '.'], Output::Char('…')),
+    (['m', 'm'], Output::Keys(&[Mute])), (['p', 'p'], Output::Keys(&[MediaPlayPause])),
+];
+
+// Typos corrected when the word ends, in lowercase ASCII letters
+#[rustfmt::skip]
+pub const TYPOS: [(&str, &str); 44] = [
+    ("teh", "the"), ("hte", "the"), ("taht", "that"), ("thier", "their"),
+    ("thsi", "this"), ("wiht", "with"), ("adn", "and"), ("jsut", "just"),
+    ("yuo", "you"), ("waht", "what"), ("wich", "which"), ("whihc", "which"),
+    ("becuase", "because"), ("recieve", "receive"), ("beleive", "believe"),
+    ("seperate", "separate"), ("definately", "definitely"), ("occured", "occurred"),
+    ("occurence", "occurrence"), ("untill", "until"), ("wierd", "weird"),
+    ("freind", "friend"), ("acheive", "achieve"), ("accomodate", "accommodate"),
+    ("adress", "address"), ("begining", "beginning"), ("calender", "calendar"),
+    ("comming", "coming"), ("enviroment", "environment"), ("existance", "existence"),
+    ("goverment", "government"), ("happend", "happened"), ("independant", "independent"),
+    ("knowlege", "knowledge"), ("neccessary", "necessary"), ("persistant", "persistent"),
+    ("posible", "possible"), ("realy", "really"), ("recomend", "recommend"),
+    ("refered", "referred"), ("succesful", "successful"), ("tommorow", "tomorrow"),
+    ("truely", "truly"), ("wether", "whether"),
+];
+// `TYPOS` compiled at build time
+pub static TYPO_TRIE: [u8; trie_len(&TYPOS)] = trie(&TYPOS);
+
+// Triggers typed before a space, replaced with their expansion
+pub const SNIPPETS: [(&str, &str); 4] = [
+    (";em", "cam@example.com"),
+    (";sig", "Best regards,\nCam"),
+    (";shrug", "¯\\_(ツ)_/¯"),
+    (";ty", "Thank you!"),
+];
+const _: () = keebs_common::snippets::check(&SNIPPETS);
+
+// Pairs of keys the alternate repeat key turns into each other, for a US
+// host layout
+pub const ALT_REPEAT: [AltRepeat; 10] = [
+    (&[LShift, Kb9], &[LShift, Kb0]),
+    (&[LBracket], &[RBracket]),
+    (&[LShift, LBracket], &[LShift, RBracket]),
+    (&[LCtrl, Z], &[LCtrl, Y]),
+    (&[Left], &[Right]),
+    (&[Up], &[Down]),
+    (&[Home], &[End]),
+    (&[PgUp], &[PgDown]),
+    (&[Tab], &[LShift, Tab]),
+    (&[LCtrl, Tab], &[LShift, LCtrl, Tab]),
+];
+
+// Row 3 holds the direct keys, in columns 12 and 13
+// Base layers, picked by the default_layer setting
+pub const BASE_LAYERS: usize = 3;
+// Layers from a base layer to the next: itself, the 4 layers above it and
+// transparent layers up to the sum of two of them
+pub const BLOCK: usize = 9;
+pub const LAYER_COUNT: usize = BASE_LAYERS * BLOCK;
+
+pub static LAYERS: Layers<14, ROWS, LAYER_COUNT, CustomActions> = stack(&BASES, &ABOVE);
+
+const BASES: Layers<14, ROWS, BASE_LAYERS, CustomActions> = keyberon::layout::layout! {
+    { // 0: QWERTY
+        [Q      W      E      R      T {SC_T} LGui Y U I      O      P      Escape {REP}]
+        [{A_LS} {L3_S} {D_LA} {L1_F} G BSpace {L4_S} H J K      L      {SM_R} Tab    {AREP}]
         [{Z_LC} {X_LA} {L2_C} V      B {CT_T} LAlt N M {L2_O} {DT_R} {SL_R} Enter  t]
+        [t      t      t      t      t   t    t    t t t      t      t      MediaVolDown MediaVolUp]
+        [t      (1)    (2)    (3)    (4) t    t    t t t      t      t      t      t]
+    }
+    { // 1: Colemak-DH
+        [Q      W      F      P      B {SC_T} LGui J L U      Y      ;      Escape {REP}]
+        [{A_LS} {L3_R} {S_LA} {L1_T} G BSpace {L4_S} M N E      I      {O_RS} Tab    {AREP}]
+        [{Z_LC} {X_LA} {L2_C} D      V {CT_T} LAlt K H {L2_O} {DT_R} {SL_R} Enter  t]
+        [t      t      t      t      t   t    t    t t t      t      t      MediaVolDown MediaVolUp]
+        [t      (1)    (2)    (3)    (4) t    t    t t t      t      t      t      t]
     }
+    { // 2: Dvorak
+        [Quote   ,      .      P      Y {SC_T} LGui F G C      R      L      Escape {REP}]
+        [{A_LS}  {L3_O} {E_LA} {L1_U} I BSpace {L4_S} D H T      N      {S_RS} Tab    {AREP}]
+        [{SM_LC} {Q_LA} {L2_J} K      X {CT_T} LAlt B M {L2_W} {V_RA} {Z_RC} Enter  t]
+        [t       t      t      t      t   t    t    t t t      t      t      MediaVolDown MediaVolUp]
+        [t       (1)    (2)    (3)    (4) t    t    t t t      t      t      t      t]
+    }
+};
+
+const ABOVE: Layers<14, ROWS, 4, CustomActions> = keyberon::layout::layout! {
     { // 1
-        [t t t t t t t * 7 8 9 + t t]
-        [t t t t t t 0 / 4 5 6 - t t]
-        [t t t t t t t . 1 2 3 . t t]
+        [{OS_LNX} {OS_WIN} {OS_MAC} {OS_AUT} t   {LLOCK} t * 7 8 9 + t t]
+        [{AC_TG}  t        t        t        t   t       0 / 4 5 6 - t t]
+        [{SW_TG}  {SWAP}   {LOCK}   t        t   t       t . 1 2 3 . t t]
+        [t        t        t        t        t   t       t t t t t t t t]
+        [t        (1)      (2)      (3)      (4) t       t t t t t t t t]
     }
     { // 2
-        [!   @   #   $   % t t t ~   |    '`' +     t t]
-        ['{' '}' '(' ')' t t t = '_' -    '"' Quote t t]
-        ['[' ']' ^   &   * t t t /   '\\' t   t     t t]
+        [!   @   #   $   %       {LLOCK} t {U_EUR} ~   |    '`'     +       t t]
+        ['{' '}' '(' ')' {U_ARR} {CMPS}  t =       '_' -    '"'     Quote   t t]
+        ['[' ']' ^   &   *       t       t t       /   '\\' {U_NEQ} {U_LAM} t t]
+        [t   t   t   t   t       t       t t       t   t    t       t       t t]
+        [t   (1) (2) (3) (4)     t       t t       t   t    t       t       t t]
     }
     { // 3
-        [t t t      t t t t t    {SF_T} PgUp   Tab   t     t t]
-        [t t Delete t t t t Left Down   Up     Right Enter t t]
-        [t t t      t t t t t    Home   PgDown End   t     t t]
+        [t t   t      t   t   {LLOCK} t t    {SF_T} PgUp   Tab   t     t t]
+        [t t   Delete t   t   t       t Left Down   Up     Right Enter t t]
+        [t t   t      t   t   t       t t    Home   PgDown End   t     t t]
+        [t t   t      t   t   t       t t    t      t      t     t     t t]
+        [t (1) (2)    (3) (4) t       t t    t      t      t     t     t t]
     }
     { // 4
-        [{BOOTLOAD} F7 F8 F9 F10 t      t MediaNextSong MediaPlayPause MediaVolDown MediaVolUp PScreen t t]
-        [{RESET}    F4 F5 F6 F11 Delete t t             Escape         Tab          Enter      Enter   t t]
-        [t          F1 F2 F3 F12 t      t MediaSleep    t              t            t          t       t t]
+        [{BOOTLOAD} F7  F8  F9  F10 {LLOCK}  {TG_NUM}  MediaNextSong MediaPlayPause MediaVolDown MediaVolUp PScreen  t t]
+        [{RESET}    F4  F5  F6  F11 Delete   t         {DVORAK}      Escape         Tab          Enter      Enter    t t]
+        [{DEFAULTS} F1  F2  F3  F12 {QWERTY} {COLEMAK} MediaSleep    {TERM_DN}      {TERM_UP}    {DEB_DN}   {DEB_UP} t t]
+        [t          t   t   t   t   t        t         t             t              t            t          t        t t]
+        [t          (1) (2) (3) (4) t        t         t             t              t            t          t        t t]
     }
 };
@@ -3,7 +3,28 @@
 
 use panic_halt as _;
 
+mod core1;
+mod dma;
 mod layout;
+mod storage;
+
+/// A GPIO probed by the wiring discovery, the duplex scan and the self-test.
+pub struct ProbePin(sparkfun_pro_micro_rp2040::hal::gpio::DynPin);
+
+impl keebs_common::discovery::Probe for ProbePin {
+    fn drive_low(&mut self) {
+        self.0.into_push_pull_output();
+        let _ = embedded_hal::digital::v2::OutputPin::set_low(&mut self.0);
+    }
+
+    fn pull_up(&mut self) {
+        self.0.into_pull_up_input();
+    }
+
+    fn is_low(&self) -> bool {
+        embedded_hal::digital::v2::InputPin::is_low(&self.0).unwrap_or(false)
+    }
+}
 
 #[rtic::app(device = sparkfun_pro_micro_rp2040::hal::pac, peripherals = true, dispatchers = [PIO0_IRQ_0])]
 mod app {
@@ -12,17 +33,18 @@ mod app {
     };
     use embedded_time::{duration::units::*, rate::*};
     use keyberon::chording::Chording;
-    use keyberon::debounce::Debouncer;
     use keyberon::key_code::KbHidReport;
     use keyberon::layout::{self, Layout};
-    use keyberon::matrix::Matrix;
     use sparkfun_pro_micro_rp2040::{
         hal::{
             self,
             clocks::{Clock, ClockSource, ClocksManager, InitError},
-            gpio::DynPin,
+            gpio::{DynPin, FunctionPio1},
+            multicore::Multicore,
+            pac,
+            pio::{PIOBuilder, PIOExt, Running, Rx, ShiftDirection, StateMachine, SM0},
             pll::{common_configs::PLL_USB_48MHZ, setup_pll_blocking},
-            sio::Sio,
+            sio::{Sio, SioFifo},
             timer::Alarm,
             usb::UsbBus,
             watchdog::Watchdog,
@@ -31,10 +53,111 @@ mod app {
         Pins, XOSC_CRYSTAL_FREQ,
     };
     use usb_device::class_prelude::*;
+    use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+    use usbd_serial::SerialPort;
 
-    use crate::layout::CustomActions;
+    use keebs_common::autocorrect::Autocorrect;
+    use keebs_common::compose::Compose;
+    use keebs_common::debounce::{Debouncer, EagerPerKey};
+    use keebs_common::direct::DirectPins;
+    use keebs_common::discovery::Discovery;
+    use keebs_common::duplex::DuplexMatrix;
+    use keebs_common::flow_tap::FlowTap;
+    use keebs_common::host::Host;
+    use keebs_common::intercore::{Command, Inbox};
+    use keebs_common::keymap::HostLayout;
+    use keebs_common::layers::VirtualLayers;
+    use keebs_common::lock::Lock;
+    use keebs_common::matrix::{Col2Row, Matrix};
+    use keebs_common::os::{self, Os, Shortcuts};
+    use keebs_common::os_detect::OsDetect;
+    use keebs_common::pio_scan::PioScan;
+    use keebs_common::repeat::Repeat;
+    use keebs_common::self_test::SelfTest;
+    use keebs_common::sequence::Sequence;
+    use keebs_common::settings::{Settings, TapClock};
+    use keebs_common::snippets::Snippets;
+    use keebs_common::swap_hands::SwapHands;
+    use keebs_common::typing::{self, Backlog, Typing};
+    use keebs_common::unicode::{self, Method};
+
+    use crate::dma::ScanDma;
+    use crate::layout::{
+        CustomActions, BASE_LAYERS, BLOCK, DIRECT_ROW, LAYER_COUNT, MATRIX_ROWS, ROWS, SCAN_ROWS,
+    };
+    use crate::storage::Storage;
+    use crate::ProbePin;
 
     const SCAN_TIME_US: u32 = 1000;
+    const DEFAULT_SETTINGS: Settings = Settings {
+        tapping_term: crate::layout::TAPPING_TERM,
+        debounce: 20,
+        default_layer: 0,
+        os: os::AUTO,
+        host_layout: 0,
+    };
+    // Cycles waited after driving a matrix pin, 1 µs at 62.5 MHz
+    const MATRIX_SETTLE: u32 = 63;
+
+    // GPIOs of the matrix, for the PIO scan
+    const PIO_COLS: [Option<u8>; 14] = [
+        Some(27), // adc1
+        Some(26), // adc0
+        Some(28), // adc2
+        Some(20), // cipo
+        Some(22), // sck
+        Some(29), // adc3
+        Some(6),
+        Some(2),
+        Some(5),
+        Some(0), // tx0
+        Some(1), // rx0
+        Some(4),
+        None, // not wired
+        None, // not wired
+    ];
+    const PIO_ROWS: [u8; MATRIX_ROWS] = [9, 7, 3]; // rx1, gpio7, gpio3
+
+    /// Matrix scanner, duplex when built with the `duplex` feature, and by
+    /// PIO with the `pio-scan` one.
+    pub enum Scanner {
+        Matrix(Matrix<DynPin, DynPin, Col2Row, 14, MATRIX_ROWS>),
+        Duplex(DuplexMatrix<ProbePin, 6, 14, MATRIX_ROWS>),
+        Pio(PioMatrix),
+    }
+    impl Scanner {
+        fn get(&mut self) -> [[bool; 14]; MATRIX_ROWS] {
+            match self {
+                Scanner::Matrix(matrix) => matrix.get().unwrap(),
+                Scanner::Duplex(duplex) => duplex.get(),
+                Scanner::Pio(pio) => pio.get(),
+            }
+        }
+    }
+
+    /// Matrix scanned over and over by a state machine of PIO1, the rows
+    /// being muxed to it while the columns stay pulled-up inputs.
+    ///
+    /// Without DMA the state machine waits once the RX FIFO is full, so the
+    /// snapshots drained by `get` are up to a tick old.
+    pub struct PioMatrix {
+        scan: PioScan<14, MATRIX_ROWS>,
+        _sm: StateMachine<(pac::PIO1, SM0), Running>,
+        rx: Rx<(pac::PIO1, SM0)>,
+        // Drains the RX FIFO instead of `get`, with the `pio-dma` feature
+        dma: Option<ScanDma>,
+    }
+    impl PioMatrix {
+        fn get(&mut self) -> [[bool; 14]; MATRIX_ROWS] {
+            match &mut self.dma {
+                Some(dma) => self.scan.decode(dma.words()),
+                None => {
+                    let rx = &mut self.rx;
+                    self.scan.decode(core::iter::from_fn(|| rx.read()))
+                }
+            }
+        }
+    }
 
     #[shared]
     struct Shared {
@@ -44,16 +167,37 @@ mod app {
             rp2040_hal::usb::UsbBus,
             keyberon::keyboard::Keyboard<()>,
         >,
+        usb_serial: SerialPort<'static, rp2040_hal::usb::UsbBus>,
+        os_detect: OsDetect,
     }
 
     #[local]
     struct Local {
         watchdog: hal::watchdog::Watchdog,
         chording: Chording<6>,
-        matrix: Matrix<DynPin, DynPin, 14, 3>,
-        debouncer: Debouncer<[[bool; 14]; 3]>,
+        fifo: SioFifo,
+        inbox: Inbox<14, SCAN_ROWS>,
+        discovery: Option<Discovery<ProbePin, 15>>,
+        self_test: SelfTest<14, SCAN_ROWS>,
         alarm: hal::timer::Alarm0,
-        layout: Layout<14, 3, 5, CustomActions>,
+        layout: Layout<14, ROWS, LAYER_COUNT, CustomActions>,
+        flow_tap: FlowTap<14, ROWS, LAYER_COUNT, CustomActions>,
+        settings: Settings,
+        applied: Settings,
+        storage: Storage,
+        host: Host,
+        tap_clock: TapClock,
+        virtual_layers: VirtualLayers<14, ROWS, LAYER_COUNT, CustomActions>,
+        shortcuts: Shortcuts,
+        sequence: Sequence,
+        typing: Typing,
+        backlog: Backlog,
+        compose: Compose,
+        snippets: Snippets,
+        autocorrect: Autocorrect,
+        repeat: Repeat,
+        swap_hands: SwapHands,
+        lock: Lock,
     }
 
     #[init(local = [bus: Option<UsbBusAllocator<hal::usb::UsbBus>> = None])]
@@ -157,7 +301,7 @@ mod app {
             .ok()
             .unwrap();
 
-        let sio = Sio::new(c.device.SIO);
+        let mut sio = Sio::new(c.device.SIO);
         let pins = Pins::new(
             c.device.IO_BANK0,
             c.device.PADS_BANK0,
@@ -165,33 +309,182 @@ mod app {
             &mut resets,
         );
 
-        let matrix: Matrix<DynPin, DynPin, 14, 3> = Matrix::new(
-            [
-                pins.adc1.into_pull_up_input().into(),
-                pins.adc0.into_pull_up_input().into(),
-                pins.adc2.into_pull_up_input().into(),
-                pins.cipo.into_pull_up_input().into(),
-                pins.sck.into_pull_up_input().into(),
-                pins.adc3.into_pull_up_input().into(),
-                pins.gpio6.into_pull_up_input().into(),
-                pins.gpio2.into_pull_up_input().into(),
-                pins.gpio5.into_pull_up_input().into(),
-                pins.tx0.into_pull_up_input().into(),
-                pins.rx0.into_pull_up_input().into(),
-                pins.gpio4.into_pull_up_input().into(),
-                pins.copi.into_pull_up_input().into(), // not wired
-                pins.ncs.into_pull_up_input().into(),  // not wired
-            ],
-            [
-                pins.rx1.into_push_pull_output().into(),
-                pins.gpio7.into_push_pull_output().into(),
-                pins.gpio3.into_push_pull_output().into(),
-            ],
-        )
-        .unwrap();
+        // Keys wired to ground, in their own row past the matrix
+        let direct = DirectPins::new([
+            (pins.copi.into_pull_up_input().into(), (DIRECT_ROW, 12)),
+            (pins.ncs.into_pull_up_input().into(), (DIRECT_ROW, 13)),
+        ]);
+        let mut self_test = SelfTest::default();
+        // Built with the `discovery` feature, the keyboard only reports the
+        // pins connected by each key on the serial port
+        let (mut matrix, discovery) = if cfg!(feature = "discovery") {
+            let discovery = Discovery::new(
+                [
+                    ("adc1", ProbePin(pins.adc1.into())),
+                    ("adc0", ProbePin(pins.adc0.into())),
+                    ("adc2", ProbePin(pins.adc2.into())),
+                    ("cipo", ProbePin(pins.cipo.into())),
+                    ("sck", ProbePin(pins.sck.into())),
+                    ("adc3", ProbePin(pins.adc3.into())),
+                    ("gpio6", ProbePin(pins.gpio6.into())),
+                    ("gpio2", ProbePin(pins.gpio2.into())),
+                    ("gpio5", ProbePin(pins.gpio5.into())),
+                    ("tx0", ProbePin(pins.tx0.into())),
+                    ("rx0", ProbePin(pins.rx0.into())),
+                    ("gpio4", ProbePin(pins.gpio4.into())),
+                    ("rx1", ProbePin(pins.rx1.into())),
+                    ("gpio7", ProbePin(pins.gpio7.into())),
+                    ("gpio3", ProbePin(pins.gpio3.into())),
+                ],
+                MATRIX_SETTLE,
+            );
+            (None, Some(discovery))
+        } else if cfg!(feature = "duplex") {
+            // Column pin p reads columns 2p and 2p + 1
+            let duplex = DuplexMatrix::new(
+                [
+                    ProbePin(pins.adc1.into()),
+                    ProbePin(pins.adc0.into()),
+                    ProbePin(pins.adc2.into()),
+                    ProbePin(pins.cipo.into()),
+                    ProbePin(pins.sck.into()),
+                    ProbePin(pins.adc3.into()),
+                ],
+                [
+                    ProbePin(pins.rx1.into()),
+                    ProbePin(pins.gpio7.into()),
+                    ProbePin(pins.gpio3.into()),
+                ],
+                MATRIX_SETTLE,
+            );
+            (Some(Scanner::Duplex(duplex)), None)
+        } else if cfg!(feature = "pio-scan") {
+            // The PIO only reads the columns, and drives the rows low by
+            // making them outputs
+            pins.adc1.into_pull_up_input();
+            pins.adc0.into_pull_up_input();
+            pins.adc2.into_pull_up_input();
+            pins.cipo.into_pull_up_input();
+            pins.sck.into_pull_up_input();
+            pins.adc3.into_pull_up_input();
+            pins.gpio6.into_pull_up_input();
+            pins.gpio2.into_pull_up_input();
+            pins.gpio5.into_pull_up_input();
+            pins.tx0.into_pull_up_input();
+            pins.rx0.into_pull_up_input();
+            pins.gpio4.into_pull_up_input();
+            pins.rx1.into_mode::<FunctionPio1>();
+            pins.gpio7.into_mode::<FunctionPio1>();
+            pins.gpio3.into_mode::<FunctionPio1>();
+            let scan = PioScan::new(PIO_COLS, PIO_ROWS);
+            let (out_base, out_count) = scan.out_pins();
+            assert_eq!(out_count, 7, "the program drives 7 pins per row");
+            // The row masks are kept in y, and x counts the 3 rows down
+            // while tagging their snapshots. 32 cycles to settle, 0.5 µs at
+            // 62.5 MHz.
+            let program = pio_proc::pio_asm!(
+                "    pull block",
+                "    mov y, osr",
+                ".wrap_target",
+                "    mov osr, y",
+                "    set x, 2",
+                "row:",
+                "    out pindirs, 7",
+                "    nop [31]",
+                "    in pins, 30",
+                "    in x, 2",
+                "    jmp x-- row",
+                ".wrap"
+            );
+            let (mut pio, sm0, _, _, _) = c.device.PIO1.split(&mut resets);
+            let installed = pio.install(&program.program).unwrap();
+            let (sm, rx, mut tx) = PIOBuilder::from_program(installed)
+                .out_pins(out_base, out_count)
+                .in_pin_base(0)
+                .out_shift_direction(ShiftDirection::Right)
+                .in_shift_direction(ShiftDirection::Right)
+                .autopush(true)
+                .push_threshold(32)
+                .build(sm0);
+            tx.write(scan.masks());
+            let dma = if cfg!(feature = "pio-dma") {
+                Some(ScanDma::new(&mut resets))
+            } else {
+                None
+            };
+            let pio = PioMatrix {
+                scan,
+                _sm: sm.start(),
+                rx,
+                dma,
+            };
+            (Some(Scanner::Pio(pio)), None)
+        } else {
+            let mut cols = [
+                ProbePin(pins.adc1.into()),
+                ProbePin(pins.adc0.into()),
+                ProbePin(pins.adc2.into()),
+                ProbePin(pins.cipo.into()),
+                ProbePin(pins.sck.into()),
+                ProbePin(pins.adc3.into()),
+                ProbePin(pins.gpio6.into()),
+                ProbePin(pins.gpio2.into()),
+                ProbePin(pins.gpio5.into()),
+                ProbePin(pins.tx0.into()),
+                ProbePin(pins.rx0.into()),
+                ProbePin(pins.gpio4.into()),
+            ];
+            let mut rows = [
+                ProbePin(pins.rx1.into()),
+                ProbePin(pins.gpio7.into()),
+                ProbePin(pins.gpio3.into()),
+            ];
+            self_test = SelfTest::bridges(&mut rows, &mut cols, MATRIX_SETTLE);
+            // The probes leave the pins as pulled up inputs, columns 12 and
+            // 13 are not wired
+            let mut cols = cols.into_iter().map(|pin| pin.0);
+            let matrix = Matrix::col2row(
+                core::array::from_fn(|_| cols.next()),
+                rows.map(|ProbePin(mut pin)| {
+                    pin.into_push_pull_output();
+                    pin
+                }),
+                MATRIX_SETTLE,
+            )
+            .unwrap();
+            (Some(Scanner::Matrix(matrix)), None)
+        };
+        if let Some(matrix) = &mut matrix {
+            self_test.run(|| direct.merge(matrix.get()).unwrap());
+        }
 
-        let layout = Layout::new(&crate::layout::LAYERS);
-        let debouncer = Debouncer::new([[false; 14]; 3], [[false; 14]; 3], 20);
+        let flow_tap = FlowTap::new(
+            &crate::layout::LAYERS,
+            &crate::layout::FLOW_TAP,
+            crate::layout::FLOW_TAP_TERM,
+        );
+        let storage = Storage::new(DEFAULT_SETTINGS);
+        let mut settings = storage.load();
+        if settings.default_layer as usize >= BASE_LAYERS {
+            settings.default_layer = 0;
+        }
+        let mut layout = Layout::new(&crate::layout::LAYERS);
+        layout.set_default_layer(settings.default_layer as usize * BLOCK);
+        let debouncer = Debouncer::new(EagerPerKey::new(settings.debounce));
+
+        // Core 1 scans and debounces the matrix, core 0 gets its events
+        if let Some(matrix) = matrix {
+            let mut psm = c.device.PSM;
+            let mut ppb = c.device.PPB;
+            let mut multicore = Multicore::new(&mut psm, &mut ppb, &mut sio.fifo);
+            let cores = multicore.cores();
+            let self_test = self_test.clone();
+            cores[1]
+                .spawn(unsafe { &mut crate::core1::STACK.mem }, move || {
+                    crate::core1::run(matrix, direct, self_test, debouncer)
+                })
+                .unwrap();
+        }
 
         let chording = Chording::new(&crate::layout::CHORDS);
 
@@ -210,39 +503,91 @@ mod app {
         let usb_bus = c.local.bus.as_ref().unwrap();
 
         let usb_class = keyberon::new_class(usb_bus, ());
-        let usb_dev = keyberon::new_device(usb_bus);
+        let usb_serial = SerialPort::new(usb_bus);
+        let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27db))
+            .manufacturer("RIIR Task Force")
+            .product("Keyberon")
+            .serial_number(env!("CARGO_PKG_VERSION"))
+            .composite_with_iads()
+            .build();
 
         // Start watchdog and feed it with the lowest priority task at 1000hz
         watchdog.start(10_000.microseconds());
 
         (
-            Shared { usb_dev, usb_class },
+            Shared {
+                usb_dev,
+                usb_class,
+                usb_serial,
+                os_detect: OsDetect::default(),
+            },
             Local {
                 layout,
+                flow_tap,
+                settings,
+                applied: settings,
+                storage,
+                host: Host::new(DEFAULT_SETTINGS),
+                tap_clock: TapClock::default(),
+                virtual_layers: VirtualLayers::new(
+                    &crate::layout::LAYERS,
+                    &crate::layout::CONDITIONAL_LAYERS,
+                    crate::layout::VIRTUAL_ROW,
+                    BLOCK,
+                ),
+                shortcuts: Shortcuts::default(),
+                sequence: Sequence::default(),
+                typing: Typing::default(),
+                backlog: Backlog::default(),
+                compose: Compose::new(&crate::layout::COMPOSE),
+                snippets: Snippets::new(&crate::layout::SNIPPETS),
+                autocorrect: Autocorrect::new(&crate::layout::TYPOS, &crate::layout::TYPO_TRIE),
+                repeat: Repeat::default(),
+                swap_hands: SwapHands::new(&crate::layout::MIRROR),
+                lock: Lock::new(&crate::layout::UNLOCK),
                 alarm,
                 chording,
                 watchdog,
-                matrix,
-                debouncer,
+                fifo: sio.fifo,
+                inbox: Inbox::default(),
+                discovery,
+                self_test,
             },
             init::Monotonics(),
         )
     }
 
-    #[task(binds = USBCTRL_IRQ, priority = 3, shared = [usb_dev, usb_class])]
+    #[task(
+        binds = USBCTRL_IRQ,
+        priority = 3,
+        shared = [usb_dev, usb_class, usb_serial, os_detect],
+    )]
     fn usb_rx(c: usb_rx::Context) {
-        (c.shared.usb_dev, c.shared.usb_class).lock(|u, k| {
-            if u.poll(&mut [k]) {
-                k.poll();
-            }
-        });
+        let shared = c.shared;
+        (
+            shared.usb_dev,
+            shared.usb_class,
+            shared.usb_serial,
+            shared.os_detect,
+        )
+            .lock(|u, k, s, d| {
+                if u.poll(&mut [k, s, d]) {
+                    k.poll();
+                }
+            });
     }
 
     #[task(
         binds = TIMER_IRQ_0,
         priority = 1,
-        shared = [usb_class],
-        local = [layout, matrix, debouncer, chording, watchdog, alarm],
+        shared = [usb_class, usb_serial, os_detect],
+        local = [
+            layout, flow_tap, fifo, inbox, discovery, self_test, chording,
+            watchdog, alarm,
+            settings, applied, storage, host, tap_clock, virtual_layers, shortcuts,
+            sequence, typing, backlog, compose,
+            snippets, autocorrect, repeat, swap_hands, lock,
+        ],
     )]
     fn scan_timer_irq(c: scan_timer_irq::Context) {
         let alarm = c.local.alarm;
@@ -250,25 +595,161 @@ mod app {
         let _ = alarm.schedule(SCAN_TIME_US.microseconds());
 
         c.local.watchdog.feed();
-        for event in c.local.chording.tick(
+        let settings = c.local.settings;
+        let host = c.local.host;
+        let mut usb_serial = c.shared.usb_serial;
+        let chatter = c.local.inbox.chatter();
+        let faults = c.local.self_test.faults();
+        usb_serial.lock(|s| host.poll(s, settings, chatter, faults));
+        if settings.default_layer != c.local.applied.default_layer {
+            if (settings.default_layer as usize) < BASE_LAYERS {
+                c.local
+                    .layout
+                    .set_default_layer(settings.default_layer as usize * BLOCK);
+                c.local.virtual_layers.clear();
+            } else {
+                settings.default_layer = c.local.applied.default_layer;
+            }
+        }
+        if settings.debounce != c.local.applied.debounce && c.local.discovery.is_none() {
             c.local
-                .debouncer
-                .events(c.local.matrix.get().unwrap())
+                .fifo
+                .write_blocking(Command::Debounce(settings.debounce).to_word());
+        }
+        *c.local.applied = *settings;
+
+        if let Some(discovery) = c.local.discovery {
+            if let Some(key) = discovery.scan() {
+                let _ = discovery.report(key, host);
+            }
+            return;
+        }
+        let fifo = c.local.fifo;
+        let inbox = c.local.inbox;
+        inbox.receive(fifo, SioFifo::read);
+
+        let swap_hands = &mut *c.local.swap_hands;
+        for event in c.local.chording.tick(
+            inbox
+                .events()
+                .into_iter()
+                .map(|event| swap_hands.event(event))
                 .collect(),
         ) {
-            c.local.layout.event(event);
+            let layer = c.local.layout.current_layer();
+            let default = settings.default_layer as usize * BLOCK;
+            c.local.virtual_layers.event(event, layer, default);
+            if let Some(event) = c.local.flow_tap.event(event, layer, default) {
+                c.local.layout.event(event);
+            }
         }
-        match c.local.layout.tick() {
-            layout::CustomEvent::Press(event) => match event {
-                CustomActions::Bootload => hal::rom_data::reset_to_usb_boot(0, 0),
-                CustomActions::Reset => cortex_m::peripheral::SCB::sys_reset(),
-            },
-            _ => (),
+        c.local.flow_tap.tick();
+        let holding = c.local.flow_tap.is_holding();
+        let mut ticks = c.local.tap_clock.ticks(holding, settings.tapping_term);
+        let mut os_detect = c.shared.os_detect;
+        let os = Os::from_setting(settings.os, os_detect.lock(|d| d.guess()));
+        let method = Method::for_os(os, crate::layout::WINDOWS_UNICODE);
+        let host_layout = HostLayout::from_setting(settings.host_layout);
+        while ticks.next(c.local.layout.keycodes()) {
+            let custom = c.local.layout.tick();
+            if custom != layout::CustomEvent::NoEvent {
+                ticks.stop();
+            }
+            match custom {
+                // Only the unlock sequence is followed while locked
+                layout::CustomEvent::Press(_) if c.local.lock.is_locked() => (),
+                layout::CustomEvent::Press(event) => match event {
+                    CustomActions::Bootload => hal::rom_data::reset_to_usb_boot(0, 0),
+                    CustomActions::Reset => cortex_m::peripheral::SCB::sys_reset(),
+                    CustomActions::TappingTermUp => settings.step_tapping_term(true),
+                    CustomActions::TappingTermDown => settings.step_tapping_term(false),
+                    CustomActions::DebounceUp => settings.step_debounce(true),
+                    CustomActions::DebounceDown => settings.step_debounce(false),
+                    CustomActions::ResetSettings => *settings = DEFAULT_SETTINGS,
+                    CustomActions::LayerLock => c.local.virtual_layers.lock(),
+                    CustomActions::ToggleLayer(layer) => {
+                        c.local.virtual_layers.toggle(*layer);
+                    }
+                    CustomActions::SetDefaultLayer(layer) => {
+                        settings.default_layer = *layer as u16;
+                    }
+                    CustomActions::SetOs(os) => settings.os = os.setting(),
+                    CustomActions::DetectOs => settings.os = os::AUTO,
+                    CustomActions::Shortcut(shortcut) => c.local.shortcuts.press(*shortcut, os),
+                    CustomActions::Unicode(ch) => {
+                        unicode::type_char(c.local.sequence, method, host_layout, *ch);
+                    }
+                    CustomActions::Compose => c.local.compose.toggle(),
+                    CustomActions::ToggleAutocorrect => c.local.autocorrect.toggle(),
+                    CustomActions::Repeat => c.local.repeat.press(),
+                    CustomActions::AltRepeat => {
+                        c.local.repeat.press_alt(&crate::layout::ALT_REPEAT, os);
+                    }
+                    CustomActions::SwapHands => c.local.swap_hands.hold(true),
+                    CustomActions::ToggleSwapHands => c.local.swap_hands.toggle(),
+                    CustomActions::Lock => c.local.lock.lock(c.local.sequence),
+                },
+                layout::CustomEvent::Release(CustomActions::Shortcut(shortcut)) => {
+                    c.local.shortcuts.release(*shortcut);
+                }
+                layout::CustomEvent::Release(CustomActions::Repeat)
+                | layout::CustomEvent::Release(CustomActions::AltRepeat) => {
+                    c.local.repeat.release()
+                }
+                layout::CustomEvent::Release(CustomActions::SwapHands) => {
+                    c.local.swap_hands.hold(false)
+                }
+                _ => (),
+            }
+        }
+        let current = c.local.layout.current_layer();
+        for event in c.local.virtual_layers.update(current) {
+            c.local.layout.event(event);
         }
+
+        let keys = typing::keys(
+            c.local
+                .layout
+                .keycodes()
+                .chain(c.local.flow_tap.keycodes())
+                .map(|kc| os.modifier(kc))
+                .chain(c.local.shortcuts.keycodes())
+                .chain(c.local.repeat.keycodes()),
+        );
+        let mut keys = c.local.backlog.next(keys, c.local.sequence.is_playing());
+        let presses = c.local.typing.update(&keys);
+        c.local.lock.filter(&mut keys, &presses);
+        c.local.repeat.update(&presses);
+        let sequence = c.local.sequence;
+        c.local
+            .compose
+            .filter(&mut keys, &presses, sequence, method, host_layout);
+        c.local
+            .snippets
+            .filter(&mut keys, &presses, sequence, method, host_layout);
+        c.local
+            .autocorrect
+            .filter(&mut keys, &presses, sequence, method, host_layout);
+
         let mut usb_class = c.shared.usb_class;
-        let report: KbHidReport = c.local.layout.keycodes().collect();
+        sequence.tick();
+        let report: KbHidReport = if sequence.is_playing() {
+            sequence.keycodes().collect()
+        } else {
+            keys.iter().copied().collect()
+        };
         if usb_class.lock(|k| k.device_mut().set_keyboard_report(report.clone())) {
             while let Ok(0) = usb_class.lock(|k| k.write(report.as_bytes())) {}
         }
+
+        // Core 1 waits in RAM while the flash is written
+        c.local.storage.tick(settings, c.local.watchdog, |pause| {
+            if pause {
+                inbox.pause(fifo, SioFifo::write_blocking, SioFifo::read)
+            } else {
+                inbox.resume(fifo, SioFifo::write_blocking);
+                true
+            }
+        });
     }
 }
@@ -0,0 +1,73 @@
+use cortex_m::prelude::_embedded_hal_watchdog_WatchdogEnable;
+use embedded_time::duration::units::*;
+use rp2040_hal::watchdog::Watchdog;
+
+use keebs_common::settings::{self, Settings};
+
+// Last 4K sector of the 16M flash, kept out of FLASH in memory.x
+const OFFSET: u32 = 16 * 1024 * 1024 - 4096;
+const XIP_BASE: u32 = 0x1000_0000;
+const PAGE_SIZE: usize = 256;
+// Wait for the settings to stop changing before wearing the flash
+const SAVE_DELAY_MS: u16 = 2000;
+
+pub struct Storage {
+    saved: Settings,
+    quiet: u16,
+    // core 1 asked to pause, the settings are saved once it is
+    pausing: bool,
+}
+
+impl Storage {
+    pub fn new(defaults: Settings) -> Self {
+        let bytes = unsafe {
+            core::slice::from_raw_parts((XIP_BASE + OFFSET) as *const u8, settings::SIZE)
+        };
+        Self {
+            saved: Settings::from_bytes(bytes, defaults),
+            quiet: 0,
+            pausing: false,
+        }
+    }
+
+    pub fn load(&self) -> Settings {
+        self.saved
+    }
+
+    /// Saves `settings` once they stopped changing, calling `pause` with
+    /// true before writing the flash and false after. While `pause(true)`
+    /// returns false the save waits for the next tick.
+    pub fn tick(
+        &mut self,
+        settings: &Settings,
+        watchdog: &mut Watchdog,
+        mut pause: impl FnMut(bool) -> bool,
+    ) {
+        if *settings == self.saved && !self.pausing {
+            self.quiet = 0;
+            return;
+        }
+        self.quiet += 1;
+        if self.quiet < SAVE_DELAY_MS {
+            return;
+        }
+        let mut page = [0xff; PAGE_SIZE];
+        page[..settings::SIZE].copy_from_slice(&settings.to_bytes());
+
+        // Nothing runs from flash while the sector is erased
+        self.pausing = true;
+        if !pause(true) {
+            return;
+        }
+        self.pausing = false;
+        watchdog.start(1_000_000.microseconds());
+        cortex_m::interrupt::free(|_| unsafe {
+            rp2040_flash::flash::flash_range_erase_and_program(OFFSET, &page, true);
+        });
+        pause(false);
+        watchdog.start(10_000.microseconds());
+
+        self.saved = *settings;
+        self.quiet = 0;
+    }
+}